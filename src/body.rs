@@ -0,0 +1,453 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use futures::future;
+use futures::prelude::*;
+use futures::stream;
+use futures::task;
+use hyper::{Body, Response};
+use never::Never;
+use std::error::Error;
+use std::io;
+use tokio::io::AsyncRead;
+
+/// Creates a body that yields each `Ok` item in `results` as a separate
+/// chunk, and fails with the error from the first `Err`.
+///
+/// Nothing after the first `Err` is used, so a body that ends with an error
+/// part-way through can be built by putting the error last. The error type
+/// can be anything that converts into `Box<dyn Error + Send + Sync>`, which
+/// includes `io::Error`, `String`, and `&'static str`.
+///
+/// When such a body is used for a response, the stub server aborts the
+/// connection when it reaches the error, so the client sees the chunks before
+/// it followed by an error for an incomplete message.
+///
+/// ```
+/// # extern crate bytes;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use bytes::Bytes;
+/// use hyper::Response;
+/// use hyper_stub::{body_from_results, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(|_| {
+///     Response::new(body_from_results(vec![
+///         Ok(Bytes::from("partial ")),
+///         Ok(Bytes::from("response")),
+///         Err("connection reset"),
+///     ]))
+/// });
+/// ```
+pub fn body_from_results<I, E>(results: I) -> Body
+where
+    I: IntoIterator<Item = Result<Bytes, E>>,
+    I::IntoIter: Send + 'static,
+    E: Into<Box<dyn Error + Send + Sync>> + 'static,
+{
+    let mut failed = false;
+    let results = results.into_iter().take_while(move |result| {
+        let done = failed;
+        failed = result.is_err();
+        !done
+    });
+    Body::wrap_stream(stream::iter_result(results))
+}
+
+/// Creates a response whose body is sent as one chunk for each size in
+/// `chunk_sizes`, using chunked transfer encoding.
+///
+/// The bytes of the first chunk are all `a`, the second all `b`, and so on,
+/// wrapping around after `z`, so that where each chunk came from can be seen
+/// in the reassembled body. A chunk size of 0 is skipped, since an empty chunk
+/// would end the body.
+///
+/// The stub server writes each chunk as a separate chunk on the wire, but the
+/// client is free to read them in differently sized pieces, and so may hand
+/// them back in different chunks from the ones that were sent. Any real proxy
+/// in between could also coalesce or split chunks, so exact boundaries are
+/// best-effort, and a client shouldn't rely on them.
+pub fn chunked_response(chunk_sizes: &[usize]) -> Response<Body> {
+    let chunks: Vec<_> = chunk_sizes
+        .iter()
+        .filter(|&&size| size > 0)
+        .enumerate()
+        .map(|(index, &size)| Ok::<_, Never>(Bytes::from(vec![b'a' + (index % 26) as u8; size])))
+        .collect();
+    Response::new(body_from_results(chunks))
+}
+
+// The size of each chunk of a generated_body, apart from the last.
+const GENERATED_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Creates a body of `total_len` bytes, generated on the fly by calling
+/// `generate` to fill each chunk in turn.
+///
+/// `generate` is called with the offset of the chunk in the body, and a
+/// buffer to fill, which is always 64 KiB, except for the last chunk. Each
+/// chunk is only generated when hyper's server is ready to write it, and is
+/// freed once written, so the stub never holds more than a chunk or so of
+/// the body at a time, however large it is.
+///
+/// That doesn't mean the body is never all in memory at once, though. With
+/// the default [`UnboundedTransport`], hyper's server can always write, so
+/// bytes the client hasn't read yet pile up in the socket. To keep memory
+/// use down, the client should read the body as it arrives, rather than
+/// waiting for the server to finish first.
+///
+/// The body is sent with chunked transfer encoding, unless the response has
+/// a `Content-Length` header, which should then be `total_len`.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{generated_body, proxy_client_fn_ok};
+///
+/// // 100 MB of zeroes, without allocating 100 MB.
+/// let client = proxy_client_fn_ok(|_| {
+///     Response::new(generated_body(100_000_000, |_offset, _buf| {}))
+/// });
+/// ```
+///
+/// [`UnboundedTransport`]: struct.UnboundedTransport.html
+pub fn generated_body<F>(total_len: u64, mut generate: F) -> Body
+where
+    F: FnMut(u64, &mut [u8]) + Send + 'static,
+{
+    let chunks = (0..total_len.div_ceil(GENERATED_CHUNK_SIZE)).map(move |index| {
+        let offset = index * GENERATED_CHUNK_SIZE;
+        let mut chunk = vec![0; GENERATED_CHUNK_SIZE.min(total_len - offset) as usize];
+        generate(offset, &mut chunk);
+        Bytes::from(chunk)
+    });
+    Body::wrap_stream(stream::iter_ok::<_, Never>(chunks))
+}
+
+// The most read from a body_from_reader's reader at a time.
+const READER_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Creates a body that streams everything read from `reader`, until it
+/// reaches the end.
+///
+/// The reader is only read from when hyper's server is ready for more of
+/// the body, up to 64 KiB at a time, and each read is sent to the client as
+/// it's made, so a large fixture can be sent without loading it all into
+/// memory. As with [`generated_body`], the client should read the body as it
+/// arrives to keep it that way.
+///
+/// If reading fails, the body fails with the reader's `io::Error`, after
+/// whatever was read before it. The stub server then aborts the connection,
+/// as for a body from [`body_from_results`], so the client gets the part of
+/// the body before the error, and then an error for an incomplete message.
+///
+/// The body is sent with chunked transfer encoding, unless the response has
+/// a `Content-Length` header.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{body_from_reader, proxy_client_fn_ok};
+/// use std::io::Cursor;
+///
+/// let client = proxy_client_fn_ok(|_| {
+///     Response::new(body_from_reader(Cursor::new(b"streamed fixture".to_vec())))
+/// });
+/// ```
+///
+/// [`generated_body`]: fn.generated_body.html
+/// [`body_from_results`]: fn.body_from_results.html
+pub fn body_from_reader<R>(mut reader: R) -> Body
+where
+    R: AsyncRead + Send + 'static,
+{
+    let mut buf = vec![0; READER_CHUNK_SIZE];
+    Body::wrap_stream(stream::poll_fn(
+        move || -> Poll<Option<Bytes>, io::Error> {
+            let len = try_ready!(reader.poll_read(&mut buf));
+            if len == 0 {
+                return Ok(Async::Ready(None));
+            }
+            Ok(Async::Ready(Some(Bytes::from(&buf[..len]))))
+        },
+    ))
+}
+
+/// What a body made by [`body_stall_at`] does once it reaches its offset.
+///
+/// [`body_stall_at`]: fn.body_stall_at.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interruption {
+    /// Stop sending, without ending the body or closing the connection, so
+    /// the client waits for the rest forever.
+    Stall,
+
+    /// Fail, so the stub server aborts the connection straight away, once
+    /// it's written the bytes before the offset, as with a body from
+    /// [`body_from_results`]. Reading the body then fails, for a hyper
+    /// client with an error caused by an `io::Error` of kind
+    /// `UnexpectedEof`.
+    ///
+    /// [`body_from_results`]: fn.body_from_results.html
+    Error,
+}
+
+/// Creates a body that sends the first `offset` bytes of `bytes`, and is
+/// then interrupted as `interruption` says, for testing a client that
+/// resumes interrupted downloads.
+///
+/// The bytes before `offset` are sent as one chunk. To let the client tell
+/// that the body it got was cut short, and how much of it is missing, give
+/// the response a `Content-Length` of the length of `bytes`. Without one,
+/// the body is sent with chunked transfer encoding. It panics if `offset`
+/// is past the end of `bytes`.
+///
+/// hyper's client has no read timeout, so with [`Interruption::Stall`], a
+/// client only notices the stall if it puts a timeout of its own around
+/// reading the body, like a tokio [`Timeout`], and nothing in the stub
+/// will ever end it. When that times out, the client has received exactly
+/// `offset` bytes.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::header::CONTENT_LENGTH;
+/// use hyper::Response;
+/// use hyper_stub::{body_stall_at, proxy_client_fn_ok, Interruption};
+///
+/// let client = proxy_client_fn_ok(|_| {
+///     let mut response = Response::new(body_stall_at(5, "hello world", Interruption::Error));
+///     response.headers_mut().insert(CONTENT_LENGTH, 11.into());
+///     response
+/// });
+/// ```
+///
+/// [`Interruption::Stall`]: enum.Interruption.html#variant.Stall
+/// [`Timeout`]: https://docs.rs/tokio/0.1/tokio/timer/struct.Timeout.html
+pub fn body_stall_at<B: Into<Bytes>>(offset: usize, bytes: B, interruption: Interruption) -> Body {
+    let bytes = bytes.into();
+    assert!(
+        offset <= bytes.len(),
+        "offset {} is past the end of a {} byte body",
+        offset,
+        bytes.len()
+    );
+
+    // An empty chunk would end the body, so there isn't one if offset is 0.
+    let head = Some(bytes.slice_to(offset)).filter(|head| !head.is_empty());
+    let head = stream::iter_ok::<_, io::Error>(head);
+
+    match interruption {
+        Interruption::Stall => {
+            Body::wrap_stream(head.chain(future::empty::<Bytes, io::Error>().into_stream()))
+        }
+        Interruption::Error => {
+            // hyper's server aborts the connection as soon as the body fails,
+            // without writing out what it had buffered, so wait for it to
+            // have flushed the head, which it does when the body isn't ready.
+            let mut yielded = false;
+            let error = stream::poll_fn(move || -> Poll<Option<Bytes>, io::Error> {
+                if !yielded {
+                    yielded = true;
+                    task::current().notify();
+                    return Ok(Async::NotReady);
+                }
+                let message = format!("body interrupted at byte {}", offset);
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, message))
+            });
+            Body::wrap_stream(head.chain(error))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_ok() {
+        let body = body_from_results(vec![
+            Ok::<_, io::Error>(Bytes::from("hello ")),
+            Ok(Bytes::from("world")),
+        ]);
+        let chunks: Vec<_> = body.collect().wait().unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(&chunks[0][..], b"hello ");
+        assert_eq!(&chunks[1][..], b"world");
+    }
+
+    #[test]
+    fn test_ends_with_error() {
+        let body = body_from_results(vec![
+            Ok(Bytes::from("hello")),
+            Err("something went wrong"),
+            Ok(Bytes::from("never sent")),
+        ]);
+
+        let mut chunks = body.wait();
+        assert_eq!(&chunks.next().unwrap().unwrap()[..], b"hello");
+        let error = chunks.next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("something went wrong"));
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn test_client_sees_error() {
+        use hyper::Response;
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = ::proxy_client_fn_ok(|_| {
+            Response::new(body_from_results(vec![
+                Ok(Bytes::from("hello")),
+                Err("something went wrong"),
+            ]))
+        });
+
+        let future = client
+            .get("http://example.com".parse().unwrap())
+            .and_then(|res| res.into_body().concat2());
+        assert!(Runtime::new().unwrap().block_on(future).is_err());
+    }
+
+    #[test]
+    fn test_chunked_response() {
+        use hyper::header::TRANSFER_ENCODING;
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = ::proxy_client_fn_ok(|_| chunked_response(&[3, 0, 1000, 1]));
+        let future = client
+            .get("http://example.com".parse().unwrap())
+            .and_then(|res| {
+                assert_eq!(res.headers()[TRANSFER_ENCODING], "chunked");
+                res.into_body().concat2()
+            });
+        let body = Runtime::new().unwrap().block_on(future).unwrap();
+
+        assert_eq!(body.len(), 1004);
+        assert_eq!(&body[..4], b"aaab");
+        assert_eq!(body[1003], b'c');
+    }
+
+    #[test]
+    fn test_generated_body() {
+        use hyper::Response;
+        use tokio::runtime::current_thread::Runtime;
+
+        const LEN: u64 = 4 * 1024 * 1024 + 3;
+
+        let client = ::proxy_client_fn_ok(|_| {
+            Response::new(generated_body(LEN, |offset, buf| {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = ((offset + i as u64) % 251) as u8;
+                }
+            }))
+        });
+
+        // Check the body as it arrives, rather than buffering all of it.
+        let future = client
+            .get("http://example.com".parse().unwrap())
+            .and_then(|res| {
+                res.into_body().fold(0u64, |position, chunk| {
+                    for (i, &byte) in chunk.iter().enumerate() {
+                        assert_eq!(byte, ((position + i as u64) % 251) as u8);
+                    }
+                    Ok::<_, ::hyper::Error>(position + chunk.len() as u64)
+                })
+            });
+        assert_eq!(Runtime::new().unwrap().block_on(future).unwrap(), LEN);
+    }
+
+    #[test]
+    fn test_body_from_reader() {
+        use hyper::Response;
+        use std::io::Cursor;
+        use tokio::runtime::current_thread::Runtime;
+
+        let fixture: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let expected = fixture.clone();
+        let client = ::proxy_client_fn_ok(move |_| {
+            Response::new(body_from_reader(Cursor::new(fixture.clone())))
+        });
+
+        let future = client
+            .get("http://example.com".parse().unwrap())
+            .and_then(|res| res.into_body().concat2());
+        let body = Runtime::new().unwrap().block_on(future).unwrap();
+        assert_eq!(&body[..], &expected[..]);
+    }
+
+    #[test]
+    fn test_body_from_reader_error() {
+        use std::io::{Cursor, Read};
+
+        struct Failing;
+
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk on fire"))
+            }
+        }
+
+        impl AsyncRead for Failing {}
+
+        let body = body_from_reader(Cursor::new(b"hello".to_vec()).chain(Failing));
+        let mut chunks = body.wait();
+        assert_eq!(&chunks.next().unwrap().unwrap()[..], b"hello");
+        let error = chunks.next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("disk on fire"));
+    }
+
+    fn interrupted_download(interruption: Interruption) -> (Vec<u8>, Option<::hyper::Error>) {
+        use hyper::header::CONTENT_LENGTH;
+        use hyper::Response;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use tokio::runtime::current_thread::Runtime;
+        use tokio::timer::Timeout;
+
+        let client = ::proxy_client_fn_ok(move |_| {
+            let bytes = vec![b'x'; 100_000];
+            let mut response = Response::new(body_stall_at(70_000, bytes, interruption));
+            response
+                .headers_mut()
+                .insert(CONTENT_LENGTH, 100_000.into());
+            response
+        });
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        let future = client
+            .get("http://example.com".parse().unwrap())
+            .and_then(move |res| {
+                res.into_body().for_each(move |chunk| {
+                    sink.lock().unwrap().extend_from_slice(&chunk);
+                    Ok(())
+                })
+            });
+        let future = Timeout::new(future, Duration::from_millis(100));
+        let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+
+        let received = received.lock().unwrap().clone();
+        (received, error.into_inner())
+    }
+
+    #[test]
+    fn test_body_stall_at() {
+        let (received, error) = interrupted_download(Interruption::Stall);
+        assert!(error.is_none(), "{:?}", error);
+        assert_eq!(received.len(), 70_000);
+
+        let (received, error) = interrupted_download(Interruption::Error);
+        let cause = error.unwrap().into_cause().unwrap();
+        let kind = cause.downcast_ref::<io::Error>().unwrap().kind();
+        assert_eq!(kind, io::ErrorKind::UnexpectedEof);
+        assert_eq!(received.len(), 70_000);
+    }
+}
@@ -0,0 +1,1318 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{Bytes, BytesMut};
+use connector::Connector;
+use futures::prelude::*;
+use futures::sync::mpsc;
+use hyper::body::{Body, Payload};
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::client::Builder as ClientBuilder;
+use hyper::service::{NewService, Service};
+use hyper::{Client, Request, Response, StatusCode, Uri};
+use never::Never;
+use panic::PanicLog;
+use raw::RawRequestLog;
+use raw_response::{ClosingConnector, RawConnector, ResettingConnector};
+use record::RequestLog;
+#[cfg(feature = "serde")]
+use record_file::RecordFile;
+use response::IntoResponse;
+use rules::{self, Matcher, ResponseFactory};
+use stats::Stats;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Debug, Formatter};
+use std::hash::Hash;
+use std::io;
+#[cfg(feature = "serde")]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio;
+use transport::{Transport, UnboundedTransport};
+
+/// A builder for stub clients with non-default behaviour.
+///
+/// The free functions at the root of this crate ([`proxy_client`] and
+/// friends) are shorthand for calling the same method on a default
+/// `StubBuilder`.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::StubBuilder;
+///
+/// let client = StubBuilder::new()
+///     .write_fragment_size(1)
+///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+/// ```
+///
+/// [`proxy_client`]: fn.proxy_client.html
+pub struct StubBuilder<T = UnboundedTransport> {
+    config: Config,
+    transport: Arc<T>,
+}
+
+// Everything about a StubBuilder except its transport, which is kept
+// separately so that it doesn't have to be Clone or Debug.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Config {
+    pub(crate) write_fragment_size: Option<usize>,
+    pub(crate) yield_between_fragments: bool,
+    pub(crate) body_delay: Option<Duration>,
+    pub(crate) handshake_delay: Option<Duration>,
+    pub(crate) raw_request_log: Option<RawRequestLog>,
+    pub(crate) request_log: Option<RequestLog>,
+    #[cfg(feature = "serde")]
+    pub(crate) record_file: Option<RecordFile>,
+    pub(crate) panic_log: Option<PanicLog>,
+    pub(crate) drain_request_bodies: bool,
+    pub(crate) max_buffered_body: Option<usize>,
+    pub(crate) extensions: Extensions,
+    pub(crate) connection_extras: ConnectionExtras,
+    pub(crate) stats: Option<Stats>,
+    pub(crate) client_builder: ClientBuilder,
+    pub(crate) connect_delay: Option<ConnectDelay>,
+    pub(crate) connect_fail_once: Option<io::ErrorKind>,
+    pub(crate) http1_only: bool,
+    pub(crate) negotiate_h2: bool,
+    // When the client was created, which request logs measure the time each
+    // request was received from. Set by Connector::new.
+    pub(crate) created: Option<Instant>,
+    pub(crate) on_request_chunk: Option<ChunkHook>,
+    // Every Arc<Config> holds a clone of this, including the ones in the
+    // stub server's connection tasks, so the receiving end is closed once
+    // the client and all of its connections have been dropped. Set by
+    // StubBuilder::proxy_client_fn_ok_with_drain.
+    pub(crate) connection_tracker: Option<mpsc::Sender<Never>>,
+}
+
+impl Config {
+    // Whether every request's body has to be read in full, so it can be
+    // recorded, before the handler is called.
+    pub(crate) fn records_requests(&self) -> bool {
+        #[cfg(feature = "serde")]
+        {
+            if self.record_file.is_some() {
+                return true;
+            }
+        }
+        self.request_log.is_some()
+    }
+}
+
+// Values added to the extensions of every request by StubBuilder::extension.
+// Each one is kept as a closure that inserts a clone of it, because
+// http::Extensions can't be cloned.
+type InsertExtension = Arc<dyn Fn(&mut http::Extensions) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct Extensions(Vec<InsertExtension>);
+
+impl Extensions {
+    pub(crate) fn apply(&self, extensions: &mut http::Extensions) {
+        for insert in &self.0 {
+            insert(extensions);
+        }
+    }
+}
+
+impl Debug for Extensions {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "Extensions({})", self.0.len())
+    }
+}
+
+// Values added to the Connected of every connection to the stub by
+// StubBuilder::connection_extra, each kept as a closure that adds a clone of
+// it, like Extensions.
+type AddConnectionExtra = Arc<dyn Fn(Connected) -> Connected + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionExtras(Vec<AddConnectionExtra>);
+
+impl ConnectionExtras {
+    pub(crate) fn apply(&self, connected: Connected) -> Connected {
+        self.0
+            .iter()
+            .fold(connected, |connected, add| add(connected))
+    }
+}
+
+impl Debug for ConnectionExtras {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "ConnectionExtras({})", self.0.len())
+    }
+}
+
+// How long to wait before connecting to each destination, set by
+// StubBuilder::connect_delay_fn.
+#[derive(Clone)]
+pub(crate) struct ConnectDelay(Arc<dyn Fn(&Destination) -> Duration + Send + Sync>);
+
+impl ConnectDelay {
+    pub(crate) fn get(&self, destination: &Destination) -> Duration {
+        (self.0)(destination)
+    }
+}
+
+impl Debug for ConnectDelay {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.write_str("ConnectDelay")
+    }
+}
+
+// Called with each chunk of every request body, set by
+// StubBuilder::on_request_chunk.
+#[derive(Clone)]
+pub(crate) struct ChunkHook(Arc<dyn Fn(&Bytes) + Send + Sync>);
+
+impl ChunkHook {
+    pub(crate) fn call(&self, chunk: &Bytes) {
+        (self.0)(chunk)
+    }
+}
+
+impl Debug for ChunkHook {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.write_str("ChunkHook")
+    }
+}
+
+impl StubBuilder {
+    /// Creates a builder with the default configuration.
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl Default for StubBuilder {
+    fn default() -> Self {
+        StubBuilder {
+            config: Default::default(),
+            transport: Arc::new(UnboundedTransport),
+        }
+    }
+}
+
+impl<T> Clone for StubBuilder<T> {
+    fn clone(&self) -> Self {
+        StubBuilder {
+            config: self.config.clone(),
+            transport: self.transport.clone(),
+        }
+    }
+}
+
+impl<T> Debug for StubBuilder<T> {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.debug_struct("StubBuilder")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl<T: Transport> StubBuilder<T> {
+    /// Uses `transport` to create the connections between clients and the stub
+    /// server, instead of the default [`UnboundedTransport`]. See
+    /// [`Transport`].
+    ///
+    /// [`Transport`]: trait.Transport.html
+    /// [`UnboundedTransport`]: struct.UnboundedTransport.html
+    pub fn transport<U: Transport>(&self, transport: U) -> StubBuilder<U> {
+        StubBuilder {
+            config: self.config.clone(),
+            transport: Arc::new(transport),
+        }
+    }
+
+    /// Splits everything the stub server writes into fragments of at most
+    /// `size` bytes.
+    ///
+    /// This is a robustness aid for the client's read loop -- for example,
+    /// with a size of 1 the response head and body are written one byte at a
+    /// time. It doesn't change the HTTP semantics of the response in any way,
+    /// so it only tests how well a client copes with a response that arrives
+    /// in awkward pieces.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn write_fragment_size(&mut self, size: usize) -> &mut Self {
+        assert!(size > 0, "write fragment size must be greater than 0");
+        self.config.write_fragment_size = Some(size);
+        self
+    }
+
+    /// Makes the stub server yield to the executor between each fragment
+    /// written when [`write_fragment_size`] is set, so that the client has a
+    /// chance to read each fragment separately.
+    ///
+    /// Has no effect unless [`write_fragment_size`] is also set.
+    ///
+    /// [`write_fragment_size`]: #method.write_fragment_size
+    pub fn yield_between_fragments(&mut self, enabled: bool) -> &mut Self {
+        self.config.yield_between_fragments = enabled;
+        self
+    }
+
+    /// Makes the stub server wait for `delay` after writing each response's
+    /// head, before it starts on the body.
+    ///
+    /// Unlike wrapping the handler in [`delayed`], which holds back the whole
+    /// response, this lets the client see the status and headers straight
+    /// away, for testing clients that act on them before the body arrives.
+    /// The delay starts once hyper's server asks for the body, just after
+    /// the head has been written, and the body isn't polled at all until it's
+    /// over. So a streaming body, like one from [`body_from_reader`],
+    /// [`chunked_response`], or [`proxy_client_split`], only starts producing
+    /// chunks after the delay, and any delays of its own are on top of it.
+    /// A response with an empty body is never polled, so it isn't delayed.
+    ///
+    /// [`delayed`]: fn.delayed.html
+    /// [`body_from_reader`]: fn.body_from_reader.html
+    /// [`chunked_response`]: fn.chunked_response.html
+    /// [`proxy_client_split`]: fn.proxy_client_split.html
+    pub fn body_delay(&mut self, delay: Duration) -> &mut Self {
+        self.config.body_delay = Some(delay);
+        self
+    }
+
+    /// Makes the stub server wait for `delay` after each connection is opened
+    /// before it starts reading from it, as if a TLS handshake had to happen
+    /// first.
+    ///
+    /// This is different from the other delays, which happen at other points
+    /// while setting up a request:
+    ///
+    /// - [`connect_delay`] holds back the connection itself, so the client's
+    ///   connector doesn't finish until it's over, and hyper's client counts
+    ///   it as connecting. With `handshake_delay`, the connector finishes
+    ///   straight away and the client writes its request, but the server
+    ///   doesn't read it, or respond, until the delay is over.
+    /// - [`body_delay`] applies to every response, after its head has been
+    ///   sent. `handshake_delay` applies once for each connection, so a
+    ///   request sent on a connection the client kept alive from an earlier
+    ///   one isn't delayed.
+    ///
+    /// The delays add up, so with all three, the first response's body
+    /// arrives after at least the sum of them.
+    ///
+    /// [`connect_delay`]: #method.connect_delay
+    /// [`body_delay`]: #method.body_delay
+    pub fn handshake_delay(&mut self, delay: Duration) -> &mut Self {
+        self.config.handshake_delay = Some(delay);
+        self
+    }
+
+    /// Records the raw bytes of the head of every request the stub receives in
+    /// `log`.
+    pub fn raw_request_log(&mut self, log: &RawRequestLog) -> &mut Self {
+        self.config.raw_request_log = Some(log.clone());
+        self
+    }
+
+    /// Records every request the stub receives in `log`.
+    pub fn request_log(&mut self, log: &RequestLog) -> &mut Self {
+        self.config.request_log = Some(log.clone());
+        self
+    }
+
+    /// Appends every request the stub receives to the file at `path`, as a
+    /// line of JSON, for looking at a test's traffic with other tools, or
+    /// after the test has finished.
+    ///
+    /// Each line is an object with the request's `method`, its `uri`, its
+    /// `headers`, as an array of `[name, value]` pairs in the order they were
+    /// received, and its `body`, encoded as standard base64 with padding.
+    /// Header values that aren't valid UTF-8 have invalid sequences replaced
+    /// with U+FFFD.
+    ///
+    /// The file is created if it doesn't exist, and added to if it does, so
+    /// several clients, or several tests, can record to the same file. It's
+    /// opened straight away, so a path that can't be written to, like one in
+    /// a directory that doesn't exist, is reported here, as an error. Like
+    /// with a [`request_log`], each request's body is read in full before the
+    /// handler is called, and its line is written then, with a single write
+    /// straight to the file, without any buffering, so lines from requests
+    /// handled at the same time are never interleaved, and every line is in
+    /// the file by the time the client gets its response. If a write fails,
+    /// the request fails as if the handler had returned an error, so the
+    /// client sees the connection close.
+    ///
+    /// Only available with the `serde` feature.
+    ///
+    /// ```
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// #
+    /// use hyper::Response;
+    /// use hyper_stub::StubBuilder;
+    /// use std::{env, fs, process};
+    ///
+    /// let path = env::temp_dir().join(format!("requests-{}.ndjson", process::id()));
+    /// let client = StubBuilder::new()
+    ///     .record_to_file(&path)
+    ///     .unwrap()
+    ///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+    /// # fs::remove_file(&path).unwrap();
+    /// ```
+    ///
+    /// [`request_log`]: #method.request_log
+    #[cfg(feature = "serde")]
+    pub fn record_to_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<&mut Self> {
+        self.config.record_file = Some(RecordFile::open(path.as_ref())?);
+        Ok(self)
+    }
+
+    /// Keeps reading each request's body after the handler has stopped
+    /// reading it, throwing the rest away, so that the handler can respond
+    /// early (for example, rejecting an upload) while the client finishes
+    /// sending the request.
+    ///
+    /// Without this, a handler that drops the request body unread makes hyper
+    /// stop reading it and close the connection once the response has been
+    /// sent, so the connection can't be reused for the client's next request.
+    ///
+    /// The body is passed to the handler as it arrives, so the handler can
+    /// still read as much of it as it wants. Has no effect if a
+    /// [`request_log`] or [`record_to_file`] is set, since then the whole
+    /// body has already been read by the time the handler is called.
+    ///
+    /// With a [`Transport`] with limited buffering, this is the only way to
+    /// respond early to a large request at all. The client can't read the
+    /// response until it has finished writing the request, so if the stub
+    /// stopped reading, both sides would wait on each other forever.
+    ///
+    /// [`request_log`]: #method.request_log
+    /// [`record_to_file`]: #method.record_to_file
+    /// [`Transport`]: trait.Transport.html
+    pub fn drain_request_bodies(&mut self, enabled: bool) -> &mut Self {
+        self.config.drain_request_bodies = enabled;
+        self
+    }
+
+    /// Limits the request bodies buffered for [`proxy_client_fn_buffered`]
+    /// handlers to `limit` bytes.
+    ///
+    /// A request whose body is longer than that gets a `413 Payload Too
+    /// Large` response, without the handler being called. The stub stops
+    /// reading the body as soon as it goes over the limit, so unless
+    /// [`drain_request_bodies`] is set, the connection is closed after the
+    /// response. Without a limit, every body is buffered in full, however
+    /// large it is.
+    ///
+    /// [`proxy_client_fn_buffered`]: fn.proxy_client_fn_buffered.html
+    /// [`drain_request_bodies`]: #method.drain_request_bodies
+    pub fn max_buffered_body(&mut self, limit: usize) -> &mut Self {
+        self.config.max_buffered_body = Some(limit);
+        self
+    }
+
+    /// Adds a clone of `value` to the extensions of every request the stub's
+    /// handler receives.
+    ///
+    /// Extensions set on a request by the client can't reach the handler,
+    /// because requests are serialized to HTTP/1.1 on the way through the
+    /// stub, and extensions aren't part of that. This is a way for a test to
+    /// pass context directly to the handler instead. The handler can retrieve
+    /// it with `req.extensions().get::<X>()`.
+    ///
+    /// If more than one value of the same type is added, the handler sees the
+    /// last one.
+    pub fn extension<X>(&mut self, value: X) -> &mut Self
+    where
+        X: Clone + Send + Sync + 'static,
+    {
+        self.config
+            .extensions
+            .0
+            .push(Arc::new(move |extensions: &mut http::Extensions| {
+                extensions.insert(value.clone());
+            }));
+        self
+    }
+
+    /// Adds a clone of `value` to the connection information of every
+    /// connection to the stub, so that hyper's client puts it in the
+    /// extensions of every response on the connection, for testing a client
+    /// that looks at connection metadata.
+    ///
+    /// hyper's client gets this information from the [`Connected`] its
+    /// connector returns. A stub's connections don't go over a network, so
+    /// by default theirs has very little in it:
+    ///
+    /// - They're marked as being to a proxy, which is what makes hyper's
+    ///   client send absolute URIs.
+    /// - They're only marked as having negotiated HTTP/2 with
+    ///   [`negotiate_h2`].
+    /// - There's no `HttpInfo`, which hyper's `HttpConnector` adds with the
+    ///   connection's addresses, since there aren't any, and nothing about
+    ///   TLS, since there isn't any.
+    ///
+    /// So a client that looks for a peer certificate, or anything else a
+    /// real connector would add, always finds nothing, unless it's added
+    /// here, with the same type the client looks for. The client can
+    /// retrieve it with `res.extensions().get::<X>()`.
+    ///
+    /// If more than one value of the same type is added, the client sees the
+    /// last one. Values aren't added to connections made by
+    /// [`proxy_client_raw`], [`accept_then_close`], or
+    /// [`reset_after_headers`].
+    ///
+    /// ```
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// #
+    /// use hyper::Response;
+    /// use hyper_stub::StubBuilder;
+    ///
+    /// #[derive(Clone)]
+    /// struct PeerCertificate(Option<Vec<u8>>);
+    ///
+    /// let client = StubBuilder::new()
+    ///     .connection_extra(PeerCertificate(None))
+    ///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+    /// ```
+    ///
+    /// [`Connected`]: https://docs.rs/hyper/0.12/hyper/client/connect/struct.Connected.html
+    /// [`negotiate_h2`]: #method.negotiate_h2
+    /// [`proxy_client_raw`]: #method.proxy_client_raw
+    /// [`accept_then_close`]: #method.accept_then_close
+    /// [`reset_after_headers`]: #method.reset_after_headers
+    pub fn connection_extra<X>(&mut self, value: X) -> &mut Self
+    where
+        X: Clone + Send + Sync + 'static,
+    {
+        self.config
+            .connection_extras
+            .0
+            .push(Arc::new(move |connected: Connected| {
+                connected.extra(value.clone())
+            }));
+        self
+    }
+
+    /// Waits for `delay` before completing every connection to the stub. See
+    /// [`connect_delay_fn`].
+    ///
+    /// [`connect_delay_fn`]: #method.connect_delay_fn
+    pub fn connect_delay(&mut self, delay: Duration) -> &mut Self {
+        self.connect_delay_fn(move |_| delay)
+    }
+
+    /// Waits before completing each connection to the stub, for as long as
+    /// `delay` returns for the connection's destination. This can be used to
+    /// make some hosts slower to connect to than others, as if they took
+    /// longer to resolve. By default, connections complete immediately.
+    ///
+    /// The delay starts when hyper's client asks the stub's connector for a
+    /// connection. The connection's future doesn't create the stub server's
+    /// handler or connect to it until the delay has passed, so, like a real
+    /// slow connect, it only affects requests that need a new connection, and
+    /// not those that reuse one from the client's pool. It's measured by the
+    /// tokio timer, so the client must be run on a tokio runtime.
+    ///
+    /// Connections opened with [`raw_connection_fn`] aren't delayed, because
+    /// they don't have a destination.
+    ///
+    /// ```
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// #
+    /// use hyper::Response;
+    /// use hyper_stub::StubBuilder;
+    /// use std::time::Duration;
+    ///
+    /// let client = StubBuilder::new()
+    ///     .connect_delay_fn(|destination| match destination.host() {
+    ///         "slow.example.com" => Duration::from_secs(2),
+    ///         _ => Duration::from_secs(0),
+    ///     })
+    ///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+    /// ```
+    ///
+    /// [`raw_connection_fn`]: #method.raw_connection_fn
+    pub fn connect_delay_fn<F>(&mut self, delay: F) -> &mut Self
+    where
+        F: Fn(&Destination) -> Duration + Send + Sync + 'static,
+    {
+        self.config.connect_delay = Some(ConnectDelay(Arc::new(delay)));
+        self
+    }
+
+    /// Makes the first connection to the stub fail with an error of the given
+    /// `kind`, like `ConnectionRefused` or `TimedOut`, and every connection
+    /// after it succeed. This is for testing how a client recovers from a
+    /// transient failure to connect, rather than an error response.
+    ///
+    /// The request that needed the connection fails with a hyper error for
+    /// which `is_connect` returns true. Its cause, from `into_cause`, is an
+    /// `io::Error` with `kind`, just as a real connector would report a
+    /// failed TCP connection. hyper's client doesn't retry the request by
+    /// itself: it only retries requests that were sent on a pooled connection
+    /// that turned out to have been closed. Nothing is added to the client's
+    /// pool, so the next request opens a new connection, which succeeds.
+    ///
+    /// Each client made by this builder fails its own first connection. Any
+    /// [`connect_delay`] still passes before the connection fails, so a
+    /// `TimedOut` failure can take as long as a real one would. Connections
+    /// opened with [`raw_connection_fn`] never fail.
+    ///
+    /// ```
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// #
+    /// use hyper::Response;
+    /// use hyper_stub::StubBuilder;
+    /// use std::io;
+    ///
+    /// let client = StubBuilder::new()
+    ///     .connect_fail_once(io::ErrorKind::ConnectionReset)
+    ///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+    /// ```
+    ///
+    /// [`connect_delay`]: #method.connect_delay
+    /// [`raw_connection_fn`]: #method.raw_connection_fn
+    pub fn connect_fail_once(&mut self, kind: io::ErrorKind) -> &mut Self {
+        self.config.connect_fail_once = Some(kind);
+        self
+    }
+
+    /// Calls `hook` with each chunk of every request body, as the stub server
+    /// reads it.
+    ///
+    /// Chunks are seen in the pieces hyper's server decodes them in, which
+    /// follow the chunks of a chunked request body, but may be split further,
+    /// so this is best used to tell whether a client streamed its body at
+    /// all, rather than exactly how it did. A body sent with
+    /// `Content-Length` that was already fully written can arrive in a
+    /// single chunk.
+    ///
+    /// The hook is called while the body is being read, not when the handler
+    /// receives it. With a [`request_log`], which reads each body in full
+    /// before calling the handler, every chunk has been seen by the time the
+    /// handler is called. With [`drain_request_bodies`], the hook also sees
+    /// the chunks that are drained after the handler stops reading.
+    ///
+    /// [`request_log`]: #method.request_log
+    /// [`drain_request_bodies`]: #method.drain_request_bodies
+    pub fn on_request_chunk<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&Bytes) + Send + Sync + 'static,
+    {
+        self.config.on_request_chunk = Some(ChunkHook(Arc::new(hook)));
+        self
+    }
+
+    /// Counts what happens to the stub's connections in `stats`.
+    pub fn stats(&mut self, stats: &Stats) -> &mut Self {
+        self.config.stats = Some(stats.clone());
+        self
+    }
+
+    /// Configures the hyper clients created by this builder with `builder`,
+    /// for example to change how long idle connections are kept in the
+    /// client's pool.
+    ///
+    /// The clients always set the `Host` header, even if `builder` has been
+    /// told not to, because the stub server relies on it.
+    pub fn client_builder(&mut self, builder: &ClientBuilder) -> &mut Self {
+        self.config.client_builder = builder.clone();
+        self
+    }
+
+    /// Catches panics in the stub's handler, instead of letting them take
+    /// down the stub server and leave the client waiting for a response that
+    /// will never come.
+    ///
+    /// When the handler panics, either while being called or while its
+    /// response future is being polled, the panic is recorded in `log` and
+    /// the client receives a `500 Internal Server Error` whose body describes
+    /// the panic. The panic is still printed as usual, so a test that doesn't
+    /// check `log` will at least show what went wrong.
+    ///
+    /// Panics are caught with [`AssertUnwindSafe`], so the handler doesn't
+    /// have to be [`UnwindSafe`]. This means that any state the handler shares
+    /// between requests might be left inconsistent by a panic, and mutexes it
+    /// holds will be poisoned. A panic in a handler is usually a bug in the
+    /// test itself, so it's best to treat the first recorded panic as the one
+    /// that matters.
+    ///
+    /// [`AssertUnwindSafe`]: https://doc.rust-lang.org/std/panic/struct.AssertUnwindSafe.html
+    /// [`UnwindSafe`]: https://doc.rust-lang.org/std/panic/trait.UnwindSafe.html
+    pub fn catch_handler_panics(&mut self, log: &PanicLog) -> &mut Self {
+        self.config.panic_log = Some(log.clone());
+        self
+    }
+
+    /// Makes the stub server speak only HTTP/1.1, like a server whose TLS
+    /// configuration only offers `http/1.1` via ALPN.
+    ///
+    /// By default, the stub server speaks HTTP/1.1, but switches to HTTP/2 if
+    /// a connection starts with the HTTP/2 connection preface, as a client
+    /// with prior knowledge of HTTP/2 sends. With this enabled, that preface
+    /// is a malformed HTTP/1.1 request, so the server closes the connection.
+    /// A client made with `http2_only` set on its [`client_builder`] then
+    /// gets an error, rather than falling back to HTTP/1.1: with prior
+    /// knowledge there's nothing to fall back from, and hyper's client
+    /// doesn't retry.
+    ///
+    /// To test falling back, use [`negotiate_h2`] instead.
+    ///
+    /// [`client_builder`]: #method.client_builder
+    /// [`negotiate_h2`]: #method.negotiate_h2
+    pub fn http1_only(&mut self, enabled: bool) -> &mut Self {
+        self.config.http1_only = enabled;
+        self
+    }
+
+    /// Simulates ALPN for every connection, as if the client had offered both
+    /// `h2` and `http/1.1` during a TLS handshake.
+    ///
+    /// There's no TLS, or any handshake, over a stub's transport, so instead
+    /// the stub's connector tells hyper's client which protocol was
+    /// negotiated, just as a TLS connector would. It picks `h2`, unless
+    /// [`http1_only`] is set, in which case it picks `http/1.1`. When told
+    /// `h2` was negotiated, hyper's client speaks HTTP/2 on the connection,
+    /// even though it wasn't made with `http2_only`, and otherwise it falls
+    /// back to HTTP/1.1. Either way, the response's `version` shows which one
+    /// was used.
+    ///
+    /// ```
+    /// # extern crate futures;
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// # extern crate tokio;
+    /// #
+    /// use futures::Future;
+    /// use hyper::{Response, Version};
+    /// use hyper_stub::StubBuilder;
+    /// use tokio::runtime::current_thread::Runtime;
+    ///
+    /// let client = StubBuilder::new()
+    ///     .negotiate_h2(true)
+    ///     .http1_only(true)
+    ///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+    ///
+    /// let future = client.get("http://example.com".parse().unwrap());
+    /// let response = Runtime::new().unwrap().block_on(future).unwrap();
+    /// assert_eq!(response.version(), Version::HTTP_11);
+    /// ```
+    ///
+    /// [`http1_only`]: #method.http1_only
+    pub fn negotiate_h2(&mut self, enabled: bool) -> &mut Self {
+        self.config.negotiate_h2 = enabled;
+        self
+    }
+
+    /// Creates a client using this configuration. See [`proxy_client`].
+    ///
+    /// [`proxy_client`]: fn.proxy_client.html
+    pub fn proxy_client<ResBody, ResponseError, ServiceError, ResponseFuture, ServiceFuture, S, N>(
+        &self,
+        new_service: N,
+    ) -> Client<Connector<N, T>>
+    where
+        ResBody: Payload,
+        ResponseError: Error + Send + Sync + 'static,
+        ServiceError: Error + Send + Sync + 'static,
+        ResponseFuture: Future<Item = Response<S::ResBody>, Error = ResponseError> + Send + 'static,
+        ServiceFuture: Future<Item = S, Error = ServiceError> + Send + 'static,
+        S: Service<
+                ReqBody = Body,
+                ResBody = ResBody,
+                Error = ResponseError,
+                Future = ResponseFuture,
+            > + Send
+            + 'static,
+        N: NewService<
+                ReqBody = S::ReqBody,
+                ResBody = S::ResBody,
+                Future = ServiceFuture,
+                Error = ResponseError,
+                Service = S,
+                InitError = ServiceError,
+            > + Sync
+            + Send,
+    {
+        let mut builder = self.config.client_builder.clone();
+        builder.set_host(true).build(Connector::new(
+            new_service,
+            self.config.clone(),
+            self.transport.clone(),
+        ))
+    }
+
+    /// Creates a client using this configuration. See [`proxy_client_fn`].
+    ///
+    /// [`proxy_client_fn`]: fn.proxy_client_fn.html
+    pub fn proxy_client_fn<E, Fut, F>(&self, handler: F) -> Client<impl Connect>
+    where
+        E: Error + Send + Sync + 'static,
+        Fut: Future<Item = Response<Body>, Error = E> + Send + 'static,
+        F: Fn(Request<Body>) -> Fut + Send + Sync + Clone + 'static,
+    {
+        use futures::future;
+        use hyper::service::service_fn;
+
+        self.proxy_client(move || future::ok::<_, Never>(service_fn(handler.clone())))
+    }
+
+    /// Creates a client using this configuration. See
+    /// [`proxy_client_fn_into`].
+    ///
+    /// [`proxy_client_fn_into`]: fn.proxy_client_fn_into.html
+    pub fn proxy_client_fn_into<F, R>(&self, handler: F) -> Client<impl Connect>
+    where
+        F: Fn(Request<Body>) -> R + Send + Sync + Clone + 'static,
+        R: IntoResponse,
+    {
+        self.proxy_client_fn_ok(move |req| handler(req).into_response())
+    }
+
+    /// Creates a client using this configuration. See
+    /// [`proxy_client_fn_mut_async`].
+    ///
+    /// [`proxy_client_fn_mut_async`]: fn.proxy_client_fn_mut_async.html
+    pub fn proxy_client_fn_mut_async<E, Fut, F>(&self, handler: F) -> Client<impl Connect>
+    where
+        E: Error + Send + Sync + 'static,
+        Fut: Future<Item = Response<Body>, Error = E> + Send + 'static,
+        F: FnMut(Request<Body>) -> Fut + Send + 'static,
+    {
+        let handler = Arc::new(Mutex::new(handler));
+        self.proxy_client_fn(move |req| {
+            // A handler that panicked can carry on if panics are caught.
+            let mut handler = handler.lock().unwrap_or_else(|err| err.into_inner());
+            handler(req)
+        })
+    }
+
+    /// Creates a client using this configuration. See [`proxy_client_split`].
+    ///
+    /// [`proxy_client_split`]: fn.proxy_client_split.html
+    pub fn proxy_client_split<E, BodyFut, H, B>(&self, head: H, body: B) -> Client<impl Connect>
+    where
+        E: Into<Box<dyn Error + Send + Sync>> + 'static,
+        BodyFut: Future<Item = Bytes, Error = E> + Send + 'static,
+        H: Fn(&Request<Body>) -> Response<()> + Send + Sync + 'static,
+        B: Fn(Request<Body>) -> BodyFut + Send + Sync + 'static,
+    {
+        let head = Arc::new(head);
+        let body = Arc::new(body);
+        self.proxy_client_fn_ok(move |req| {
+            let response = head(&req);
+            let future = body(req);
+            response.map(|()| Body::wrap_stream(future.into_stream()))
+        })
+    }
+
+    /// Creates a client using this configuration's transport and
+    /// [`client_builder`]. See [`proxy_client_raw`].
+    ///
+    /// None of the other options have any effect, because they configure the
+    /// stub server, which isn't used.
+    ///
+    /// [`client_builder`]: #method.client_builder
+    /// [`proxy_client_raw`]: fn.proxy_client_raw.html
+    pub fn proxy_client_raw<F>(&self, handler: F) -> Client<impl Connect>
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let mut builder = self.config.client_builder.clone();
+        builder
+            .set_host(true)
+            .build(RawConnector::new(handler, self.transport.clone()))
+    }
+
+    /// Creates a client using this configuration's transport and
+    /// [`client_builder`]. See [`accept_then_close`].
+    ///
+    /// None of the other options have any effect, because they configure the
+    /// stub server, which isn't used.
+    ///
+    /// [`client_builder`]: #method.client_builder
+    /// [`accept_then_close`]: fn.accept_then_close.html
+    pub fn accept_then_close(&self) -> Client<impl Connect> {
+        let mut builder = self.config.client_builder.clone();
+        builder
+            .set_host(true)
+            .build(ClosingConnector::new(self.transport.clone()))
+    }
+
+    /// Creates a client using this configuration's transport and
+    /// [`client_builder`]. See [`reset_after_headers`].
+    ///
+    /// None of the other options have any effect, because they configure the
+    /// stub server, which isn't used.
+    ///
+    /// [`client_builder`]: #method.client_builder
+    /// [`reset_after_headers`]: fn.reset_after_headers.html
+    pub fn reset_after_headers(&self, error_code: u32) -> Client<impl Connect> {
+        let mut builder = self.config.client_builder.clone();
+        builder
+            .set_host(true)
+            .build(ResettingConnector::new(error_code, self.transport.clone()))
+    }
+
+    /// Creates a client using this configuration. See [`proxy_client_rules`].
+    ///
+    /// [`proxy_client_rules`]: fn.proxy_client_rules.html
+    pub fn proxy_client_rules(
+        &self,
+        rules: Vec<(Matcher, ResponseFactory)>,
+    ) -> Client<impl Connect> {
+        self.proxy_client_fn_ok(rules::rules_handler(rules))
+    }
+
+    /// Creates a client using this configuration. See [`proxy_client_map`].
+    ///
+    /// [`proxy_client_map`]: fn.proxy_client_map.html
+    pub fn proxy_client_map(&self, map: HashMap<Uri, (StatusCode, Bytes)>) -> Client<impl Connect> {
+        self.proxy_client_fn_ok(rules::map_handler(map))
+    }
+
+    /// Creates a client using this configuration. See [`proxy_client_keyed`].
+    ///
+    /// [`proxy_client_keyed`]: fn.proxy_client_keyed.html
+    pub fn proxy_client_keyed<K, X>(
+        &self,
+        extractor: X,
+        map: HashMap<K, ResponseFactory>,
+        default: ResponseFactory,
+    ) -> Client<impl Connect>
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+        X: Fn(&Request<Body>) -> K + Send + Sync + 'static,
+    {
+        self.proxy_client_fn_ok(rules::keyed_handler(extractor, map, default))
+    }
+
+    /// Opens a single connection to a stub server that handles requests with
+    /// `handler`, and returns the client's half of it, without a hyper client
+    /// in between.
+    ///
+    /// This is for tests that need to control exactly what is written to the
+    /// connection, like HTTP/1.1 pipelining, which hyper's client never does.
+    /// The stub server spawns a task to serve the connection, so the returned
+    /// future must be run on a tokio runtime.
+    ///
+    /// hyper's server supports pipelining over any transport: it handles
+    /// pipelined requests one at a time, reading the next request only once
+    /// the response to the previous one has been written, so responses always
+    /// come back in the order the requests were sent, however long each
+    /// handler takes.
+    ///
+    /// ```
+    /// # extern crate futures;
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// # extern crate tokio;
+    /// #
+    /// use futures::{future, Future};
+    /// use hyper::Response;
+    /// use hyper_stub::StubBuilder;
+    /// use tokio::runtime::current_thread::Runtime;
+    ///
+    /// let future = StubBuilder::new()
+    ///     .raw_connection_fn(|_| future::ok::<_, hyper::Error>(Response::new("hi".into())))
+    ///     .and_then(|io| tokio::io::write_all(io, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n"))
+    ///     .and_then(|(io, _)| tokio::io::read_to_end(io, Vec::new()));
+    ///
+    /// let (_, response) = Runtime::new().unwrap().block_on(future).unwrap();
+    /// assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    /// ```
+    pub fn raw_connection_fn<E, Fut, F>(
+        &self,
+        handler: F,
+    ) -> impl Future<Item = T::Client, Error = io::Error>
+    where
+        E: Error + Send + Sync + 'static,
+        Fut: Future<Item = Response<Body>, Error = E> + Send + 'static,
+        F: Fn(Request<Body>) -> Fut + Send + Sync + Clone + 'static,
+    {
+        use futures::future;
+        use hyper::service::service_fn;
+
+        let new_service = move || future::ok::<_, Never>(service_fn(handler.clone()));
+        Connector::new(new_service, self.config.clone(), self.transport.clone())
+            .connect_raw(None)
+            .map(|(io, _)| io)
+            .map_err(io::Error::other)
+    }
+
+    /// Creates a client using this configuration. See [`proxy_client_fn_ok`].
+    ///
+    /// [`proxy_client_fn_ok`]: fn.proxy_client_fn_ok.html
+    pub fn proxy_client_fn_ok<F>(&self, handler: F) -> Client<impl Connect>
+    where
+        F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+    {
+        use futures::future;
+
+        self.proxy_client_fn(move |req| future::ok::<_, Never>(handler(req)))
+    }
+
+    /// Creates a client using this configuration. See [`proxy_client_fn_io`].
+    ///
+    /// [`proxy_client_fn_io`]: fn.proxy_client_fn_io.html
+    pub fn proxy_client_fn_io<F>(&self, handler: F) -> Client<impl Connect>
+    where
+        F: Fn(Request<Body>) -> io::Result<Response<Body>> + Send + Sync + Clone + 'static,
+    {
+        use futures::future;
+
+        self.proxy_client_fn(move |req| future::result(handler(req)))
+    }
+
+    /// Creates a client using this configuration. See
+    /// [`proxy_client_fn_buffered`].
+    ///
+    /// [`proxy_client_fn_buffered`]: fn.proxy_client_fn_buffered.html
+    pub fn proxy_client_fn_buffered<F>(&self, handler: F) -> Client<impl Connect>
+    where
+        F: Fn(Request<Bytes>) -> Response<Body> + Send + Sync + Clone + 'static,
+    {
+        let limit = self.config.max_buffered_body;
+        self.proxy_client_fn(move |req| {
+            let handler = handler.clone();
+            let (parts, body) = req.into_parts();
+
+            // Going over the limit is reported as an error of None, to stop
+            // reading the body.
+            body.map_err(Some)
+                .fold(BytesMut::new(), move |mut buf, chunk| {
+                    if limit.is_some_and(|limit| buf.len() + chunk.len() > limit) {
+                        return Err(None);
+                    }
+                    buf.extend_from_slice(&chunk);
+                    Ok(buf)
+                })
+                .then(move |result| match result {
+                    Ok(buf) => Ok(handler(Request::from_parts(parts, buf.freeze()))),
+                    Err(None) => {
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                        Ok(response)
+                    }
+                    Err(Some(err)) => Err(err),
+                })
+        })
+    }
+
+    /// Creates a client using this configuration, along with a future that
+    /// completes once the stub server has finished with all of its
+    /// connections. See [`proxy_client_fn_ok_with_drain`].
+    ///
+    /// [`proxy_client_fn_ok_with_drain`]: fn.proxy_client_fn_ok_with_drain.html
+    pub fn proxy_client_fn_ok_with_drain<F>(
+        &self,
+        handler: F,
+    ) -> (Client<impl Connect>, impl Future<Item = (), Error = ()>)
+    where
+        F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(0);
+        let mut builder = self.clone();
+        builder.config.connection_tracker = Some(sender);
+
+        let client = builder.proxy_client_fn_ok(handler);
+        let drain = receiver.for_each(|never: Never| -> Result<(), ()> { match never {} });
+        (client, drain)
+    }
+
+    /// Creates a client using this configuration, along with a future that
+    /// completes once the stub server has finished with all of its
+    /// connections and side effects. See [`proxy_client_fn_side_effect`].
+    ///
+    /// [`proxy_client_fn_side_effect`]: fn.proxy_client_fn_side_effect.html
+    pub fn proxy_client_fn_side_effect<F, E>(
+        &self,
+        handler: F,
+    ) -> (Client<impl Connect>, impl Future<Item = (), Error = ()>)
+    where
+        F: Fn(Request<Body>) -> (Response<Body>, E) + Send + Sync + Clone + 'static,
+        E: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(0);
+        let mut builder = self.clone();
+        builder.config.connection_tracker = Some(sender.clone());
+
+        // Each side effect holds a clone of the sender until it's done, so
+        // the drain future waits for it like it does for connections.
+        let client = builder.proxy_client_fn_ok(move |req| {
+            let (response, effect) = handler(req);
+            let tracker = sender.clone();
+            tokio::spawn(effect.then(move |_| {
+                drop(tracker);
+                Ok(())
+            }));
+            response
+        });
+        let drain = receiver.for_each(|never: Never| -> Result<(), ()> { match never {} });
+        (client, drain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_byte_fragments() {
+        use futures::prelude::*;
+        use hyper::header::CONTENT_TYPE;
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = StubBuilder::new()
+            .write_fragment_size(1)
+            .yield_between_fragments(true)
+            .proxy_client_fn_ok(|_| {
+                Response::builder()
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body("fragmented response".into())
+                    .unwrap()
+            });
+
+        Runtime::new()
+            .unwrap()
+            .block_on({
+                client
+                    .get("http://example.com".parse().unwrap())
+                    .and_then(|res| {
+                        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+                        res.into_body().concat2()
+                    })
+                    .map(|bytes| assert_eq!(&bytes[..], b"fragmented response"))
+                    .map_err(|err| panic!("{:?}", err))
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_extensions() {
+        use tokio::runtime::current_thread::Runtime;
+
+        #[derive(Clone, Debug, PartialEq)]
+        struct FromClient;
+
+        #[derive(Clone, Debug, PartialEq)]
+        struct Context(&'static str);
+
+        let client = StubBuilder::new()
+            .extension(Context("from the test"))
+            .proxy_client_fn_ok(|req| {
+                assert!(req.extensions().get::<FromClient>().is_none());
+                let context = req.extensions().get::<Context>().unwrap();
+                Response::new(context.0.into())
+            });
+
+        let mut request = Request::get("http://example.com")
+            .body(Body::empty())
+            .unwrap();
+        request.extensions_mut().insert(FromClient);
+
+        let future = client
+            .request(request)
+            .and_then(|res| res.into_body().concat2());
+        let body = Runtime::new().unwrap().block_on(future).unwrap();
+        assert_eq!(&body[..], b"from the test");
+    }
+
+    #[test]
+    fn test_on_request_chunk() {
+        use futures::stream;
+        use std::sync::Mutex;
+        use tokio::runtime::current_thread::Runtime;
+
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        let hook_chunks = chunks.clone();
+        let client = StubBuilder::new()
+            .on_request_chunk(move |chunk| hook_chunks.lock().unwrap().push(chunk.clone()))
+            .proxy_client_fn_ok(|req| Response::new(req.into_body()));
+
+        let parts = vec![Ok::<_, io::Error>("one "), Ok("two "), Ok("three")];
+        let request = Request::post("http://example.com")
+            .body(Body::wrap_stream(stream::iter_result(parts)))
+            .unwrap();
+        let future = client
+            .request(request)
+            .and_then(|res| res.into_body().concat2());
+        let body = Runtime::new().unwrap().block_on(future).unwrap();
+
+        assert_eq!(&body[..], b"one two three");
+        assert_eq!(*chunks.lock().unwrap(), ["one ", "two ", "three"]);
+    }
+
+    #[test]
+    fn test_body_delay() {
+        use hyper::header::CONTENT_LENGTH;
+        use std::time::Instant;
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = StubBuilder::new()
+            .body_delay(Duration::from_millis(200))
+            .proxy_client_fn_ok(|_| Response::new("hello".into()));
+
+        let mut runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        let response = runtime
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+
+        let body = runtime.block_on(response.into_body().concat2()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(&body[..], b"hello");
+    }
+
+    #[test]
+    fn test_handshake_delay() {
+        use std::time::Instant;
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = StubBuilder::new()
+            .handshake_delay(Duration::from_millis(200))
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let mut runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        runtime
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // The second request reuses the connection, so isn't delayed again.
+        let start = Instant::now();
+        runtime
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_drain() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::{Duration, Instant};
+        use tokio::runtime::current_thread::Runtime;
+        use tokio::timer::Delay;
+
+        let (client, drain) =
+            StubBuilder::new().proxy_client_fn_ok_with_drain(|_| Response::new(Body::empty()));
+
+        let drained = Arc::new(AtomicBool::new(false));
+        let mut runtime = Runtime::new().unwrap();
+        let flag = drained.clone();
+        runtime.spawn(drain.map(move |()| flag.store(true, Ordering::SeqCst)));
+
+        for _ in 0..2 {
+            let future = client.get("http://example.com".parse().unwrap());
+            runtime.block_on(future).unwrap();
+        }
+
+        // The connection is still open, in the client's pool.
+        let delay = Delay::new(Instant::now() + Duration::from_millis(50));
+        runtime.block_on(delay).unwrap();
+        assert!(!drained.load(Ordering::SeqCst));
+
+        drop(client);
+        runtime.run().unwrap();
+        assert!(drained.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_pipelining() {
+        use futures::future;
+        use std::time::{Duration, Instant};
+        use tokio::runtime::current_thread::Runtime;
+        use tokio::timer::Delay;
+
+        // The first response is the slowest, so if responses weren't sent in
+        // order, it would come last.
+        let handler = |req: Request<Body>| {
+            let delay = match req.uri().path() {
+                "/1" => Duration::from_millis(100),
+                _ => Duration::from_millis(0),
+            };
+            let response = Response::new(req.uri().path().to_string().into());
+            Delay::new(Instant::now() + delay).map(|_| response)
+        };
+
+        let requests = "GET /1 HTTP/1.1\r\nHost: example.com\r\n\r\n\
+                        GET /2 HTTP/1.1\r\nHost: example.com\r\n\r\n\
+                        GET /3 HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+
+        let future = StubBuilder::new()
+            .raw_connection_fn(handler)
+            .and_then(move |io| ::tokio::io::write_all(io, requests))
+            .and_then(|(io, _)| ::tokio::io::read_to_end(io, Vec::new()))
+            .and_then(|(_, response)| future::ok(String::from_utf8(response).unwrap()));
+        let response = Runtime::new().unwrap().block_on(future).unwrap();
+
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+        let positions: Vec<_> = ["\r\n\r\n/1", "\r\n\r\n/2", "\r\n\r\n/3"]
+            .iter()
+            .map(|body| response.find(body).unwrap())
+            .collect();
+        assert!(positions[0] < positions[1] && positions[1] < positions[2]);
+    }
+
+    #[test]
+    fn test_protocol_negotiation() {
+        use futures::prelude::*;
+        use hyper::Version;
+        use tokio::runtime::current_thread::Runtime;
+
+        let version = |builder: &mut StubBuilder| {
+            let client = builder
+                .proxy_client_fn_ok(|req| Response::new(format!("{:?}", req.version()).into()));
+            let future = client
+                .get("http://example.com".parse().unwrap())
+                .and_then(|res| {
+                    let version = res.version();
+                    res.into_body().concat2().map(move |body| (version, body))
+                });
+            let (version, body) = Runtime::new().unwrap().block_on(future).unwrap();
+            (version, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        assert_eq!(
+            version(StubBuilder::new().negotiate_h2(true)),
+            (Version::HTTP_2, "HTTP/2.0".to_string())
+        );
+        assert_eq!(
+            version(StubBuilder::new().negotiate_h2(true).http1_only(true)),
+            (Version::HTTP_11, "HTTP/1.1".to_string())
+        );
+        assert_eq!(
+            version(&mut StubBuilder::new()),
+            (Version::HTTP_11, "HTTP/1.1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_prior_knowledge_h2() {
+        use hyper::Version;
+        use tokio::runtime::current_thread::Runtime;
+
+        let mut runtime = Runtime::new().unwrap();
+        let client = StubBuilder::new()
+            .client_builder(Client::builder().http2_only(true))
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+        let future = client.get("http://example.com".parse().unwrap());
+        assert_eq!(runtime.block_on(future).unwrap().version(), Version::HTTP_2);
+
+        let client = StubBuilder::new()
+            .client_builder(Client::builder().http2_only(true))
+            .http1_only(true)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+        let future = client.get("http://example.com".parse().unwrap());
+        assert!(runtime.block_on(future).is_err());
+    }
+}
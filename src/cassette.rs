@@ -0,0 +1,474 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use hyper::{Body, Chunk, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::{self, Delay};
+
+/// A VCR-style recording of interactions with a handler, which can later be
+/// replayed without the original handler.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use futures::{future, Future};
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_fn, Cassette};
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let cassette = Cassette::new();
+/// let mut runtime = Runtime::new().unwrap();
+///
+/// let recording = proxy_client_fn(cassette.record(|_| {
+///     future::ok::<_, hyper::Error>(Response::new("hello".into()))
+/// }));
+/// let future = recording.get("http://example.com".parse().unwrap());
+/// runtime.block_on(future).unwrap();
+///
+/// let replaying = proxy_client_fn(cassette.replay().handler());
+/// let future = replaying.get("http://example.com".parse().unwrap());
+/// runtime.block_on(future).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Cassette {
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+/// A single request and response, recorded in a [`Cassette`].
+///
+/// [`Cassette`]: struct.Cassette.html
+#[derive(Clone, Debug)]
+pub struct Interaction {
+    method: Method,
+    uri: Uri,
+    request_headers: HeaderMap,
+    request_body: Bytes,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    elapsed: Duration,
+}
+
+impl Interaction {
+    /// Returns the method of the recorded request.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Returns the URI of the recorded request.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Returns the headers of the recorded request.
+    pub fn request_headers(&self) -> &HeaderMap {
+        &self.request_headers
+    }
+
+    /// Returns the body of the recorded request, as far as the handler had
+    /// read it by the time the response body was read to the end.
+    ///
+    /// The body is recorded as the handler reads it, rather than being read
+    /// in full first, so that the handler sees the request as it was sent. A
+    /// handler that responds without reading the body leaves this empty.
+    pub fn request_body(&self) -> &Bytes {
+        &self.request_body
+    }
+
+    /// Returns the status of the recorded response.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the headers of the recorded response.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Returns the body of the recorded response.
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Returns how long the handler took to produce the response (not
+    /// including its body), measured with [`Instant`].
+    ///
+    /// [`Instant`]: https://doc.rust-lang.org/std/time/struct.Instant.html
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+impl Cassette {
+    /// Creates an empty cassette.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the interactions recorded so far, in the order their responses
+    /// finished.
+    pub fn interactions(&self) -> Vec<Interaction> {
+        self.interactions.lock().unwrap().clone()
+    }
+
+    /// Returns the number of interactions recorded so far.
+    pub fn len(&self) -> usize {
+        self.interactions.lock().unwrap().len()
+    }
+
+    // Like interactions, but ignores poisoning, for use while unwinding, when
+    // panicking again would abort.
+    pub(crate) fn snapshot(&self) -> Vec<Interaction> {
+        match self.interactions.lock() {
+            Ok(interactions) => interactions.clone(),
+            Err(err) => err.into_inner().clone(),
+        }
+    }
+
+    /// Returns whether no interactions have been recorded yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wraps `handler`, recording every request it handles and the response it
+    /// gives into this cassette.
+    ///
+    /// An interaction is recorded once the response body has been read to the
+    /// end.
+    pub fn record<F, Fut>(
+        &self,
+        handler: F,
+    ) -> impl Fn(Request<Body>) -> RecordFuture<Fut> + Send + Sync + Clone + 'static
+    where
+        F: Fn(Request<Body>) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Item = Response<Body>>,
+    {
+        let interactions = self.interactions.clone();
+        move |req| {
+            // The rest of the interaction is filled in as the response arrives.
+            let interaction = Interaction {
+                method: req.method().clone(),
+                uri: req.uri().clone(),
+                request_headers: req.headers().clone(),
+                request_body: Bytes::new(),
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: Bytes::new(),
+                elapsed: Duration::from_secs(0),
+            };
+
+            let request_body = Arc::new(Mutex::new(Vec::new()));
+            let req = req.map(|body| {
+                Body::wrap_stream(TeeBody {
+                    body,
+                    buf: request_body.clone(),
+                })
+            });
+
+            RecordFuture {
+                start: Instant::now(),
+                future: handler(req),
+                request_body,
+                interaction: Some(interaction),
+                interactions: interactions.clone(),
+            }
+        }
+    }
+
+    /// Returns a [`Replay`], which can be used to create a handler that
+    /// replays the interactions in this cassette.
+    ///
+    /// [`Replay`]: struct.Replay.html
+    pub fn replay(&self) -> Replay {
+        Replay {
+            cassette: self.clone(),
+            preserve_timing: false,
+        }
+    }
+}
+
+/// The future returned by handlers created by [`Cassette::record`].
+///
+/// [`Cassette::record`]: struct.Cassette.html#method.record
+pub struct RecordFuture<Fut> {
+    future: Fut,
+    start: Instant,
+    request_body: Arc<Mutex<Vec<u8>>>,
+    interaction: Option<Interaction>,
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+impl<Fut> Future for RecordFuture<Fut>
+where
+    Fut: Future<Item = Response<Body>>,
+{
+    type Item = Response<Body>;
+    type Error = Fut::Error;
+
+    fn poll(&mut self) -> Poll<Response<Body>, Fut::Error> {
+        let response = try_ready!(self.future.poll());
+        let mut interaction = self
+            .interaction
+            .take()
+            .expect("RecordFuture polled after completion");
+
+        interaction.elapsed = self.start.elapsed();
+        interaction.status = response.status();
+        interaction.headers = response.headers().clone();
+
+        let (parts, body) = response.into_parts();
+        let body = RecordBody {
+            body,
+            buf: Vec::new(),
+            request_body: self.request_body.clone(),
+            interaction: Some(interaction),
+            interactions: self.interactions.clone(),
+        };
+
+        Ok(Async::Ready(Response::from_parts(
+            parts,
+            Body::wrap_stream(body),
+        )))
+    }
+}
+
+// Passes a request body through to the handler, keeping a copy of what it
+// reads for the interaction.
+struct TeeBody {
+    body: Body,
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Stream for TeeBody {
+    type Item = Chunk;
+    type Error = ::hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, ::hyper::Error> {
+        let chunk = try_ready!(self.body.poll());
+        if let Some(ref chunk) = chunk {
+            self.buf
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .extend_from_slice(chunk);
+        }
+        Ok(Async::Ready(chunk))
+    }
+}
+
+// Passes a response body through, and records the interaction it belongs to
+// once it has been read to the end.
+struct RecordBody {
+    body: Body,
+    buf: Vec<u8>,
+    request_body: Arc<Mutex<Vec<u8>>>,
+    interaction: Option<Interaction>,
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+impl Stream for RecordBody {
+    type Item = Chunk;
+    type Error = ::hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, ::hyper::Error> {
+        let chunk = try_ready!(self.body.poll());
+        match chunk {
+            Some(ref chunk) => self.buf.extend_from_slice(chunk),
+            None => {
+                if let Some(mut interaction) = self.interaction.take() {
+                    interaction.body = mem::take(&mut self.buf).into();
+                    let request_body = self.request_body.lock().unwrap_or_else(|e| e.into_inner());
+                    interaction.request_body = request_body.clone().into();
+                    self.interactions
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(interaction);
+                }
+            }
+        }
+        Ok(Async::Ready(chunk))
+    }
+}
+
+/// Options for replaying the interactions recorded in a [`Cassette`].
+///
+/// [`Cassette`]: struct.Cassette.html
+#[derive(Clone, Debug)]
+pub struct Replay {
+    cassette: Cassette,
+    preserve_timing: bool,
+}
+
+impl Replay {
+    /// Sets whether to delay each replayed response by however long the
+    /// original handler took to produce it (see [`Interaction::elapsed`]).
+    ///
+    /// Default is `false`, meaning responses are replayed immediately.
+    ///
+    /// [`Interaction::elapsed`]: struct.Interaction.html#method.elapsed
+    pub fn preserve_timing(mut self, enabled: bool) -> Self {
+        self.preserve_timing = enabled;
+        self
+    }
+
+    /// Creates a handler that replays the cassette's interactions.
+    ///
+    /// Each request is answered with the first interaction with the same
+    /// method and URI that hasn't already been replayed. If there isn't one,
+    /// the response is `404 Not Found`.
+    pub fn handler(self) -> impl Fn(Request<Body>) -> ReplayFuture + Send + Sync + Clone + 'static {
+        let remaining = Arc::new(Mutex::new(self.cassette.interactions()));
+        let preserve_timing = self.preserve_timing;
+
+        move |req| {
+            let mut remaining = remaining.lock().unwrap();
+            let position = remaining
+                .iter()
+                .position(|i| i.method == req.method() && i.uri == *req.uri());
+
+            match position.map(|position| remaining.remove(position)) {
+                Some(interaction) => ReplayFuture {
+                    delay: if preserve_timing {
+                        Some(Delay::new(Instant::now() + interaction.elapsed))
+                    } else {
+                        None
+                    },
+                    response: Some(interaction.response()),
+                },
+
+                None => {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    ReplayFuture {
+                        delay: None,
+                        response: Some(response),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The future returned by handlers created by [`Replay::handler`].
+///
+/// [`Replay::handler`]: struct.Replay.html#method.handler
+pub struct ReplayFuture {
+    delay: Option<Delay>,
+    response: Option<Response<Body>>,
+}
+
+impl Future for ReplayFuture {
+    type Item = Response<Body>;
+    type Error = timer::Error;
+
+    fn poll(&mut self) -> Poll<Response<Body>, timer::Error> {
+        if let Some(ref mut delay) = self.delay {
+            try_ready!(delay.poll());
+        }
+
+        let response = self
+            .response
+            .take()
+            .expect("ReplayFuture polled after completion");
+        Ok(Async::Ready(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use hyper::client::connect::Connect;
+    use hyper::Client;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn get<C: Connect + 'static>(client: &Client<C>, path: &str) -> (Response<()>, Duration) {
+        let start = Instant::now();
+        let uri = format!("http://example.com{}", path).parse().unwrap();
+        let (parts, body) = Runtime::new()
+            .unwrap()
+            .block_on(client.get(uri).and_then(|res| {
+                let (parts, body) = res.into_parts();
+                body.concat2().map(|body| (parts, body))
+            }))
+            .unwrap();
+        assert_eq!(&body[..], path.as_bytes());
+        (Response::from_parts(parts, ()), start.elapsed())
+    }
+
+    fn slow_handler(
+        req: Request<Body>,
+    ) -> impl Future<Item = Response<Body>, Error = timer::Error> {
+        let duration = match req.uri().path() {
+            "/slow" => Duration::from_millis(100),
+            _ => Duration::from_millis(0),
+        };
+        let response = Response::new(req.uri().path().to_string().into());
+        Delay::new(Instant::now() + duration).map(|_| response)
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let cassette = Cassette::new();
+        let recording = ::proxy_client_fn(cassette.record(|req| {
+            let mut response = Response::new(req.uri().path().to_string().into());
+            *response.status_mut() = StatusCode::CREATED;
+            future::ok::<_, hyper::Error>(response)
+        }));
+        get(&recording, "/a");
+        get(&recording, "/b");
+
+        assert_eq!(cassette.len(), 2);
+        assert_eq!(cassette.interactions()[1].uri(), "http://example.com/b");
+        assert_eq!(&cassette.interactions()[1].body()[..], b"/b");
+        assert_eq!(
+            cassette.interactions()[1].request_headers()["host"],
+            "example.com"
+        );
+
+        let replaying = ::proxy_client_fn(cassette.replay().handler());
+        assert_eq!(get(&replaying, "/b").0.status(), StatusCode::CREATED);
+        assert_eq!(get(&replaying, "/a").0.status(), StatusCode::CREATED);
+
+        let uri = "http://example.com/a".parse().unwrap();
+        let response = Runtime::new()
+            .unwrap()
+            .block_on(replaying.get(uri))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_preserve_timing() {
+        let cassette = Cassette::new();
+        let recording = ::proxy_client_fn(cassette.record(slow_handler));
+        get(&recording, "/fast");
+        get(&recording, "/slow");
+
+        let recorded = cassette.interactions()[1].elapsed();
+        assert!(recorded >= Duration::from_millis(100));
+
+        let replaying = ::proxy_client_fn(cassette.replay().handler());
+        assert!(get(&replaying, "/slow").1 < Duration::from_millis(100));
+
+        let replaying = ::proxy_client_fn(cassette.replay().preserve_timing(true).handler());
+        assert!(get(&replaying, "/fast").1 < Duration::from_millis(100));
+        let replayed = get(&replaying, "/slow").1;
+        assert!(replayed >= recorded);
+        assert!(replayed < recorded + Duration::from_millis(500));
+    }
+}
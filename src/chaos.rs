@@ -0,0 +1,361 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::future;
+use futures::prelude::*;
+use handlers::deadline;
+use hyper::{Body, Request, Response, StatusCode};
+use std::f64::consts::PI;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::timer::Delay;
+
+/// Something a [`seeded_stub`] can do with a request.
+///
+/// [`seeded_stub`]: fn.seeded_stub.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Behavior {
+    /// Respond with the stub's handler.
+    Ok,
+
+    /// Respond with the stub's handler, after the given delay.
+    Delay(Duration),
+
+    /// Respond with the stub's handler, after a delay drawn at random from
+    /// the given distribution.
+    Latency(LatencyProfile),
+
+    /// Respond with the given status and an empty body, without calling the
+    /// stub's handler.
+    Error(StatusCode),
+
+    /// Close the connection without responding, so the client sees the
+    /// connection fail.
+    Drop,
+}
+
+/// A distribution of delays, for [`jittered`] and [`Behavior::Latency`].
+///
+/// Delays are drawn using the same generator that [`seeded_stub`] uses to
+/// pick behaviors, so they're reproducible for a given seed too.
+///
+/// [`jittered`]: fn.jittered.html
+/// [`Behavior::Latency`]: enum.Behavior.html#variant.Latency
+/// [`seeded_stub`]: fn.seeded_stub.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LatencyProfile {
+    /// Any delay between `min` and `max`, inclusive, with equal probability.
+    /// If `max` is less than `min`, the delay is always `min`.
+    Uniform { min: Duration, max: Duration },
+
+    /// A normally distributed delay, generated with the Box-Muller
+    /// transform. Samples below zero, which are likely when `stddev` is large
+    /// compared to `mean`, are clamped to zero, so the response is sent
+    /// without any delay. Samples too large for a `Duration` are clamped to
+    /// `Duration::MAX`.
+    Normal { mean: Duration, stddev: Duration },
+}
+
+impl LatencyProfile {
+    pub(crate) fn sample(&self, rng: &mut SplitMix64) -> Duration {
+        match *self {
+            LatencyProfile::Uniform { min, max } => {
+                let range = max.checked_sub(min).unwrap_or_default();
+                let nanos = range.as_nanos().min(u128::from(u64::MAX - 1)) as u64;
+                min + Duration::from_nanos(rng.next() % (nanos + 1))
+            }
+
+            LatencyProfile::Normal { mean, stddev } => {
+                // u1 is in (0, 1], so that its logarithm is finite.
+                let u1 = ((rng.next() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+                let u2 = (rng.next() >> 11) as f64 / (1u64 << 53) as f64;
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+                let secs = mean.as_secs_f64() + z * stddev.as_secs_f64();
+                // Samples too large for a Duration, with a huge stddev,
+                // saturate rather than panicking on the server's task.
+                Duration::try_from_secs_f64(secs.max(0.0)).unwrap_or(Duration::MAX)
+            }
+        }
+    }
+}
+
+/// Wraps `handler` so that each request gets one of `behaviors`, picked at
+/// random from a sequence determined by `seed`.
+///
+/// Each behavior is paired with a weight, and is picked with probability
+/// proportional to it, so `[(3, Behavior::Ok), (1, Behavior::Drop)]` drops
+/// about one request in four. A behavior with a weight of 0 is never picked.
+///
+/// The random numbers come from [SplitMix64], seeded with `seed`. It's fast
+/// and simple, and not at all suitable for anything but tests. One generator
+/// is shared by every connection to the stub, and advanced once per request
+/// to pick a behavior, then again for [`Behavior::Latency`] to draw the delay,
+/// so the same seed always gives the same sequence of behaviors and delays,
+/// as long as the requests arrive in the same order. The generator is part of
+/// this crate, so the sequence for a seed won't change when dependencies are
+/// updated.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::{Response, StatusCode};
+/// use hyper_stub::{proxy_client_fn, seeded_stub, Behavior};
+/// use std::time::Duration;
+///
+/// let handler = seeded_stub(
+///     42,
+///     &[
+///         (6, Behavior::Ok),
+///         (2, Behavior::Delay(Duration::from_millis(10))),
+///         (1, Behavior::Error(StatusCode::SERVICE_UNAVAILABLE)),
+///         (1, Behavior::Drop),
+///     ],
+///     |_| Response::new("hello".into()),
+/// );
+/// let client = proxy_client_fn(handler);
+/// ```
+///
+/// # Panics
+///
+/// Panics if `behaviors` is empty, or if every weight is 0.
+///
+/// [SplitMix64]: http://prng.di.unimi.it/splitmix64.c
+/// [`Behavior::Latency`]: enum.Behavior.html#variant.Latency
+pub fn seeded_stub<F>(
+    seed: u64,
+    behaviors: &[(u32, Behavior)],
+    handler: F,
+) -> impl Fn(Request<Body>) -> SeededFuture + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    let total: u64 = behaviors.iter().map(|&(weight, _)| u64::from(weight)).sum();
+    assert!(
+        total > 0,
+        "seeded_stub needs a behavior with a non-zero weight"
+    );
+
+    let behaviors = Arc::new(behaviors.to_vec());
+    let rng = Arc::new(Mutex::new(SplitMix64(seed)));
+
+    move |req| {
+        let mut rng = rng.lock().unwrap();
+        let mut choice = rng.next() % total;
+        let behavior = behaviors
+            .iter()
+            .find(|&&(weight, _)| {
+                let found = choice < u64::from(weight);
+                choice = choice.saturating_sub(u64::from(weight));
+                found
+            })
+            .map(|&(_, behavior)| behavior)
+            .unwrap();
+
+        let future: Box<dyn Future<Item = _, Error = _> + Send> = match behavior {
+            Behavior::Ok => {
+                drop(rng);
+                Box::new(future::ok(handler(req)))
+            }
+
+            Behavior::Delay(delay) => delayed_response(delay, handler.clone(), req),
+
+            Behavior::Latency(profile) => {
+                let delay = profile.sample(&mut rng);
+                delayed_response(delay, handler.clone(), req)
+            }
+
+            Behavior::Error(status) => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = status;
+                Box::new(future::ok(response))
+            }
+
+            Behavior::Drop => Box::new(future::err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "connection dropped by seeded stub",
+            ))),
+        };
+
+        SeededFuture { inner: future }
+    }
+}
+
+fn delayed_response<F>(
+    delay: Duration,
+    handler: F,
+    req: Request<Body>,
+) -> Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send>
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + 'static,
+{
+    let future = Delay::new(deadline(delay))
+        .map_err(io::Error::other)
+        .and_then(move |()| future::ok(handler(req)));
+    Box::new(future)
+}
+
+/// The future returned by handlers created by [`seeded_stub`].
+///
+/// [`seeded_stub`]: fn.seeded_stub.html
+pub struct SeededFuture {
+    inner: Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send>,
+}
+
+impl Future for SeededFuture {
+    type Item = Response<Body>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Response<Body>, io::Error> {
+        self.inner.poll()
+    }
+}
+
+// See http://prng.di.unimi.it/splitmix64.c.
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn outcomes(seed: u64) -> Vec<Option<u16>> {
+        let handler = seeded_stub(
+            seed,
+            &[
+                (2, Behavior::Ok),
+                (1, Behavior::Delay(Duration::from_millis(1))),
+                (1, Behavior::Error(StatusCode::SERVICE_UNAVAILABLE)),
+                (1, Behavior::Drop),
+                (0, Behavior::Error(StatusCode::IM_A_TEAPOT)),
+            ],
+            |_| Response::new(Body::empty()),
+        );
+        let client = ::proxy_client_fn(handler);
+
+        let mut runtime = Runtime::new().unwrap();
+        (0..20)
+            .map(|_| {
+                let future = client.get("http://example.com".parse().unwrap());
+                runtime
+                    .block_on(future)
+                    .ok()
+                    .map(|res| res.status().as_u16())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_splitmix64() {
+        // The first outputs for seed 1234567 from the reference implementation.
+        let mut rng = SplitMix64(1_234_567);
+        assert_eq!(rng.next(), 6_457_827_717_110_365_317);
+        assert_eq!(rng.next(), 3_203_168_211_198_807_973);
+    }
+
+    #[test]
+    fn test_latency_profile_bounds() {
+        let mut rng = SplitMix64(42);
+        let uniform = LatencyProfile::Uniform {
+            min: Duration::from_millis(10),
+            max: Duration::from_millis(20),
+        };
+        let samples: Vec<_> = (0..1000).map(|_| uniform.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|&d| d >= Duration::from_millis(10)));
+        assert!(samples.iter().all(|&d| d <= Duration::from_millis(20)));
+        assert!(samples.iter().any(|&d| d < Duration::from_millis(12)));
+        assert!(samples.iter().any(|&d| d > Duration::from_millis(18)));
+
+        let normal = LatencyProfile::Normal {
+            mean: Duration::from_millis(100),
+            stddev: Duration::from_millis(10),
+        };
+        let samples: Vec<_> = (0..1000).map(|_| normal.sample(&mut rng)).collect();
+        let mean = samples.iter().sum::<Duration>() / 1000;
+        assert!(mean > Duration::from_millis(98) && mean < Duration::from_millis(102));
+        // More than six standard deviations out is vanishingly unlikely.
+        assert!(samples.iter().all(|&d| d > Duration::from_millis(40)));
+        assert!(samples.iter().all(|&d| d < Duration::from_millis(160)));
+
+        let clamped = LatencyProfile::Normal {
+            mean: Duration::from_millis(0),
+            stddev: Duration::from_millis(10),
+        };
+        let samples: Vec<_> = (0..100).map(|_| clamped.sample(&mut rng)).collect();
+        assert!(samples.contains(&Duration::from_millis(0)));
+        assert!(samples.iter().any(|&d| d > Duration::from_millis(0)));
+
+        let huge = LatencyProfile::Normal {
+            mean: Duration::MAX,
+            stddev: Duration::MAX,
+        };
+        let samples: Vec<_> = (0..100).map(|_| huge.sample(&mut rng)).collect();
+        assert!(samples.contains(&Duration::MAX));
+    }
+
+    #[test]
+    fn test_huge_delay() {
+        use tokio::timer::Timeout;
+
+        let huge = LatencyProfile::Uniform {
+            min: Duration::MAX,
+            max: Duration::MAX,
+        };
+        for &behavior in &[Behavior::Delay(Duration::MAX), Behavior::Latency(huge)] {
+            let handler = seeded_stub(7, &[(1, behavior)], |_| Response::new(Body::empty()));
+            let client = ::proxy_client_fn(handler);
+
+            // The response never arrives, but the stub doesn't fail either.
+            let future = client.get("http://example.com".parse().unwrap());
+            let future = Timeout::new(future, Duration::from_millis(100));
+            let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+            assert!(error.is_elapsed(), "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_latency() {
+        use std::time::Instant;
+
+        let profile = LatencyProfile::Uniform {
+            min: Duration::from_millis(20),
+            max: Duration::from_millis(40),
+        };
+        let handler = seeded_stub(7, &[(1, Behavior::Latency(profile))], |_| {
+            Response::new(Body::empty())
+        });
+        let client = ::proxy_client_fn(handler);
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..5 {
+            let start = Instant::now();
+            let future = client.get("http://example.com".parse().unwrap());
+            assert_eq!(runtime.block_on(future).unwrap().status(), StatusCode::OK);
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let first = outcomes(42);
+        assert_eq!(first, outcomes(42));
+        assert_ne!(first, outcomes(43));
+
+        assert!(first.contains(&Some(200)));
+        assert!(first.contains(&Some(503)));
+        assert!(first.contains(&None));
+        assert!(!first.contains(&Some(418)));
+    }
+}
@@ -2,42 +2,200 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use builder::Config;
+use fragmented::FragmentedIo;
 use futures::prelude::*;
 use hyper::body::{Body, Payload};
 use hyper::client::connect::{Connect, Connected, Destination};
 use hyper::server::conn::Http;
 use hyper::service::{NewService, Service};
 use hyper::Response;
-use memsocket::{self, UnboundedSocket};
+use probe::ConnectionRequests;
+use raw::RawRecordingIo;
+use service::{BoxError, StubService};
 use std::error::Error;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio;
+use tokio::timer::Delay;
+use transport::{Transport, UnboundedTransport};
 
 #[doc(hidden)]
-pub struct Connector<N> {
+pub struct Connector<N, T = UnboundedTransport> {
     new_service: N,
     server: Arc<Http>,
+    config: Arc<Config>,
+    transport: Arc<T>,
+    // Whether a connection has already been refused, for
+    // StubBuilder::connect_fail_once.
+    connect_failed: Arc<AtomicBool>,
 }
 
-impl<N> Connector<N> {
-    pub fn new(new_service: N) -> Self {
+impl<N, T> Connector<N, T> {
+    pub(crate) fn new(new_service: N, mut config: Config, transport: Arc<T>) -> Self {
+        config.created = Some(Instant::now());
+        let mut server = Http::new();
+        server.http1_only(config.http1_only);
         Connector {
             new_service,
-            server: Arc::new(Http::new()),
+            server: Arc::new(server),
+            config: Arc::new(config),
+            transport,
+            connect_failed: Arc::new(AtomicBool::new(false)),
         }
     }
+
+    // Opens a connection to the stub server without going through a hyper
+    // client, for when the caller wants to use the client half directly.
+    pub(crate) fn connect_raw(
+        &self,
+        connect_info: Option<ConnectInfo>,
+    ) -> ConnectorConnectFuture<N::Future, T>
+    where
+        N: NewService,
+    {
+        ConnectorConnectFuture {
+            server: self.server.clone(),
+            config: self.config.clone(),
+            transport: self.transport.clone(),
+            connect_info,
+            delay: None,
+            fail_once: None,
+            service_future: self.new_service.new_service(),
+        }
+    }
+}
+
+/// Where a stub's client thought it was connecting to, available to handlers
+/// as a request extension.
+///
+/// This comes from the [`Destination`] hyper's client passes to its connector,
+/// which is derived from the request's URI. The port is only known if the URI
+/// has one explicitly: for `http://example.com:8443/`, [`port`] returns
+/// `Some(8443)`, and for `http://example.com/`, it returns `None`, rather than
+/// the scheme's default of 80. An explicit default port, like
+/// `http://example.com:80/`, is still reported as `Some(80)`.
+///
+/// Since a connection is reused for multiple requests, this is the same for
+/// every request on the connection. Requests on a connection opened with
+/// [`StubBuilder::raw_connection_fn`] don't have one.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_fn_ok, ConnectInfo};
+///
+/// let client = proxy_client_fn_ok(|req| {
+///     let port = req.extensions().get::<ConnectInfo>().unwrap().port();
+///     Response::new(format!("{:?}", port).into())
+/// });
+/// ```
+///
+/// [`Destination`]: https://docs.rs/hyper/0.12/hyper/client/connect/struct.Destination.html
+/// [`port`]: #method.port
+/// [`StubBuilder::raw_connection_fn`]: struct.StubBuilder.html#method.raw_connection_fn
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectInfo {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+}
+
+impl ConnectInfo {
+    fn new(destination: &Destination) -> Self {
+        ConnectInfo {
+            scheme: destination.scheme().to_string(),
+            host: destination.host().to_string(),
+            port: destination.port(),
+        }
+    }
+
+    /// Returns the scheme of the URI the connection was opened for.
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// Returns the host of the URI the connection was opened for.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the port of the URI the connection was opened for, if it had
+    /// one explicitly.
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+}
+
+/// The position of a request among the ones the stub server has received on
+/// its connection, available to handlers as a request extension, for
+/// simulating a protocol that keeps state for each connection.
+///
+/// [`get`] returns 0 for the first request on a connection, 1 for the
+/// second, and so on. Every new connection starts again from 0.
+///
+/// This is only useful if the client reuses connections. hyper's client
+/// keeps idle connections in a pool, and sends a request on one of them if
+/// it can, so requests made one after another to the same host go on the
+/// same connection. But requests made at the same time over HTTP/1 each get
+/// a connection of their own, and a client that doesn't pool connections,
+/// or a response with `Connection: close` (see [`close_connection`]), means
+/// every request is the first on its connection. Requests on a connection
+/// opened with [`StubBuilder::raw_connection_fn`] don't have one.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_fn_ok, ConnectionRequestIndex};
+///
+/// let client = proxy_client_fn_ok(|req| {
+///     match req.extensions().get::<ConnectionRequestIndex>().unwrap().get() {
+///         0 => Response::new("hello".into()),
+///         _ => Response::new("hello again".into()),
+///     }
+/// });
+/// ```
+///
+/// [`get`]: #method.get
+/// [`close_connection`]: fn.close_connection.html
+/// [`StubBuilder::raw_connection_fn`]: struct.StubBuilder.html#method.raw_connection_fn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionRequestIndex(pub(crate) usize);
+
+impl ConnectionRequestIndex {
+    /// Returns how many requests the stub server received on the connection
+    /// before this one.
+    pub fn get(self) -> usize {
+        self.0
+    }
 }
 
 // A custom future type is necessary because using Future::map returns a type
 // that includes an anonymous type, and so can't be associated with a struct.
 #[doc(hidden)]
-pub struct ConnectorConnectFuture<ServiceFuture> {
+pub struct ConnectorConnectFuture<ServiceFuture, T> {
     server: Arc<Http>,
+    config: Arc<Config>,
+    transport: Arc<T>,
+    connect_info: Option<ConnectInfo>,
+    // The connect delay, if any, which has to pass before the service future
+    // is polled.
+    delay: Option<Delay>,
+    // Set by StubBuilder::connect_fail_once. If the flag hasn't been set yet,
+    // this connection sets it, and fails with an error of the given kind once
+    // the delay has passed.
+    fail_once: Option<(Arc<AtomicBool>, io::ErrorKind)>,
     service_future: ServiceFuture,
 }
 
-impl<ResBody, ResponseError, ServiceError, ResponseFuture, ServiceFuture, S> Future
-    for ConnectorConnectFuture<ServiceFuture>
+impl<ResBody, ResponseError, ServiceError, ResponseFuture, ServiceFuture, S, T> Future
+    for ConnectorConnectFuture<ServiceFuture, T>
 where
     ResBody: Payload,
     ResponseError: Error + Send + Sync + 'static,
@@ -47,28 +205,98 @@ where
     S: Service<ReqBody = Body, ResBody = ResBody, Error = ResponseError, Future = ResponseFuture>
         + Send
         + 'static,
+    T: Transport,
 {
-    type Item = (UnboundedSocket, Connected);
-    type Error = ServiceError;
+    type Item = (T::Client, Connected);
+    type Error = BoxError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.service_future.poll().map(|async| {
+        if let Some(ref mut delay) = self.delay {
+            match delay.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.delay = None;
+
+        if let Some((flag, kind)) = self.fail_once.take() {
+            if !flag.swap(true, Ordering::SeqCst) {
+                let error = io::Error::new(kind, "connection failed by stub");
+                return Err(error.into());
+            }
+        }
+
+        self.service_future.poll().map_err(Into::into).map(|async| {
             async.map(|service| {
-                let (client_io, server_io) = memsocket::unbounded();
-                tokio::spawn(
-                    self.server
-                        .serve_connection(server_io, service)
-                        .map_err(|err| panic!("{:?}", err)),
+                let (client_io, server_io) = self.transport.connect();
+                let server_io = RawRecordingIo::new(server_io, self.config.raw_request_log.clone());
+                let server_io = FragmentedIo::new(
+                    server_io,
+                    self.config.write_fragment_size,
+                    self.config.yield_between_fragments,
                 );
+                let stats = self.config.stats.clone();
+                let http1_only = self.config.http1_only;
+                let requests = ConnectionRequests::default();
+                if let Some(ref stats) = stats {
+                    let host = self.connect_info.as_ref().map(ConnectInfo::host);
+                    stats.record_connection_opened(host);
+                }
+
+                let connection = self
+                    .server
+                    .serve_connection(
+                        server_io,
+                        StubService::new(
+                            service,
+                            self.config.clone(),
+                            self.connect_info.clone(),
+                            requests.clone(),
+                        ),
+                    )
+                    .then(move |result| {
+                        if let Some(stats) = stats {
+                            stats.record_connection_closed();
+                        }
+                        result
+                    })
+                    .or_else(move |err| {
+                        // Errors from the handler or its response body
+                        // are how tests simulate a broken server, so the
+                        // client just sees the connection fail, as does a
+                        // client sending HTTP/2 to a server that's been
+                        // told to only speak HTTP/1.1. Anything else is a
+                        // bug.
+                        if err.is_user() || (http1_only && err.is_parse()) {
+                            Ok(())
+                        } else {
+                            panic!("{:?}", err)
+                        }
+                    });
+
+                // See StubBuilder::handshake_delay.
+                match self.config.handshake_delay {
+                    Some(delay) => {
+                        tokio::spawn(Delay::new(Instant::now() + delay).then(|_| connection))
+                    }
+                    None => tokio::spawn(connection),
+                };
 
-                (client_io, Connected::new().proxy(true))
+                let connected = Connected::new().proxy(true).extra(requests);
+                let connected = self.config.connection_extras.apply(connected);
+                if self.config.negotiate_h2 && !self.config.http1_only {
+                    (client_io, connected.negotiated_h2())
+                } else {
+                    (client_io, connected)
+                }
             })
         })
     }
 }
 
-impl<ResBody, ResponseError, ServiceError, ResponseFuture, ServiceFuture, S, N> Connect
-    for Connector<N>
+impl<ResBody, ResponseError, ServiceError, ResponseFuture, ServiceFuture, S, N, T> Connect
+    for Connector<N, T>
 where
     ResBody: Payload,
     ResponseError: Error + Send + Sync + 'static,
@@ -88,16 +316,191 @@ where
         >
         + Sync
         + Send,
+    T: Transport,
 {
-    type Transport = UnboundedSocket;
-    type Error = ServiceError;
-    type Future = ConnectorConnectFuture<ServiceFuture>;
+    type Transport = T::Client;
+    type Error = BoxError;
+    type Future = ConnectorConnectFuture<ServiceFuture, T>;
 
-    fn connect(&self, _: Destination) -> Self::Future {
-        let server = self.server.clone();
-        ConnectorConnectFuture {
-            server,
-            service_future: self.new_service.new_service(),
+    fn connect(&self, destination: Destination) -> Self::Future {
+        let mut future = self.connect_raw(Some(ConnectInfo::new(&destination)));
+        if let Some(ref connect_delay) = self.config.connect_delay {
+            future.delay = Some(Delay::new(Instant::now() + connect_delay.get(&destination)));
+        }
+        if let Some(kind) = self.config.connect_fail_once {
+            future.fail_once = Some((self.connect_failed.clone(), kind));
+        }
+        future
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+    use std::sync::Mutex;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn connect_info(uri: &str) -> ConnectInfo {
+        let seen = Arc::new(Mutex::new(None));
+        let handler_seen = seen.clone();
+        let client = ::proxy_client_fn_ok(move |req: Request<Body>| {
+            *handler_seen.lock().unwrap() = req.extensions().get::<ConnectInfo>().cloned();
+            Response::new(Body::empty())
+        });
+
+        let future = client.get(uri.parse().unwrap());
+        Runtime::new().unwrap().block_on(future).unwrap();
+        let info = seen.lock().unwrap().take();
+        info.unwrap()
+    }
+
+    #[test]
+    fn test_connect_delay_per_host() {
+        use std::time::{Duration, Instant};
+        use StubBuilder;
+
+        let client = StubBuilder::new()
+            .connect_delay_fn(|destination| match destination.host() {
+                "slow.example.com" => Duration::from_millis(200),
+                _ => Duration::from_millis(0),
+            })
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut elapsed = |uri: &str| {
+            let start = Instant::now();
+            runtime.block_on(client.get(uri.parse().unwrap())).unwrap();
+            start.elapsed()
+        };
+
+        assert!(elapsed("http://slow.example.com") >= Duration::from_millis(200));
+        assert!(elapsed("http://fast.example.com") < Duration::from_millis(200));
+
+        // A pooled connection doesn't need connecting again.
+        assert!(elapsed("http://slow.example.com") < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_new_service_error_is_passed_through() {
+        use futures::future;
+        use hyper::service::service_fn_ok;
+        use std::fmt;
+
+        let client = ::proxy_client(|| {
+            future::err::<(), _>(fmt::Error)
+                .map(|()| service_fn_ok(|_| Response::new(Body::empty())))
+        });
+
+        let error = Runtime::new()
+            .unwrap()
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap_err();
+        assert!(error.is_connect());
+        assert!(error.into_cause().unwrap().is::<fmt::Error>());
+    }
+
+    #[test]
+    fn test_connection_extra() {
+        use hyper::client::connect::HttpInfo;
+        use StubBuilder;
+
+        #[derive(Clone, Debug, PartialEq)]
+        struct PeerCertificate(Option<&'static str>);
+
+        #[derive(Clone, Debug, PartialEq)]
+        struct Region(&'static str);
+
+        let client = StubBuilder::new()
+            .connection_extra(PeerCertificate(None))
+            .connection_extra(Region("eu"))
+            .connection_extra(Region("us"))
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let response = Runtime::new()
+            .unwrap()
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap();
+        let extensions = response.extensions();
+        assert_eq!(extensions.get(), Some(&PeerCertificate(None)));
+        assert_eq!(extensions.get(), Some(&Region("us")));
+        assert!(extensions.get::<HttpInfo>().is_none());
+    }
+
+    #[test]
+    fn test_connect_fail_once() {
+        use StubBuilder;
+
+        let client = StubBuilder::new()
+            .connect_fail_once(io::ErrorKind::ConnectionRefused)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let mut runtime = Runtime::new().unwrap();
+        let error = runtime
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap_err();
+        assert!(error.is_connect());
+
+        // Retrying opens a new connection, which succeeds.
+        let response = runtime
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[test]
+    fn test_connect_fail_once_kind() {
+        use StubBuilder;
+
+        let kinds = [
+            io::ErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::TimedOut,
+        ];
+        for &kind in &kinds {
+            let client = StubBuilder::new()
+                .connect_fail_once(kind)
+                .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+            let error = Runtime::new()
+                .unwrap()
+                .block_on(client.get("http://example.com".parse().unwrap()))
+                .unwrap_err();
+            let cause = error.into_cause().unwrap();
+            assert_eq!(cause.downcast_ref::<io::Error>().unwrap().kind(), kind);
         }
     }
+
+    #[test]
+    fn test_connection_request_index() {
+        let client = ::proxy_client_fn_ok(|req: Request<Body>| {
+            let index = req.extensions().get::<ConnectionRequestIndex>().unwrap();
+            Response::new(index.get().to_string().into())
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |uri: &str| {
+            let future = client
+                .get(uri.parse().unwrap())
+                .and_then(|res| res.into_body().concat2());
+            String::from_utf8(runtime.block_on(future).unwrap().to_vec()).unwrap()
+        };
+
+        // Both requests are sent on the same kept-alive connection.
+        assert_eq!(get("http://example.com/first"), "0");
+        assert_eq!(get("http://example.com/second"), "1");
+
+        // A request for another host opens a connection of its own.
+        assert_eq!(get("http://other.example.com/"), "0");
+    }
+
+    #[test]
+    fn test_connect_info_port() {
+        let info = connect_info("http://example.com:8443/path");
+        assert_eq!(info.scheme(), "http");
+        assert_eq!(info.host(), "example.com");
+        assert_eq!(info.port(), Some(8443));
+
+        assert_eq!(connect_info("http://example.com/").port(), None);
+    }
 }
@@ -0,0 +1,132 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::prelude::*;
+use hyper::body::Sender;
+use hyper::{Body, Chunk};
+use tokio;
+
+// Takes over reading a request body from the connection, and gives the
+// handler a body that is fed from it. Once the handler drops its body, the
+// rest of the request body is still read and thrown away, so the client can
+// finish sending it.
+pub fn drain_in_background(body: Body) -> Body {
+    let (sender, handler_body) = Body::channel();
+    tokio::spawn(Drain {
+        body,
+        sender: Some(sender),
+        pending: None,
+    });
+    handler_body
+}
+
+struct Drain {
+    body: Body,
+    sender: Option<Sender>,
+    pending: Option<Chunk>,
+}
+
+impl Drain {
+    // Tries to hand the pending chunk to the handler. Gives up on the handler
+    // for good if it has dropped its body.
+    fn poll_send(&mut self) -> Async<()> {
+        if let Some(ref mut sender) = self.sender {
+            if self.pending.is_some() {
+                match sender.poll_ready() {
+                    Ok(Async::NotReady) => return Async::NotReady,
+                    Ok(Async::Ready(())) => {
+                        let chunk = self.pending.take().unwrap();
+                        if sender.send_data(chunk).is_ok() {
+                            return Async::Ready(());
+                        }
+                    }
+                    Err(_) => {}
+                }
+            } else {
+                return Async::Ready(());
+            }
+        }
+
+        self.sender = None;
+        self.pending = None;
+        Async::Ready(())
+    }
+}
+
+impl Future for Drain {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if let Async::NotReady = self.poll_send() {
+                return Ok(Async::NotReady);
+            }
+
+            match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => {
+                    if self.sender.is_some() {
+                        self.pending = Some(chunk);
+                    }
+                }
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => {
+                    if let Some(sender) = self.sender.take() {
+                        sender.abort();
+                    }
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+    use hyper::{Body, Request, Response, StatusCode};
+    use memsocket;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::runtime::current_thread::Runtime;
+    use StubBuilder;
+
+    #[test]
+    fn test_early_response() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let client = StubBuilder::new()
+            .transport(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                memsocket::unbounded()
+            })
+            .drain_request_bodies(true)
+            .proxy_client_fn_ok(|_| {
+                let mut response = Response::new("rejected".into());
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                response
+            });
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..2 {
+            let body = vec![b'x'; 1024 * 1024];
+            let request = Request::post("http://example.com/upload")
+                .body(Body::from(body))
+                .unwrap();
+            let (status, body) = runtime
+                .block_on(client.request(request).and_then(|res| {
+                    let status = res.status();
+                    res.into_body().concat2().map(move |body| (status, body))
+                }))
+                .unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(&body[..], b"rejected");
+        }
+
+        // The connection was kept alive, because the first body was read to
+        // the end.
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+}
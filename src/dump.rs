@@ -0,0 +1,256 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use cassette::{Cassette, Interaction};
+use hyper::HeaderMap;
+use std::io::{self, Write};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A guard that prints the interactions recorded in a [`Cassette`] to stderr
+/// when it is dropped, unless it has been [disarmed].
+///
+/// A library can't find out whether the test using it passed, so instead the
+/// guard should be created at the start of a test and disarmed at the end.
+/// If the test fails by panicking, the guard is dropped while the stack
+/// unwinds without having been disarmed, and prints every interaction
+/// recorded so far, after the panic message. Passing tests print nothing.
+///
+/// Each interaction is printed with the request's headers and body, prefixed
+/// with `>`, followed by the response's, prefixed with `<`, like `curl -v`.
+/// See [`Interaction::request_body`] for how much of the request body is
+/// recorded.
+///
+/// The guard never panics itself, even if the cassette's lock has been
+/// poisoned or stderr can't be written to, since panicking while already
+/// unwinding would abort the test process. If the guard is dropped without
+/// being disarmed and *without* a panic (for example, by an early `return`),
+/// it still prints the interactions, since that's usually a mistake.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use futures::future;
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_fn, Cassette, DumpOnDrop};
+///
+/// let cassette = Cassette::new();
+/// let guard = DumpOnDrop::new(&cassette);
+/// let client = proxy_client_fn(cassette.record(|_| {
+///     future::ok::<_, hyper::Error>(Response::new("hello".into()))
+/// }));
+///
+/// // ... run the test with `client` ...
+///
+/// guard.disarm();
+/// ```
+///
+/// [`Cassette`]: struct.Cassette.html
+/// [disarmed]: #method.disarm
+/// [`Interaction::request_body`]: struct.Interaction.html#method.request_body
+#[derive(Debug)]
+#[must_use = "the guard dumps interactions when it is dropped"]
+pub struct DumpOnDrop {
+    cassette: Cassette,
+    armed: bool,
+    output: Output,
+}
+
+// Where a DumpOnDrop writes when it's dropped. Tests can have it write to a
+// buffer instead of stderr, to check what's written while unwinding.
+#[derive(Debug)]
+enum Output {
+    Stderr,
+    #[cfg(test)]
+    Buffer(Arc<Mutex<Vec<u8>>>),
+}
+
+impl DumpOnDrop {
+    /// Creates a guard that will print the interactions recorded in
+    /// `cassette` when dropped.
+    pub fn new(cassette: &Cassette) -> Self {
+        DumpOnDrop {
+            cassette: cassette.clone(),
+            armed: true,
+            output: Output::Stderr,
+        }
+    }
+
+    /// Consumes the guard without printing anything. Call this once the test
+    /// has passed.
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+
+    fn dump<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let interactions = self.cassette.snapshot();
+
+        if thread::panicking() {
+            writeln!(
+                out,
+                "hyper-stub: test panicked with {} recorded interactions:",
+                interactions.len()
+            )?;
+        } else {
+            writeln!(
+                out,
+                "hyper-stub: guard was never disarmed, {} recorded interactions:",
+                interactions.len()
+            )?;
+        }
+
+        for (index, interaction) in interactions.iter().enumerate() {
+            write_interaction(&mut out, index, interaction)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_interaction<W: Write>(
+    out: &mut W,
+    index: usize,
+    interaction: &Interaction,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "#{}: {} {} -> {} (after {:?})",
+        index,
+        interaction.method(),
+        interaction.uri(),
+        interaction.status(),
+        interaction.elapsed()
+    )?;
+    write_message(
+        out,
+        '>',
+        interaction.request_headers(),
+        interaction.request_body(),
+    )?;
+    write_message(out, '<', interaction.headers(), interaction.body())
+}
+
+// Writes the headers and body of a request or response, with each line
+// prefixed with `prefix`.
+fn write_message<W: Write>(
+    out: &mut W,
+    prefix: char,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> io::Result<()> {
+    for (name, value) in headers {
+        writeln!(
+            out,
+            "    {} {}: {}",
+            prefix,
+            name,
+            String::from_utf8_lossy(value.as_bytes())
+        )?;
+    }
+    if !body.is_empty() {
+        writeln!(out, "    {}", prefix)?;
+        for line in String::from_utf8_lossy(body).lines() {
+            writeln!(out, "    {} {}", prefix, line)?;
+        }
+    }
+    Ok(())
+}
+
+impl Drop for DumpOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        match self.output {
+            Output::Stderr => {
+                let stderr = io::stderr();
+                let _ = self.dump(stderr.lock());
+            }
+
+            #[cfg(test)]
+            Output::Buffer(ref buf) => {
+                let mut buf = match buf.lock() {
+                    Ok(buf) => buf,
+                    Err(err) => err.into_inner(),
+                };
+                let _ = self.dump(&mut *buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use hyper::{Body, Request, Response};
+    use std::panic;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn record() -> Cassette {
+        let cassette = Cassette::new();
+        let client = ::proxy_client_fn(cassette.record(|req: Request<Body>| {
+            req.into_body()
+                .concat2()
+                .map(|_| Response::new("hello\nworld".into()))
+        }));
+        let request = Request::post("http://example.com/path")
+            .header("x-request", "yes")
+            .body("ping".into())
+            .unwrap();
+        Runtime::new()
+            .unwrap()
+            .block_on(
+                client
+                    .request(request)
+                    .and_then(|res| res.into_body().concat2()),
+            )
+            .unwrap();
+        cassette
+    }
+
+    #[test]
+    fn test_dump_format() {
+        let guard = DumpOnDrop::new(&record());
+        let mut out = Vec::new();
+        guard.dump(&mut out).unwrap();
+        guard.disarm();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("never disarmed, 1 recorded interactions"));
+        assert!(out.contains("#0: POST http://example.com/path -> 200 OK"));
+        assert!(out.contains("\n    > x-request: yes\n"));
+        assert!(out.ends_with("\n    >\n    > ping\n    <\n    < hello\n    < world\n"));
+    }
+
+    #[test]
+    fn test_dumps_while_panicking() {
+        let cassette = record();
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let result = panic::catch_unwind(|| {
+            let mut guard = DumpOnDrop::new(&cassette);
+            guard.output = Output::Buffer(buf.clone());
+            panic!("test failed");
+        });
+        assert!(result.is_err());
+
+        let out = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        assert!(out.starts_with("hyper-stub: test panicked with 1 recorded interactions:\n"));
+        assert!(out.contains("#0: POST http://example.com/path -> 200 OK"));
+        assert!(out.contains("\n    > ping\n"));
+    }
+
+    #[test]
+    fn test_disarmed_writes_nothing() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let mut guard = DumpOnDrop::new(&record());
+        guard.output = Output::Buffer(buf.clone());
+        guard.disarm();
+        assert!(buf.lock().unwrap().is_empty());
+    }
+}
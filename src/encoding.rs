@@ -0,0 +1,384 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use hyper::{Body, HeaderMap, Request, Response};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+/// Wraps `handler` so that its response bodies are encoded with `gzip` or
+/// `deflate` when the request's `Accept-Encoding` header allows it, and sent
+/// as they are otherwise.
+///
+/// The encoding used is the one with the highest q-value in
+/// `Accept-Encoding`, preferring `gzip` if they're equal. A q-value of 0 means
+/// the encoding isn't acceptable, and `*` stands for any encoding not listed
+/// explicitly. No other encodings are supported, so if neither of these is
+/// acceptable, or there's no `Accept-Encoding` header at all, the body isn't
+/// encoded. Responses that already have a `Content-Encoding` are left alone.
+///
+/// Encoded responses get a `Content-Encoding` header, and every response gets
+/// `Vary: accept-encoding`, added alongside any `Vary` values the handler
+/// set, like the one from [`localized_stub`]. The handler's body is read in
+/// full before it is encoded, and its `Content-Length`, if it had one, is
+/// removed, since it would be wrong for the encoded body.
+///
+/// The encoded bodies are valid `gzip` and `deflate` (zlib) streams, but they
+/// aren't compressed: the data is stored in them as it is, so that this crate
+/// doesn't need a compression library. That's enough to test that a client
+/// decodes what it's sent, but not to test anything that depends on the
+/// encoded body being smaller.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{negotiate_encoding, proxy_client_fn};
+///
+/// let client = proxy_client_fn(negotiate_encoding(|_| Response::new("hello".into())));
+/// ```
+///
+/// [`localized_stub`]: fn.localized_stub.html
+pub fn negotiate_encoding<F>(
+    handler: F,
+) -> impl Fn(Request<Body>) -> EncodingFuture + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| {
+        let encoding = negotiate(req.headers());
+        let (mut parts, body) = handler(req).into_parts();
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+        let encoding = match encoding {
+            Some(_) if parts.headers.contains_key(CONTENT_ENCODING) => None,
+            encoding => encoding,
+        };
+
+        let future = body.concat2().map(move |body| {
+            let body = match encoding {
+                Some(Encoding::Gzip) => {
+                    parts
+                        .headers
+                        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                    gzip(&body)
+                }
+                Some(Encoding::Deflate) => {
+                    parts
+                        .headers
+                        .insert(CONTENT_ENCODING, HeaderValue::from_static("deflate"));
+                    zlib(&body)
+                }
+                None => body.to_vec(),
+            };
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, body.into())
+        });
+
+        EncodingFuture {
+            inner: Box::new(future),
+        }
+    }
+}
+
+/// The future returned by handlers created by [`negotiate_encoding`].
+///
+/// [`negotiate_encoding`]: fn.negotiate_encoding.html
+pub struct EncodingFuture {
+    inner: Box<dyn Future<Item = Response<Body>, Error = ::hyper::Error> + Send>,
+}
+
+impl Future for EncodingFuture {
+    type Item = Response<Body>;
+    type Error = ::hyper::Error;
+
+    fn poll(&mut self) -> Poll<Response<Body>, ::hyper::Error> {
+        self.inner.poll()
+    }
+}
+
+/// Creates a handler that responds with `actual_bytes`, sent as they are,
+/// but with a `Content-Encoding` of `declared_encoding`, for testing how a
+/// client handles a body that isn't encoded the way the server says it is.
+///
+/// This can say a body is `gzip` when it's plain text, or, with
+/// `declared_encoding` of `identity`, send a `gzip` stream that the client
+/// won't decode. It panics if `declared_encoding` isn't a valid header
+/// value. The body is never encoded by the stub, even if the handler is
+/// wrapped in [`negotiate_encoding`], which leaves alone responses that
+/// already have a `Content-Encoding`.
+///
+/// hyper's client doesn't decode bodies itself, so it passes the bytes on
+/// as they are. A client that decodes them, like one using a `gzip` decoder
+/// on top of hyper, should fail with a decoding error, such as an invalid
+/// header or checksum, rather than returning the bytes, or garbage, as if
+/// they were the body.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{bad_content_encoding_stub, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(bad_content_encoding_stub("gzip", "not gzip"));
+/// ```
+///
+/// [`negotiate_encoding`]: fn.negotiate_encoding.html
+pub fn bad_content_encoding_stub<B: Into<Bytes>>(
+    declared_encoding: &str,
+    actual_bytes: B,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    let encoding = HeaderValue::from_str(declared_encoding).expect("invalid Content-Encoding");
+    let body = actual_bytes.into();
+
+    move |_| {
+        let mut response = Response::new(body.clone().into());
+        response
+            .headers_mut()
+            .insert(CONTENT_ENCODING, encoding.clone());
+        response
+    }
+}
+
+// Picks an encoding from the request's Accept-Encoding header, if it has one
+// that's acceptable.
+fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut deflate = None;
+    let mut wildcard = None;
+
+    let codings = headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for coding in codings {
+        let (name, q) = parse_q(coding);
+        match &name[..] {
+            "gzip" | "x-gzip" => gzip = Some(q),
+            "deflate" => deflate = Some(q),
+            "*" => wildcard = Some(q),
+            _ => {}
+        }
+    }
+
+    let gzip = gzip.or(wildcard).unwrap_or(0.0);
+    let deflate = deflate.or(wildcard).unwrap_or(0.0);
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else if deflate > 0.0 {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+// Splits an item from a header like Accept-Encoding or Accept-Language into
+// its lowercased name and its q-value, which is 1 if it doesn't have one.
+pub(crate) fn parse_q(item: &str) -> (String, f32) {
+    let mut params = item.split(';');
+    let name = params.next().unwrap().trim().to_ascii_lowercase();
+    let q = params
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .filter_map(|q| q.trim().parse::<f32>().ok())
+        .next()
+        .unwrap_or(1.0);
+    (name, q)
+}
+
+// Encodes data as a raw DEFLATE stream (RFC 1951), made up only of stored,
+// uncompressed blocks.
+fn deflate_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5 * (data.len() / 0xffff + 1));
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out
+}
+
+// Encodes data in the gzip format (RFC 1952).
+fn gzip(data: &[u8]) -> Vec<u8> {
+    // No flags, no modification time, no extra flags, and an unknown OS.
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend_from_slice(&deflate_stored(data));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+// Encodes data in the zlib format (RFC 1950), which is what HTTP calls
+// "deflate".
+fn zlib(data: &[u8]) -> Vec<u8> {
+    // A 32K window, and no compression, with the check bits that make the
+    // header a multiple of 31.
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate_stored(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn get(accept_encoding: Option<&str>) -> Response<Vec<u8>> {
+        let client = ::proxy_client_fn(negotiate_encoding(|_| Response::new("hello".into())));
+
+        let mut request = Request::get("http://example.com");
+        if let Some(accept_encoding) = accept_encoding {
+            request.header(ACCEPT_ENCODING, accept_encoding);
+        }
+        let future = client
+            .request(request.body(Body::empty()).unwrap())
+            .and_then(|res| {
+                let (parts, body) = res.into_parts();
+                body.concat2()
+                    .map(move |body| Response::from_parts(parts, body.to_vec()))
+            });
+        Runtime::new().unwrap().block_on(future).unwrap()
+    }
+
+    // Decodes a gzip stream made of stored deflate blocks, like the ones
+    // this module makes, standing in for a client's gzip decoder.
+    fn gunzip(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if data.len() < 18 || data[..3] != [0x1f, 0x8b, 8] {
+            return Err("invalid gzip header");
+        }
+        let (mut pos, mut out) = (10, Vec::new());
+        loop {
+            let header = data.get(pos..pos + 5).ok_or("unexpected end of stream")?;
+            let len = usize::from(u16::from_le_bytes([header[1], header[2]]));
+            let block = data
+                .get(pos + 5..pos + 5 + len)
+                .ok_or("unexpected end of stream")?;
+            out.extend_from_slice(block);
+            pos += 5 + len;
+            if header[0] == 1 {
+                break;
+            }
+        }
+        let crc = data.get(pos..pos + 4).ok_or("unexpected end of stream")?;
+        if crc != crc32(&out).to_le_bytes() {
+            return Err("invalid checksum");
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_bad_content_encoding_stub() {
+        let client = ::proxy_client_fn(negotiate_encoding(bad_content_encoding_stub(
+            "gzip",
+            "plain text",
+        )));
+        let request = Request::get("http://example.com")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let future = client.request(request).and_then(|res| {
+            assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+            res.into_body().concat2()
+        });
+        let body = Runtime::new().unwrap().block_on(future).unwrap();
+
+        assert_eq!(&body[..], b"plain text");
+        assert_eq!(gunzip(&body), Err("invalid gzip header"));
+        assert_eq!(gunzip(&gzip(b"plain text")).unwrap(), b"plain text");
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(deflate_stored(b""), [1, 0, 0, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_negotiates_encoding() {
+        let response = get(Some("gzip, deflate"));
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        #[rustfmt::skip]
+        let expected = [
+            0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff,
+            1, 5, 0, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o',
+            0x86, 0xa6, 0x10, 0x36, 5, 0, 0, 0,
+        ];
+        assert_eq!(response.body()[..], expected[..]);
+
+        let response = get(Some("gzip;q=0.5, deflate"));
+        assert_eq!(response.headers()[CONTENT_ENCODING], "deflate");
+        assert_eq!(response.body()[..2], [0x78, 0x01]);
+
+        assert_eq!(
+            get(Some("gzip;q=0, *")).headers()[CONTENT_ENCODING],
+            "deflate"
+        );
+    }
+
+    #[test]
+    fn test_without_accept_encoding() {
+        for accept_encoding in &[None, Some("br"), Some("gzip;q=0")] {
+            let response = get(*accept_encoding);
+            assert!(response.headers().get(CONTENT_ENCODING).is_none());
+            assert_eq!(response.body(), b"hello");
+        }
+    }
+
+    #[test]
+    fn test_keeps_handler_vary() {
+        let client = ::proxy_client_fn(negotiate_encoding(|_| {
+            Response::builder()
+                .header(VARY, "accept-language")
+                .body("hello".into())
+                .unwrap()
+        }));
+
+        let future = client.get("http://example.com".parse().unwrap());
+        let response = Runtime::new().unwrap().block_on(future).unwrap();
+        let vary: Vec<_> = response.headers().get_all(VARY).iter().collect();
+        assert_eq!(vary, ["accept-language", "accept-encoding"]);
+    }
+}
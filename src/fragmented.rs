@@ -0,0 +1,100 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{task, Poll};
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// Wraps the server's end of a connection, so that no single write is larger
+// than the configured fragment size. When yielding is enabled, the serving task
+// also goes back to the executor after each fragment, which gives the client a
+// chance to read it before the next one is written.
+pub struct FragmentedIo<T> {
+    io: T,
+    fragment_size: Option<usize>,
+    yield_between: bool,
+    yielded: bool,
+}
+
+impl<T> FragmentedIo<T> {
+    pub fn new(io: T, fragment_size: Option<usize>, yield_between: bool) -> Self {
+        FragmentedIo {
+            io,
+            fragment_size,
+            yield_between,
+            yielded: true,
+        }
+    }
+}
+
+impl<T: Read> Read for FragmentedIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for FragmentedIo<T> {}
+
+impl<T: Write> Write for FragmentedIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = match self.fragment_size {
+            Some(size) => size,
+            None => return self.io.write(buf),
+        };
+
+        if self.yield_between && !self.yielded {
+            self.yielded = true;
+            task::current().notify();
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "yielding"));
+        }
+
+        let len = buf.len().min(size);
+        let written = self.io.write(&buf[..len])?;
+        self.yielded = false;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for FragmentedIo<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the size of every write it accepts.
+    struct RecordingWrites(Vec<usize>);
+
+    impl Write for RecordingWrites {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_one_byte_fragments() {
+        let mut io = FragmentedIo::new(RecordingWrites(Vec::new()), Some(1), false);
+        io.write_all(b"fragmented response").unwrap();
+        assert_eq!(io.io.0, vec![1; b"fragmented response".len()]);
+    }
+
+    #[test]
+    fn test_unfragmented() {
+        let mut io = FragmentedIo::new(RecordingWrites(Vec::new()), None, false);
+        io.write_all(b"whole response").unwrap();
+        assert_eq!(io.io.0, vec![b"whole response".len()]);
+    }
+}
@@ -0,0 +1,1721 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use chaos::{LatencyProfile, SplitMix64};
+use connector::ConnectInfo;
+use encoding::parse_q;
+use futures::future::{self, Empty};
+use futures::prelude::*;
+use hyper::client::connect::Connect;
+use hyper::client::ResponseFuture;
+use hyper::header::{
+    HeaderName, HeaderValue, ACCEPT_LANGUAGE, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_REQUEST_METHOD, CONNECTION, CONTENT_LANGUAGE, CONTENT_LENGTH, ETAG, HOST,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, ORIGIN, TRANSFER_ENCODING, VARY,
+};
+use hyper::Client;
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use never::Never;
+use rules::ResponseFactory;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::{self, Delay, Timeout};
+
+/// Creates a handler that responds with `500 Internal Server Error` until it
+/// has recovered, and with `200 OK` from then on. This is useful for testing
+/// circuit breakers.
+///
+/// The handler recovers as soon as *either* it has served `fail_count` errors,
+/// *or* `recover_after` has elapsed since the handler was created -- whichever
+/// happens first. Time is measured with [`Instant`], so it is monotonic and
+/// unaffected by changes to the system clock. Once the handler has recovered,
+/// it stays recovered.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{flaky_then_healthy, proxy_client_fn_ok};
+/// use std::time::Duration;
+///
+/// let client = proxy_client_fn_ok(flaky_then_healthy(3, Duration::from_secs(1)));
+/// ```
+///
+/// [`Instant`]: https://doc.rust-lang.org/std/time/struct.Instant.html
+pub fn flaky_then_healthy(
+    fail_count: usize,
+    recover_after: Duration,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    let start = Instant::now();
+    let failures = Arc::new(AtomicUsize::new(0));
+
+    move |_| {
+        let mut response = Response::new(Body::empty());
+
+        if start.elapsed() < recover_after && failures.fetch_add(1, Ordering::SeqCst) < fail_count {
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+
+        response
+    }
+}
+
+/// Wraps `handler` so that it answers CORS preflight requests, and adds CORS
+/// headers to its responses to actual requests.
+///
+/// A preflight request is an `OPTIONS` request with an `Origin` and an
+/// `Access-Control-Request-Method` header. The wrapper answers these itself
+/// with `204 No Content`, without calling `handler`. If the origin is allowed,
+/// the response lists `allowed_methods` and `allowed_headers` in
+/// `Access-Control-Allow-Methods` and `Access-Control-Allow-Headers`. All other
+/// requests are passed to `handler`, and if they have an allowed origin, the
+/// response gets an `Access-Control-Allow-Origin` header. Requests from an
+/// origin that isn't allowed get no CORS headers at all, which is how a browser
+/// knows to block them.
+///
+/// If `allowed_origins` contains `"*"`, every origin is allowed, and responses
+/// have `Access-Control-Allow-Origin: *`. Browsers refuse to use a wildcard
+/// response for credentialed requests (those with cookies or HTTP
+/// authentication), so to test those, list the origins explicitly instead. Then
+/// the response echoes the request's origin back, with `Vary: Origin` and
+/// `Access-Control-Allow-Credentials: true`.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::{Method, Response};
+/// use hyper_stub::{cors_stub, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(cors_stub(
+///     &["https://app.example.com"],
+///     &[Method::GET, Method::POST],
+///     &["content-type"],
+///     |_| Response::new("hello".into()),
+/// ));
+/// ```
+pub fn cors_stub<F>(
+    allowed_origins: &[&str],
+    allowed_methods: &[Method],
+    allowed_headers: &[&str],
+    handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    fn join<I: IntoIterator<Item = T>, T: AsRef<str>>(items: I) -> HeaderValue {
+        let items: Vec<_> = items.into_iter().map(|s| s.as_ref().to_string()).collect();
+        HeaderValue::from_str(&items.join(", ")).expect("invalid CORS header value")
+    }
+
+    let wildcard = allowed_origins.contains(&"*");
+    let allowed_origins: Vec<_> = allowed_origins.iter().map(|s| s.to_string()).collect();
+    let allowed_methods = join(allowed_methods);
+    let allowed_headers = join(allowed_headers);
+
+    move |req| {
+        let origin = req.headers().get(ORIGIN).cloned().filter(|origin| {
+            wildcard
+                || origin
+                    .to_str()
+                    .map(|origin| allowed_origins.iter().any(|o| o == origin))
+                    .unwrap_or(false)
+        });
+
+        let preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(ORIGIN)
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+        let mut response = if preflight {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NO_CONTENT;
+            if origin.is_some() {
+                let headers = response.headers_mut();
+                headers.insert(ACCESS_CONTROL_ALLOW_METHODS, allowed_methods.clone());
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers.clone());
+            }
+            response
+        } else {
+            handler(req)
+        };
+
+        if let Some(origin) = origin {
+            let headers = response.headers_mut();
+            if wildcard {
+                headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+            } else {
+                headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                headers.insert(
+                    ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+                headers.append(VARY, HeaderValue::from_static("origin"));
+            }
+        }
+
+        response
+    }
+}
+
+/// Wraps `handler` so that each response is sent after `delay`.
+///
+/// The delay starts when the request arrives, and is measured by the tokio
+/// timer, so the client must be run on a tokio runtime.
+pub fn delayed<F>(
+    delay: Duration,
+    handler: F,
+) -> impl Fn(Request<Body>) -> DelayedFuture + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| DelayedFuture {
+        delay: Delay::new(Instant::now() + delay),
+        response: Some(handler(req)),
+    }
+}
+
+/// Wraps `handler` so that each response is sent after a delay chosen by
+/// `delay` for its request, like a different delay for each path, to model
+/// an API whose endpoints aren't all equally fast.
+///
+/// `delay` is called with each request before it's passed to `handler`, so
+/// every request gets a delay of its own. As with [`delayed`], the delay
+/// starts when the request arrives, and `handler` is called straight away,
+/// with only sending its response held back.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{delayed_by, proxy_client_fn};
+/// use std::time::Duration;
+///
+/// let client = proxy_client_fn(delayed_by(
+///     |req| match req.uri().path() {
+///         "/slow" => Duration::from_millis(500),
+///         _ => Duration::from_millis(0),
+///     },
+///     |_| Response::new("hello".into()),
+/// ));
+/// ```
+///
+/// [`delayed`]: fn.delayed.html
+pub fn delayed_by<D, F>(
+    delay: D,
+    handler: F,
+) -> impl Fn(Request<Body>) -> DelayedFuture + Send + Sync + Clone + 'static
+where
+    D: Fn(&Request<Body>) -> Duration + Send + Sync + Clone + 'static,
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| DelayedFuture {
+        delay: Delay::new(deadline(delay(&req))),
+        response: Some(handler(req)),
+    }
+}
+
+// The deadline for a delay starting now. The tokio timer can't wait more than
+// about two years, and adding a long enough delay to an Instant overflows, so
+// longer delays are clamped to a year, which is forever as far as a test is
+// concerned.
+pub(crate) fn deadline(delay: Duration) -> Instant {
+    Instant::now() + delay.min(Duration::from_secs(365 * 24 * 60 * 60))
+}
+
+/// Wraps `handler` so that each response is sent after a delay drawn at
+/// random from `profile`.
+///
+/// The delays come from the same generator as [`seeded_stub`], seeded with
+/// `seed`, and shared by every connection to the stub, so the same seed always
+/// gives the same sequence of delays, as long as the requests arrive in the
+/// same order. As with [`delayed`], the delay starts when the request arrives.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{jittered, proxy_client_fn, LatencyProfile};
+/// use std::time::Duration;
+///
+/// let profile = LatencyProfile::Normal {
+///     mean: Duration::from_millis(50),
+///     stddev: Duration::from_millis(10),
+/// };
+/// let client = proxy_client_fn(jittered(42, profile, |_| Response::new("hello".into())));
+/// ```
+///
+/// [`seeded_stub`]: fn.seeded_stub.html
+/// [`delayed`]: fn.delayed.html
+pub fn jittered<F>(
+    seed: u64,
+    profile: LatencyProfile,
+    handler: F,
+) -> impl Fn(Request<Body>) -> DelayedFuture + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    let rng = Arc::new(Mutex::new(SplitMix64(seed)));
+    move |req| {
+        let delay = profile.sample(&mut rng.lock().unwrap());
+        DelayedFuture {
+            delay: Delay::new(deadline(delay)),
+            response: Some(handler(req)),
+        }
+    }
+}
+
+/// The future returned by handlers created by [`delayed`], [`delayed_by`]
+/// and [`jittered`].
+///
+/// [`delayed`]: fn.delayed.html
+/// [`delayed_by`]: fn.delayed_by.html
+/// [`jittered`]: fn.jittered.html
+pub struct DelayedFuture {
+    delay: Delay,
+    response: Option<Response<Body>>,
+}
+
+impl Future for DelayedFuture {
+    type Item = Response<Body>;
+    type Error = timer::Error;
+
+    fn poll(&mut self) -> Poll<Response<Body>, timer::Error> {
+        try_ready!(self.delay.poll());
+        let response = self
+            .response
+            .take()
+            .expect("DelayedFuture polled after completion");
+        Ok(Async::Ready(response))
+    }
+}
+
+/// Sends a request to a stub that takes `stub_delay` to respond, giving up
+/// after `client_timeout`.
+///
+/// hyper's client has no timeout of its own, so the timeout is enforced on
+/// the client side, by wrapping the response future in a tokio [`Timeout`].
+/// The stub doesn't know about the timeout at all, and keeps waiting to
+/// respond after the client has given up. Because the timeout covers only
+/// the response head, a response whose body arrives slowly won't time out.
+///
+/// When `stub_delay` is longer than `client_timeout`, the returned future
+/// fails with an error for which [`is_elapsed`] is true. Otherwise, it
+/// resolves to the stub's `200 OK` response.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use hyper_stub::timeout_test;
+/// use std::time::Duration;
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let future = timeout_test(Duration::from_secs(1), Duration::from_millis(10));
+/// let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+/// assert!(error.is_elapsed());
+/// ```
+///
+/// [`Timeout`]: https://docs.rs/tokio/0.1/tokio/timer/struct.Timeout.html
+/// [`is_elapsed`]: https://docs.rs/tokio/0.1/tokio/timer/timeout/struct.Error.html#method.is_elapsed
+pub fn timeout_test(stub_delay: Duration, client_timeout: Duration) -> Timeout<ResponseFuture> {
+    let client = ::proxy_client_fn(delayed(stub_delay, |_| Response::new(Body::empty())));
+    let future = client.get("http://example.com".parse().unwrap());
+    Timeout::new(future, client_timeout)
+}
+
+/// Creates a client whose stub accepts connections and reads requests, but
+/// never responds to them.
+///
+/// This is for testing how a client behaves when a server hangs. The stub's
+/// handler returns a future that never completes and never asks to be polled
+/// again, so it doesn't use any CPU while waiting. Nothing in the stub will
+/// ever time out, so the test must wrap the request in a timeout of its own
+/// (see [`timeout_test`]), or it will hang forever.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use hyper_stub::blocking_stub;
+/// use std::time::Duration;
+/// use tokio::runtime::current_thread::Runtime;
+/// use tokio::timer::Timeout;
+///
+/// let client = blocking_stub();
+/// let future = client.get("http://example.com".parse().unwrap());
+/// let future = Timeout::new(future, Duration::from_millis(10));
+/// let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+/// assert!(error.is_elapsed());
+/// ```
+///
+/// [`timeout_test`]: fn.timeout_test.html
+pub fn blocking_stub() -> Client<impl Connect> {
+    ::proxy_client_fn(|_| -> Empty<Response<Body>, Never> { future::empty() })
+}
+
+/// Creates a handler that accepts a request's head, but never reads its
+/// body, or responds, for testing how a client handles an upload that
+/// stalls.
+///
+/// The handler holds on to the request, so the stub server doesn't close
+/// the connection, but never polls its body, so, after the head, hyper's
+/// server reads at most what came in with it, and one more chunk. How far
+/// the client gets with its upload depends on the stub's [`Transport`]:
+///
+/// - With the default [`UnboundedTransport`], the client can always write,
+///   so it writes the whole body into the socket's buffer, and its upload
+///   completes. Only the response never comes, as with [`blocking_stub`].
+/// - With a bounded transport, like `memsocket::bounded`, the client can
+///   only write until the socket's buffer is full, and then its send stalls,
+///   and stays stalled, since nothing will ever read from the socket again.
+///
+/// As with [`blocking_stub`], nothing in the stub will ever time out, so the
+/// test must wrap the request in a timeout of its own.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// # extern crate memsocket;
+/// #
+/// use hyper_stub::{stall_upload_stub, StubBuilder};
+///
+/// let client = StubBuilder::new()
+///     .transport(|| memsocket::bounded(1024))
+///     .proxy_client_fn(stall_upload_stub());
+/// ```
+///
+/// [`Transport`]: trait.Transport.html
+/// [`UnboundedTransport`]: struct.UnboundedTransport.html
+/// [`blocking_stub`]: fn.blocking_stub.html
+pub fn stall_upload_stub(
+) -> impl Fn(Request<Body>) -> StalledUploadFuture + Send + Sync + Clone + 'static {
+    |req| StalledUploadFuture { _request: req }
+}
+
+/// The future returned by handlers created by [`stall_upload_stub`], which
+/// never completes.
+///
+/// [`stall_upload_stub`]: fn.stall_upload_stub.html
+pub struct StalledUploadFuture {
+    _request: Request<Body>,
+}
+
+impl Future for StalledUploadFuture {
+    type Item = Response<Body>;
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Response<Body>, Never> {
+        // Never asks to be polled again, like future::Empty.
+        Ok(Async::NotReady)
+    }
+}
+
+/// Wraps `handler` so that it only handles requests made with an `https` URI.
+/// Requests made with any other scheme get a `400 Bad Request` response, like
+/// the one a real HTTPS server sends when it receives plain HTTP.
+///
+/// This is only a simulation: the stub never uses TLS, whatever the scheme.
+/// Instead, the wrapper checks the scheme of the URI hyper's client connected
+/// for (see [`ConnectInfo`]), so it tests whether the client *asked* for HTTPS.
+/// Requests on connections without a [`ConnectInfo`] are rejected too.
+///
+/// [`ConnectInfo`]: struct.ConnectInfo.html
+pub fn https_only_stub<F>(
+    handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| {
+        let https = match req.extensions().get::<ConnectInfo>() {
+            Some(info) => info.scheme() == "https",
+            None => false,
+        };
+
+        if https {
+            return handler(req);
+        }
+
+        let mut response = Response::new("The plain HTTP request was sent to HTTPS port".into());
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        response
+    }
+}
+
+/// Wraps `handler` so that it only handles requests whose `Host` header is
+/// `expected_host`, like a virtual host, and responds to the rest with
+/// `421 Misdirected Request`.
+///
+/// Hosts are compared case-insensitively, but otherwise exactly, so a port
+/// in the header has to be in `expected_host` too. A request without a
+/// `Host` header is misdirected.
+///
+/// The stub sees the `Host` header exactly as the client sent it. Stub
+/// clients always add one, taken from the request URI's host, and its port
+/// if the URI has one, like `example.com:8080`, even if their
+/// [`client_builder`] was told not to with `set_host(false)`, because the stub
+/// server relies on it. To test sending a different host from the one in the
+/// URI, set the header on the request, and the client will leave it alone.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_fn_ok, vhost_stub};
+///
+/// let client = proxy_client_fn_ok(vhost_stub("api.example.com", |_| {
+///     Response::new("hello".into())
+/// }));
+/// ```
+///
+/// [`client_builder`]: struct.StubBuilder.html#method.client_builder
+pub fn vhost_stub<F>(
+    expected_host: &str,
+    handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    let expected_host = expected_host.to_string();
+
+    move |req| {
+        let host = req.headers().get(HOST).and_then(|host| host.to_str().ok());
+        if host.is_some_and(|host| host.eq_ignore_ascii_case(&expected_host)) {
+            return handler(req);
+        }
+
+        let message = format!("this server doesn't serve {}", host.unwrap_or("<no host>"));
+        let mut response = Response::new(message.into());
+        *response.status_mut() = StatusCode::MISDIRECTED_REQUEST;
+        response
+    }
+}
+
+/// Creates a handler that passes each request to the [`ResponseFactory`] in
+/// `map` for the host its connection was opened for, or to `default` if
+/// there isn't one, so one stub client can stand in for several services.
+///
+/// Unlike [`vhost_stub`], this doesn't look at the `Host` header, but at the
+/// host from the URI hyper's client connected for (see [`ConnectInfo`]),
+/// which is the same for every request on a connection, and which the code
+/// under test can't override with a header. Since hyper's client keeps a
+/// separate pool of connections for each host, requests to different hosts
+/// never share a connection.
+///
+/// Keys are either a host, like `api.example.com`, or a host and port, like
+/// `api.example.com:8443`, and are compared case-insensitively. A request
+/// goes to the host and port key if there is one for its URI's port, and to
+/// the host key otherwise, so a host key covers every port. A port is only
+/// known when the URI has one explicitly, so a URI that relies on its
+/// scheme's default port, like `http://api.example.com/`, only ever matches
+/// a host key, while `http://api.example.com:80/` matches
+/// `api.example.com:80` too. Requests on a connection without a
+/// [`ConnectInfo`], like one opened with
+/// [`StubBuilder::raw_connection_fn`], go to `default`.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{multi_host_stub, proxy_client_fn_ok, ResponseFactory};
+/// use std::collections::HashMap;
+///
+/// let mut map: HashMap<_, ResponseFactory> = HashMap::new();
+/// map.insert("users.internal".to_string(), Box::new(|_| Response::new("users".into())));
+/// map.insert("billing.internal".to_string(), Box::new(|_| Response::new("billing".into())));
+///
+/// let client = proxy_client_fn_ok(multi_host_stub(
+///     map,
+///     Box::new(|_| Response::new("unknown service".into())),
+/// ));
+/// ```
+///
+/// [`ResponseFactory`]: type.ResponseFactory.html
+/// [`vhost_stub`]: fn.vhost_stub.html
+/// [`ConnectInfo`]: struct.ConnectInfo.html
+/// [`StubBuilder::raw_connection_fn`]: struct.StubBuilder.html#method.raw_connection_fn
+pub fn multi_host_stub(
+    map: HashMap<String, ResponseFactory>,
+    default: ResponseFactory,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    let map: HashMap<_, _> = map
+        .into_iter()
+        .map(|(host, factory)| (host.to_ascii_lowercase(), factory))
+        .collect();
+    let map = Arc::new(map);
+    let default = Arc::new(default);
+
+    move |req| {
+        let factory = req.extensions().get::<ConnectInfo>().and_then(|info| {
+            let host = info.host().to_ascii_lowercase();
+            let with_port = info.port().map(|port| format!("{}:{}", host, port));
+            with_port
+                .and_then(|key| map.get(&key))
+                .or_else(|| map.get(&host))
+        });
+
+        match factory {
+            Some(factory) => factory(req),
+            None => default(req),
+        }
+    }
+}
+
+/// Creates a handler that responds with `503 Service Unavailable` and
+/// `maintenance_body` for `duration`, as if the server were down for
+/// maintenance, and passes every request to `handler` after that.
+///
+/// The window starts when `maintenance_window` is called, and is measured with
+/// [`Instant`], like [`flaky_then_healthy`]. It's half-open: a request
+/// arriving exactly `duration` after the start is passed to `handler`.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{maintenance_window, proxy_client_fn_ok};
+/// use std::time::Duration;
+///
+/// let client = proxy_client_fn_ok(maintenance_window(
+///     Duration::from_secs(1),
+///     "<h1>Down for maintenance</h1>",
+///     |_| Response::new("hello".into()),
+/// ));
+/// ```
+///
+/// [`Instant`]: https://doc.rust-lang.org/std/time/struct.Instant.html
+/// [`flaky_then_healthy`]: fn.flaky_then_healthy.html
+pub fn maintenance_window<B, F>(
+    duration: Duration,
+    maintenance_body: B,
+    handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    B: Into<Bytes>,
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    let start = Instant::now();
+    let maintenance_body = maintenance_body.into();
+
+    move |req| {
+        if start.elapsed() >= duration {
+            return handler(req);
+        }
+
+        let mut response = Response::new(maintenance_body.clone().into());
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        response
+    }
+}
+
+/// Creates a handler that responds with `500 Internal Server Error` and
+/// `error_body` to requests that `predicate` matches, and passes every other
+/// request to `ok_handler`, for injecting a single failure, like a stack
+/// trace from a crashing endpoint, into otherwise working traffic.
+///
+/// `predicate` is called first, exactly once for each request, and if it
+/// returns true, `ok_handler` isn't called at all, so it never sees a request
+/// that fails. Matching isn't limited to the first request that matches:
+/// every matching request fails, so to fail only once, have `predicate` keep
+/// count.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{fail_matching, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(fail_matching(
+///     |req| req.uri().path() == "/crash",
+///     "thread 'main' panicked at 'oops', src/main.rs:1:1",
+///     |_| Response::new("hello".into()),
+/// ));
+/// ```
+pub fn fail_matching<P, B, F>(
+    predicate: P,
+    error_body: B,
+    ok_handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    P: Fn(&Request<Body>) -> bool + Send + Sync + Clone + 'static,
+    B: Into<Bytes>,
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    let error_body = error_body.into();
+
+    move |req| {
+        if !predicate(&req) {
+            return ok_handler(req);
+        }
+
+        let mut response = Response::new(error_body.clone().into());
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    }
+}
+
+/// Wraps `handler` so that it responds without reading the request body, as a
+/// server that rejects an upload early would, while the client may still be
+/// sending it.
+///
+/// The body is dropped before `handler` is called, so `handler` is given a
+/// request with an empty body, and the response is written as soon as it
+/// returns. When hyper's client receives a complete response while it is
+/// still uploading, it resolves the response future with that response, stops
+/// polling the request body and drops it, so the rest of the body is never
+/// sent. Because the upload was cut short, the connection can't be reused:
+/// hyper's server closes it, and the client's next request opens a new one.
+///
+/// This only works if the stub doesn't read request bodies itself. With a
+/// [`request_log`], the stub buffers every body before calling the handler,
+/// so the response can't be sent until the upload has finished, and with
+/// [`drain_request_bodies`], the response is sent early, but the rest of the
+/// body is read, so the client finishes sending it.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::{Response, StatusCode};
+/// use hyper_stub::{early_response, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(early_response(|_| {
+///     let mut response = Response::new("too large".into());
+///     *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+///     response
+/// }));
+/// ```
+///
+/// [`request_log`]: struct.StubBuilder.html#method.request_log
+/// [`drain_request_bodies`]: struct.StubBuilder.html#method.drain_request_bodies
+pub fn early_response<F>(
+    handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| handler(req.map(|_| Body::empty()))
+}
+
+/// Wraps `handler` so that the request's `header` is copied into its
+/// response, for testing that a client propagates a correlation ID, like
+/// `X-Request-ID` or `traceparent`.
+///
+/// Every value of the header is copied, in order, replacing any values
+/// `handler` set for it. If the request doesn't have the header, the response
+/// is left as `handler` returned it, so it only has the header if `handler`
+/// added it.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::header::HeaderName;
+/// use hyper::Response;
+/// use hyper_stub::{echo_correlation, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(echo_correlation(
+///     HeaderName::from_static("x-request-id"),
+///     |_| Response::new("hello".into()),
+/// ));
+/// ```
+pub fn echo_correlation<F>(
+    header: HeaderName,
+    handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| {
+        let values: Vec<_> = req.headers().get_all(&header).iter().cloned().collect();
+        let mut response = handler(req);
+        if !values.is_empty() {
+            let headers = response.headers_mut();
+            headers.remove(&header);
+            for value in values {
+                headers.append(header.clone(), value);
+            }
+        }
+        response
+    }
+}
+
+/// Wraps `handler` so that every response has a `Connection: close` header,
+/// for testing that a client doesn't reuse a connection the server said it
+/// would close.
+///
+/// hyper's server closes the connection once it has written a response with
+/// that header. hyper's client sees the header too, and doesn't put the
+/// connection back in its pool of idle connections to be kept alive, so its
+/// next request to the stub opens a new one. Count connections with
+/// [`Stats::connections_opened`] to check: without this wrapper, a client
+/// making requests one after another only opens one connection, and with it,
+/// one per request.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{close_connection, Stats, StubBuilder};
+///
+/// let stats = Stats::new();
+/// let client = StubBuilder::new()
+///     .stats(&stats)
+///     .proxy_client_fn_ok(close_connection(|_| Response::new("bye".into())));
+/// ```
+///
+/// [`Stats::connections_opened`]: struct.Stats.html#method.connections_opened
+pub fn close_connection<F>(
+    handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| {
+        let mut response = handler(req);
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+        response
+    }
+}
+
+/// Wraps `handler` so that requests framed with `Content-Length` are
+/// rejected with `400 Bad Request`, like a server that only accepts chunked
+/// uploads, for testing that a client uses chunked transfer encoding.
+///
+/// A request is rejected if it has a `Content-Length` header and no
+/// `Transfer-Encoding` header. Every other request, including one with
+/// neither, like most `GET` requests, is passed on to `handler`.
+///
+/// This goes by the framing headers the client sent, which hyper's server
+/// passes on to the handler as they were received, even after the body has
+/// been read by a [`request_log`]. With hyper's client, that's decided by
+/// the request's body: one with a known length, like a [`Body`] made from
+/// bytes or a string, is sent with `Content-Length`, and a [`Body`] made from
+/// a stream with `Transfer-Encoding: chunked`. An empty body is sent with
+/// neither, whatever the method, so it's passed on.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_fn_ok, require_chunked_stub};
+///
+/// let client = proxy_client_fn_ok(require_chunked_stub(|_| Response::new("uploaded".into())));
+/// ```
+///
+/// [`request_log`]: struct.StubBuilder.html#method.request_log
+/// [`Body`]: https://docs.rs/hyper/0.12/hyper/struct.Body.html
+pub fn require_chunked_stub<F>(
+    handler: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| {
+        let headers = req.headers();
+        if !headers.contains_key(CONTENT_LENGTH) || headers.contains_key(TRANSFER_ENCODING) {
+            return handler(req);
+        }
+
+        let mut response = Response::new("Chunked transfer encoding required".into());
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        response
+    }
+}
+
+/// Wraps `handler` so that requests whose body ends before the length given
+/// by their `Content-Length` header are rejected with `400 Bad Request`, for
+/// catching a client that declares more than it sends.
+///
+/// The body is read in full before `handler` is called. A request without a
+/// `Content-Length`, like one sent with chunked transfer encoding, or with
+/// no body at all, is always passed on. One with a `Content-Length` that
+/// isn't a valid length never gets this far, since hyper's server can't
+/// tell where its body ends, and closes the connection instead.
+///
+/// hyper's server uses `Content-Length` to decide where the body ends, so
+/// the body is either exactly as long as declared, or reading it fails. It
+/// fails if the client sends fewer bytes than it declared, once the client
+/// stops sending, which is when it closes its side of the connection, and
+/// then the request is rejected, as long as the connection is still open
+/// for the response. A body *longer* than declared is never caught here:
+/// hyper's server only reads as many bytes as were declared, so `handler`
+/// gets the request, and the bytes left over are read as the start of the
+/// next request on the connection, which hyper's server rejects with a
+/// `400 Bad Request` of its own. hyper's client always sends exactly the
+/// length it declares, so this only catches mistakes in clients that write
+/// requests themselves.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_fn, validate_content_length_stub};
+///
+/// let client = proxy_client_fn(validate_content_length_stub(|req| {
+///     Response::new(req.into_body())
+/// }));
+/// ```
+pub fn validate_content_length_stub<F>(
+    handler: F,
+) -> impl Fn(Request<Body>) -> ContentLengthFuture + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    move |req| {
+        let declared = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        let declared = match declared {
+            Some(declared) => declared,
+            None => return ContentLengthFuture::ready(handler(req)),
+        };
+
+        let handler = handler.clone();
+        let (parts, body) = req.into_parts();
+        let future = body.concat2().then(move |body| {
+            let response = match body {
+                Ok(body) => handler(Request::from_parts(parts, body.into())),
+                Err(error) => bad_request(format!(
+                    "Content-Length was {}, but reading the body failed: {}",
+                    declared, error
+                )),
+            };
+            Ok(response)
+        });
+
+        ContentLengthFuture {
+            inner: Box::new(future),
+        }
+    }
+}
+
+/// The future returned by handlers created by
+/// [`validate_content_length_stub`].
+///
+/// [`validate_content_length_stub`]: fn.validate_content_length_stub.html
+pub struct ContentLengthFuture {
+    inner: Box<dyn Future<Item = Response<Body>, Error = Never> + Send>,
+}
+
+impl ContentLengthFuture {
+    fn ready(response: Response<Body>) -> Self {
+        ContentLengthFuture {
+            inner: Box::new(future::ok(response)),
+        }
+    }
+}
+
+impl Future for ContentLengthFuture {
+    type Item = Response<Body>;
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Response<Body>, Never> {
+        self.inner.poll()
+    }
+}
+
+fn bad_request(message: String) -> Response<Body> {
+    let mut response = Response::new(message.into());
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    response
+}
+
+/// Creates a handler that serves `body` with an `ETag` of `etag`, and
+/// answers conditional requests for it with `304 Not Modified`, for testing
+/// a client's HTTP caching.
+///
+/// `etag` is the header's value, including its quotes, like `"v1"`, or
+/// `W/"v1"` for a weak one. It panics if that isn't a valid header value.
+///
+/// Only `GET` and `HEAD` requests are conditional. Every other request gets
+/// `200 OK` with the body, as does a request without any conditional
+/// headers. Otherwise:
+///
+/// - If the request has `If-None-Match`, the response is `304 Not Modified`
+///   if any of the tags listed matches `etag`, or the header is `*`. Tags
+///   are compared weakly, as a server has to for `If-None-Match`, so `W/"v1"`
+///   and `"v1"` match each other.
+/// - If it doesn't, but has `If-Modified-Since`, the response is always
+///   `304 Not Modified`, whatever the date. The stub's body never changes,
+///   and it has no `Last-Modified` date to compare with, so the date isn't
+///   even parsed.
+///
+/// A `304 Not Modified` response has the `ETag`, but no body.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{conditional_stub, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(conditional_stub("\"v1\"", "hello"));
+/// ```
+pub fn conditional_stub<B: Into<Bytes>>(
+    etag: &str,
+    body: B,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    let etag = HeaderValue::from_str(etag).expect("invalid ETag");
+    let body = body.into();
+
+    move |req| {
+        let conditional = req.method() == Method::GET || req.method() == Method::HEAD;
+        let not_modified = conditional
+            && match req.headers().get(IF_NONE_MATCH) {
+                Some(tags) => tags
+                    .to_str()
+                    .unwrap_or("")
+                    .split(',')
+                    .any(|tag| tag.trim() == "*" || weak_eq(tag, &etag)),
+                None => req.headers().contains_key(IF_MODIFIED_SINCE),
+            };
+
+        let mut response = if not_modified {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            response
+        } else {
+            Response::new(body.clone().into())
+        };
+        response.headers_mut().insert(ETAG, etag.clone());
+        response
+    }
+}
+
+/// Creates a handler that responds with the body from `map` for the
+/// language that best matches the request's `Accept-Language` header, for
+/// testing a client that asks for localized content.
+///
+/// `map` is keyed by language tag, like `en` or `pt-BR`. Languages are
+/// chosen like this:
+///
+/// - The language ranges in `Accept-Language` are tried from the highest
+///   q-value to the lowest, and in the order they're listed when q-values
+///   are equal. A range without a q-value has a q-value of 1, and one with a
+///   q-value of 0 is never used.
+/// - Each range is looked up as in [RFC 4647]: if no tag in `map` is equal
+///   to it, ignoring case, its last subtag is removed and the lookup tried
+///   again, so `en-GB` is served `en` if there's no `en-GB`. The reverse
+///   doesn't happen, so `en` is never served `en-GB`.
+/// - A `*` range, a request whose ranges all fail to match, and one without
+///   an `Accept-Language` header, get `default_lang`.
+///
+/// Every response says which language it's in with `Content-Language`, and
+/// has `Vary: accept-language`. It panics if `default_lang` isn't in `map`,
+/// or if a tag can't be put in a header.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{localized_stub, proxy_client_fn_ok};
+/// use std::collections::HashMap;
+///
+/// let mut map = HashMap::new();
+/// map.insert("en".to_string(), "Hello");
+/// map.insert("fr".to_string(), "Bonjour");
+///
+/// let client = proxy_client_fn_ok(localized_stub(map, "en"));
+/// ```
+///
+/// [RFC 4647]: https://tools.ietf.org/html/rfc4647#section-3.4
+pub fn localized_stub<B: Into<Bytes>>(
+    map: HashMap<String, B>,
+    default_lang: &str,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    let map: HashMap<_, _> = map
+        .into_iter()
+        .map(|(lang, body)| {
+            let content_language = HeaderValue::from_str(&lang).expect("invalid language tag");
+            (lang.to_ascii_lowercase(), (content_language, body.into()))
+        })
+        .collect();
+    let default_lang = default_lang.to_ascii_lowercase();
+    assert!(
+        map.contains_key(&default_lang),
+        "default language {} has no body",
+        default_lang
+    );
+    let map = Arc::new(map);
+
+    move |req| {
+        let lang = accepted_languages(req.headers())
+            .into_iter()
+            .filter_map(|range| {
+                let mut range = &range[..];
+                loop {
+                    if map.contains_key(range) {
+                        return Some(range.to_string());
+                    }
+                    range = &range[..range.rfind('-')?];
+                }
+            })
+            .next()
+            .unwrap_or_else(|| default_lang.clone());
+
+        let (ref content_language, ref body) = map[&lang];
+        let mut response = Response::new(body.clone().into());
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_LANGUAGE, content_language.clone());
+        headers.insert(VARY, HeaderValue::from_static("accept-language"));
+        response
+    }
+}
+
+// The language ranges in a request's Accept-Language headers, lowercased,
+// from most to least preferred, leaving out unacceptable ones and the
+// wildcard.
+fn accepted_languages(headers: &HeaderMap) -> Vec<String> {
+    let mut ranges: Vec<(String, f32)> = headers
+        .get_all(ACCEPT_LANGUAGE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_q)
+        .filter(|&(ref name, q)| q > 0.0 && !name.is_empty() && name != "*")
+        .collect();
+
+    // sort_by is stable, so ranges with equal q-values stay in order.
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    ranges.into_iter().map(|(name, _)| name).collect()
+}
+
+// Compares two entity tags with the weak comparison function from RFC 7232,
+// which ignores whether either of them is weak.
+fn weak_eq(a: &str, b: &HeaderValue) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    opaque(a) == opaque(b.to_str().unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use hyper::body::Payload;
+    use hyper::client::connect::Connect;
+    use hyper::Client;
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+    use transport::{Transport, UnboundedTransport};
+    use StubBuilder;
+
+    fn status<C: Connect + 'static>(runtime: &mut Runtime, client: &Client<C>) -> StatusCode {
+        runtime
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap()
+            .status()
+    }
+
+    #[test]
+    fn test_recovers_after_fail_count() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(flaky_then_healthy(2, Duration::from_secs(60)));
+
+        let statuses: Vec<_> = (0..4).map(|_| status(&mut runtime, &client)).collect();
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::OK,
+                StatusCode::OK,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recovers_after_duration() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(flaky_then_healthy(100, Duration::from_millis(50)));
+
+        assert_eq!(
+            status(&mut runtime, &client),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(status(&mut runtime, &client), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_maintenance_window() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(maintenance_window(
+            Duration::from_millis(50),
+            "down for maintenance",
+            |_| Response::new(Body::empty()),
+        ));
+
+        let future = client
+            .get("http://example.com".parse().unwrap())
+            .and_then(|res| {
+                assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+                res.into_body().concat2()
+            });
+        assert_eq!(
+            &runtime.block_on(future).unwrap()[..],
+            b"down for maintenance"
+        );
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(status(&mut runtime, &client), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_early_response() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let client = ::StubBuilder::new()
+            .transport(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                ::memsocket::unbounded()
+            })
+            .proxy_client_fn_ok(early_response(|req| {
+                assert!(req.body().is_end_stream());
+                let mut response = Response::new("too large".into());
+                *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                response
+            }));
+
+        // An upload that sends a chunk every 20ms, and would take 200ms.
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        let body = timer::Interval::new(Instant::now(), Duration::from_millis(20))
+            .take(10)
+            .map(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                vec![b'x'; 1024]
+            });
+        let request = Request::post("http://example.com/upload")
+            .body(Body::wrap_stream(body))
+            .unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let future = client.request(request).and_then(|res| {
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            res.into_body().concat2()
+        });
+        assert_eq!(&runtime.block_on(future).unwrap()[..], b"too large");
+        let sent_before_response = sent.load(Ordering::SeqCst);
+        assert!(sent_before_response < 10);
+
+        // The client has stopped sending the body, and opens a new
+        // connection for its next request.
+        let future = Delay::new(Instant::now() + Duration::from_millis(250));
+        runtime.block_on(future).unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), sent_before_response);
+        status(&mut runtime, &client);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_jittered() {
+        let profile = LatencyProfile::Uniform {
+            min: Duration::from_millis(20),
+            max: Duration::from_millis(40),
+        };
+        let client = ::proxy_client_fn(jittered(1, profile, |_| Response::new(Body::empty())));
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..5 {
+            let start = Instant::now();
+            assert_eq!(status(&mut runtime, &client), StatusCode::OK);
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_jittered_huge_delay() {
+        let profile = LatencyProfile::Uniform {
+            min: Duration::MAX,
+            max: Duration::MAX,
+        };
+        let client = ::proxy_client_fn(jittered(1, profile, |_| Response::new(Body::empty())));
+
+        // The response never arrives, but the stub doesn't fail either.
+        let future = client.get("http://example.com".parse().unwrap());
+        let future = Timeout::new(future, Duration::from_millis(100));
+        let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+        assert!(error.is_elapsed(), "{:?}", error);
+    }
+
+    #[test]
+    fn test_echo_correlation() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(echo_correlation(
+            HeaderName::from_static("x-request-id"),
+            |_| Response::new(Body::empty()),
+        ));
+
+        let request = Request::get("http://example.com")
+            .header("x-request-id", "abc-123")
+            .body(Body::empty())
+            .unwrap();
+        let response = runtime.block_on(client.request(request)).unwrap();
+        assert_eq!(response.headers()["x-request-id"], "abc-123");
+
+        let request = Request::get("http://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = runtime.block_on(client.request(request)).unwrap();
+        assert!(response.headers().get("x-request-id").is_none());
+    }
+
+    #[test]
+    fn test_multi_host_stub() {
+        let mut map: HashMap<_, ResponseFactory> = HashMap::new();
+        map.insert(
+            "users.internal".to_string(),
+            Box::new(|_| Response::new("users".into())),
+        );
+        map.insert(
+            "Billing.internal".to_string(),
+            Box::new(|_| Response::new("billing".into())),
+        );
+        map.insert(
+            "billing.internal:8443".to_string(),
+            Box::new(|_| Response::new("billing admin".into())),
+        );
+        let client = ::proxy_client_fn_ok(multi_host_stub(
+            map,
+            Box::new(|_| Response::new("default".into())),
+        ));
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |uri: &str, host: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(host) = host {
+                request.header(HOST, host);
+            }
+            let future = client
+                .request(request.body(Body::empty()).unwrap())
+                .and_then(|res| res.into_body().concat2());
+            String::from_utf8(runtime.block_on(future).unwrap().to_vec()).unwrap()
+        };
+
+        assert_eq!(get("http://users.internal/", None), "users");
+        assert_eq!(get("http://billing.internal/", None), "billing");
+        assert_eq!(get("http://BILLING.internal:80/", None), "billing");
+        assert_eq!(get("http://billing.internal:8443/", None), "billing admin");
+        assert_eq!(get("http://other.internal/", None), "default");
+
+        // The Host header doesn't change where the request goes.
+        assert_eq!(
+            get("http://users.internal/", Some("billing.internal")),
+            "users"
+        );
+    }
+
+    #[test]
+    fn test_vhost_stub() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(vhost_stub("api.example.com:8080", |_| {
+            Response::new(Body::empty())
+        }));
+        let mut status = |uri: &str, host: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(host) = host {
+                request.header(HOST, host);
+            }
+            let future = client.request(request.body(Body::empty()).unwrap());
+            runtime.block_on(future).unwrap().status()
+        };
+
+        assert_eq!(status("http://api.example.com:8080/", None), StatusCode::OK);
+        assert_eq!(status("http://API.example.com:8080/", None), StatusCode::OK);
+        assert_eq!(
+            status("http://api.example.com/", None),
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        assert_eq!(
+            status("http://www.example.com:8080/", None),
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        assert_eq!(
+            status("http://10.0.0.1:8080/", Some("api.example.com:8080")),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn test_timeout_test() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let future = timeout_test(Duration::from_millis(200), Duration::from_millis(20));
+        assert!(runtime.block_on(future).unwrap_err().is_elapsed());
+
+        let future = timeout_test(Duration::from_millis(20), Duration::from_millis(200));
+        assert_eq!(runtime.block_on(future).unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_blocking_stub() {
+        let client = blocking_stub();
+        let future = client.get("http://example.com".parse().unwrap());
+        let future = Timeout::new(future, Duration::from_millis(50));
+        let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+        assert!(error.is_elapsed());
+    }
+
+    #[test]
+    fn test_validate_content_length_matches() {
+        let client = ::proxy_client_fn(validate_content_length_stub(|req| {
+            Response::new(req.into_body())
+        }));
+        let mut runtime = Runtime::new().unwrap();
+
+        let post = |body| Request::post("http://example.com").body(body).unwrap();
+        let chunks = vec![Ok::<_, ::std::io::Error>("chun"), Ok("ked")];
+        for request in [
+            post(Body::from("hello")),
+            post(Body::wrap_stream(stream::iter_result(chunks))),
+            Request::get("http://example.com")
+                .body(Body::empty())
+                .unwrap(),
+        ] {
+            let response = runtime.block_on(client.request(request)).unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn test_validate_content_length_mismatch() {
+        let head = |length: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: {}\r\n\r\n",
+                length
+            )
+        };
+        let send = |request: String| {
+            let future = StubBuilder::new()
+                .raw_connection_fn(validate_content_length_stub(|_| {
+                    Response::new(Body::empty())
+                }))
+                .and_then(|io| ::tokio::io::write_all(io, request))
+                .and_then(|(io, _)| ::tokio::io::shutdown(io))
+                .and_then(|io| ::tokio::io::read_to_end(io, Vec::new()));
+            let (_, response) = Runtime::new().unwrap().block_on(future).unwrap();
+            String::from_utf8(response).unwrap()
+        };
+
+        assert!(send(head("5") + "hello").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = send(head("10") + "hello");
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("Content-Length was 10"), "{}", response);
+    }
+
+    #[test]
+    fn test_stall_upload_stub() {
+        // Sends a body of 16 chunks of 64 KiB, returning how many of them the
+        // client had taken from the stream when the request timed out.
+        fn upload<T: Transport>(transport: T) -> usize {
+            let client = StubBuilder::new()
+                .transport(transport)
+                .proxy_client_fn(stall_upload_stub());
+
+            let sent = Arc::new(AtomicUsize::new(0));
+            let counter = sent.clone();
+            let chunks = stream::iter_ok::<_, ::std::io::Error>(vec![vec![0; 64 * 1024]; 16])
+                .inspect(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            let request = Request::post("http://example.com/upload")
+                .body(Body::wrap_stream(chunks))
+                .unwrap();
+
+            let future = Timeout::new(client.request(request), Duration::from_millis(100));
+            let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+            assert!(error.is_elapsed());
+            sent.load(Ordering::SeqCst)
+        }
+
+        assert!(upload(|| ::memsocket::bounded(1024)) < 16);
+        assert_eq!(upload(UnboundedTransport), 16);
+    }
+
+    #[test]
+    fn test_https_only_stub() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(https_only_stub(|_| Response::new(Body::empty())));
+
+        let get = |uri: &str| client.get(uri.parse().unwrap());
+        let response = runtime.block_on(get("https://example.com")).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = runtime.block_on(get("http://example.com")).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_cors_preflight_and_get() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(cors_stub(
+            &["https://app.example.com"],
+            &[Method::GET, Method::PUT],
+            &["content-type", "x-token"],
+            |_| Response::new("hello".into()),
+        ));
+
+        let preflight = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("http://example.com/resource")
+            .header(ORIGIN, "https://app.example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .body(Body::empty())
+            .unwrap();
+        let response = runtime.block_on(client.request(preflight)).unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-token"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        let get = |origin| {
+            Request::get("http://example.com/resource")
+                .header(ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = runtime
+            .block_on(client.request(get("https://app.example.com")))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+
+        let response = runtime
+            .block_on(client.request(get("https://evil.example.com")))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[test]
+    fn test_delayed_by() {
+        let client = ::proxy_client_fn(delayed_by(
+            |req| match req.uri().path() {
+                "/slow" => Duration::from_millis(200),
+                _ => Duration::from_millis(0),
+            },
+            |_| Response::new(Body::empty()),
+        ));
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut latency = |uri: &str| {
+            let start = Instant::now();
+            runtime.block_on(client.get(uri.parse().unwrap())).unwrap();
+            start.elapsed()
+        };
+
+        assert!(latency("http://example.com/fast") < Duration::from_millis(200));
+        assert!(latency("http://example.com/slow") >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_close_connection() {
+        use stats::Stats;
+        use StubBuilder;
+
+        let mut runtime = Runtime::new().unwrap();
+        let handler = |_| Response::new(Body::empty());
+
+        let kept_alive = Stats::new();
+        let client = StubBuilder::new()
+            .stats(&kept_alive)
+            .proxy_client_fn_ok(handler);
+        status(&mut runtime, &client);
+        status(&mut runtime, &client);
+        assert_eq!(kept_alive.connections_opened(), 1);
+
+        let closed = Stats::new();
+        let client = StubBuilder::new()
+            .stats(&closed)
+            .proxy_client_fn_ok(close_connection(handler));
+        status(&mut runtime, &client);
+        status(&mut runtime, &client);
+        assert_eq!(closed.connections_opened(), 2);
+    }
+
+    #[test]
+    fn test_require_chunked_stub() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(require_chunked_stub(|req| {
+            assert_eq!(req.headers()[TRANSFER_ENCODING], "chunked");
+            Response::new(Body::empty())
+        }));
+
+        let post = |body| {
+            Request::post("http://example.com/upload")
+                .body(body)
+                .unwrap()
+        };
+
+        let length_framed = post(Body::from("data"));
+        let response = runtime.block_on(client.request(length_framed)).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let chunks = vec![Ok::<_, ::std::io::Error>("da"), Ok("ta")];
+        let chunked = post(Body::wrap_stream(stream::iter_result(chunks)));
+        let response = runtime.block_on(client.request(chunked)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_fail_matching() {
+        let ok_calls = Arc::new(AtomicUsize::new(0));
+        let client = ::proxy_client_fn_ok(fail_matching(
+            |req| req.uri().path() == "/crash",
+            "panicked at 'oops'\n  at src/main.rs:1:1",
+            {
+                let ok_calls = ok_calls.clone();
+                move |req| {
+                    ok_calls.fetch_add(1, Ordering::SeqCst);
+                    Response::new(req.uri().path().to_string().into())
+                }
+            },
+        ));
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |uri: &str| {
+            let future = client.get(uri.parse().unwrap()).and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().map(move |body| (status, body))
+            });
+            let (status, body) = runtime.block_on(future).unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        assert_eq!(
+            get("http://example.com/first"),
+            (StatusCode::OK, "/first".to_string())
+        );
+        assert_eq!(
+            get("http://example.com/crash"),
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "panicked at 'oops'\n  at src/main.rs:1:1".to_string()
+            )
+        );
+        assert_eq!(
+            get("http://example.com/last"),
+            (StatusCode::OK, "/last".to_string())
+        );
+        assert_eq!(ok_calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_localized_stub() {
+        let mut map = HashMap::new();
+        map.insert("en".to_string(), "Hello");
+        map.insert("fr".to_string(), "Bonjour");
+        map.insert("pt-BR".to_string(), "Olá");
+        let client = ::proxy_client_fn_ok(localized_stub(map, "en"));
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |accept_language: Option<&str>| {
+            let mut request = Request::get("http://example.com");
+            if let Some(accept_language) = accept_language {
+                request.header(ACCEPT_LANGUAGE, accept_language);
+            }
+            let future = client
+                .request(request.body(Body::empty()).unwrap())
+                .and_then(|res| {
+                    assert_eq!(res.headers()[VARY], "accept-language");
+                    let content_language = res.headers()[CONTENT_LANGUAGE].clone();
+                    res.into_body()
+                        .concat2()
+                        .map(move |body| (content_language, body))
+                });
+            let (content_language, body) = runtime.block_on(future).unwrap();
+            (
+                content_language.to_str().unwrap().to_string(),
+                String::from_utf8(body.to_vec()).unwrap(),
+            )
+        };
+        let expect = |lang: &str, body: &str| (lang.to_string(), body.to_string());
+
+        assert_eq!(get(Some("en")), expect("en", "Hello"));
+        assert_eq!(get(Some("fr")), expect("fr", "Bonjour"));
+        assert_eq!(get(Some("FR-ca")), expect("fr", "Bonjour"));
+        assert_eq!(get(Some("en;q=0.5, fr")), expect("fr", "Bonjour"));
+        assert_eq!(get(Some("de, fr;q=0.8, en;q=0.8")), expect("fr", "Bonjour"));
+        assert_eq!(get(Some("pt-br")), expect("pt-BR", "Olá"));
+        assert_eq!(get(Some("pt")), expect("en", "Hello"));
+        assert_eq!(get(Some("de")), expect("en", "Hello"));
+        assert_eq!(get(Some("fr;q=0, *")), expect("en", "Hello"));
+        assert_eq!(get(None), expect("en", "Hello"));
+    }
+
+    fn conditional_get(
+        runtime: &mut Runtime,
+        client: &Client<impl Connect + 'static>,
+        header: Option<(HeaderName, &'static str)>,
+    ) -> (StatusCode, Bytes) {
+        let mut request = Request::get("http://example.com/cached");
+        if let Some((name, value)) = header {
+            request.header(name, value);
+        }
+        let future = client
+            .request(request.body(Body::empty()).unwrap())
+            .and_then(|res| {
+                assert_eq!(res.headers()[ETAG], "W/\"v1\"");
+                let status = res.status();
+                res.into_body()
+                    .concat2()
+                    .map(move |body| (status, body.into_bytes()))
+            });
+        runtime.block_on(future).unwrap()
+    }
+
+    #[test]
+    fn test_conditional_stub() {
+        let mut runtime = Runtime::new().unwrap();
+        let client = ::proxy_client_fn_ok(conditional_stub("W/\"v1\"", "hello"));
+
+        let (status, body) = conditional_get(&mut runtime, &client, None);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"hello");
+
+        let matching = Some((IF_NONE_MATCH, "\"v0\", \"v1\""));
+        let (status, body) = conditional_get(&mut runtime, &client, matching);
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+
+        let stale = Some((IF_NONE_MATCH, "\"v0\""));
+        let (status, body) = conditional_get(&mut runtime, &client, stale);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"hello");
+
+        let since = Some((IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT"));
+        let (status, _) = conditional_get(&mut runtime, &client, since);
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+    }
+}
@@ -0,0 +1,290 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Handlers for stubbing JSON APIs.
+//!
+//! These are only available with the `serde` feature enabled. Values are
+//! serialized with [`serde_json`] once, when the handler is created, so a value
+//! that can't be serialized is reported as an `Err` straight away, rather than
+//! surfacing later as a mysterious failed request.
+//!
+//! [`serde_json`]: https://docs.rs/serde_json
+
+use bytes::Bytes;
+use hyper::client::connect::Connect;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{self, Value};
+use std::fmt::Debug;
+
+/// Creates a response with the given status and `value` serialized as its JSON
+/// body, with `Content-Type: application/json`.
+///
+/// # Errors
+///
+/// Returns an error if `value` can't be serialized as JSON.
+pub fn json_response<T>(status: StatusCode, value: &T) -> serde_json::Result<Response<Body>>
+where
+    T: Serialize + ?Sized,
+{
+    let body = serde_json::to_vec(value)?;
+    Ok(json_bytes_response(status, body.into()))
+}
+
+/// Creates a handler that responds to every request with `200 OK` and `value`
+/// serialized as its JSON body.
+///
+/// # Errors
+///
+/// Returns an error if `value` can't be serialized as JSON.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// let client = hyper_stub::proxy_client_fn_ok(hyper_stub::json_ok(&[1, 2, 3]).unwrap());
+/// ```
+pub fn json_ok<T>(
+    value: &T,
+) -> serde_json::Result<impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static>
+where
+    T: Serialize + ?Sized,
+{
+    json_status(StatusCode::OK, value)
+}
+
+/// Creates a handler that responds to every request with the given status and
+/// `value` serialized as its JSON body.
+///
+/// # Errors
+///
+/// Returns an error if `value` can't be serialized as JSON.
+pub fn json_status<T>(
+    status: StatusCode,
+    value: &T,
+) -> serde_json::Result<impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static>
+where
+    T: Serialize + ?Sized,
+{
+    let body: Bytes = serde_json::to_vec(value)?.into();
+    Ok(move |_| json_bytes_response(status, body.clone()))
+}
+
+/// Creates a handler that responds to every request with the given status and
+/// a body of `{"error": message}`.
+///
+/// Unlike the other JSON handlers, this can't fail, because the envelope is
+/// always serializable. See [`json_error_with`] to use a different envelope.
+///
+/// [`json_error_with`]: fn.json_error_with.html
+pub fn json_error(
+    status: StatusCode,
+    message: &str,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    json_error_with(status, message, |message| json!({ "error": message }))
+}
+
+/// Creates a handler that responds to every request with the given status and
+/// a body produced by passing `message` to `envelope`.
+///
+/// ```
+/// # #[macro_use] extern crate serde_json;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::StatusCode;
+/// use hyper_stub::json_error_with;
+///
+/// let handler = json_error_with(StatusCode::NOT_FOUND, "no such user", |message| {
+///     json!({ "errors": [{ "detail": message }] })
+/// });
+/// ```
+pub fn json_error_with<F>(
+    status: StatusCode,
+    message: &str,
+    envelope: F,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    F: FnOnce(&str) -> Value,
+{
+    let body: Bytes = envelope(message).to_string().into_bytes().into();
+    move |_| json_bytes_response(status, body.clone())
+}
+
+/// Creates a client whose stub responds to every request with `200 OK` and
+/// `value` serialized as its JSON body. This is shorthand for passing
+/// [`json_ok`] to [`proxy_client_fn_ok`], for the common case of a typed API
+/// test that checks the client gets `value` back, with [`assert_json_body`].
+///
+/// # Errors
+///
+/// Returns an error if `value` can't be serialized as JSON, before any client
+/// is created.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use futures::{Future, Stream};
+/// use hyper_stub::{assert_json_body, json_roundtrip_stub};
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let client = json_roundtrip_stub(&vec![1, 2, 3]).unwrap();
+/// let future = client
+///     .get("http://example.com".parse().unwrap())
+///     .and_then(|res| res.into_body().concat2());
+/// let body = Runtime::new().unwrap().block_on(future).unwrap();
+/// assert_json_body(&body, &vec![1, 2, 3]);
+/// ```
+///
+/// [`json_ok`]: fn.json_ok.html
+/// [`proxy_client_fn_ok`]: fn.proxy_client_fn_ok.html
+/// [`assert_json_body`]: fn.assert_json_body.html
+pub fn json_roundtrip_stub<T>(value: &T) -> serde_json::Result<Client<impl Connect>>
+where
+    T: Serialize + ?Sized,
+{
+    Ok(::proxy_client_fn_ok(json_ok(value)?))
+}
+
+/// Deserializes `body` as JSON, asserts that it equals `expected`, and returns
+/// it.
+///
+/// # Panics
+///
+/// Panics if `body` isn't valid JSON for `T`, with a message including the
+/// body, or if the value doesn't equal `expected`.
+pub fn assert_json_body<T>(body: &[u8], expected: &T) -> T
+where
+    T: DeserializeOwned + PartialEq + Debug,
+{
+    let actual: T = match serde_json::from_slice(body) {
+        Ok(actual) => actual,
+        Err(err) => panic!(
+            "couldn't deserialize JSON body ({}): {}",
+            err,
+            String::from_utf8_lossy(body)
+        ),
+    };
+    assert_eq!(
+        &actual, expected,
+        "JSON body didn't have the expected value"
+    );
+    actual
+}
+
+pub(crate) fn json_bytes_response(status: StatusCode, body: Bytes) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use serde_json;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct User {
+        id: u32,
+        name: String,
+        tags: Vec<String>,
+    }
+
+    fn get(
+        handler: impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+    ) -> (Response<()>, Vec<u8>) {
+        let client = ::proxy_client_fn_ok(handler);
+        Runtime::new()
+            .unwrap()
+            .block_on({
+                client
+                    .get("http://example.com".parse().unwrap())
+                    .and_then(|res| {
+                        let (parts, body) = res.into_parts();
+                        body.concat2()
+                            .map(|bytes| (Response::from_parts(parts, ()), bytes.to_vec()))
+                    })
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_json_ok_round_trip() {
+        let user = User {
+            id: 1,
+            name: "Alyssa".to_string(),
+            tags: vec!["admin".to_string()],
+        };
+
+        let (res, body) = get(json_ok(&user).unwrap());
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(serde_json::from_slice::<User>(&body).unwrap(), user);
+    }
+
+    #[test]
+    fn test_json_status() {
+        let (res, body) = get(json_status(StatusCode::CREATED, &vec![1, 2, 3]).unwrap());
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(
+            serde_json::from_slice::<Vec<u32>>(&body).unwrap(),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_json_error() {
+        let (res, body) = get(json_error(StatusCode::BAD_REQUEST, "bad things"));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value, json!({ "error": "bad things" }));
+    }
+
+    #[test]
+    fn test_json_roundtrip_stub() {
+        let user = User {
+            id: 7,
+            name: "Zoë \"Z\" O'Brien".to_string(),
+            tags: vec!["admin".to_string(), "ops".to_string()],
+        };
+
+        let client = json_roundtrip_stub(&user).unwrap();
+        let future = client
+            .get("http://example.com".parse().unwrap())
+            .and_then(|res| res.into_body().concat2());
+        let body = Runtime::new().unwrap().block_on(future).unwrap();
+
+        assert_eq!(assert_json_body(&body, &user), user);
+    }
+
+    #[test]
+    #[should_panic(expected = "couldn't deserialize JSON body")]
+    fn test_assert_json_body_invalid() {
+        let user = User {
+            id: 1,
+            name: String::new(),
+            tags: vec![],
+        };
+        assert_json_body(b"{\"id\": 1}", &user);
+    }
+
+    #[test]
+    fn test_serialize_error() {
+        use std::collections::BTreeMap;
+
+        // JSON object keys must be strings.
+        let mut map = BTreeMap::new();
+        map.insert(vec![1], 2);
+        assert!(json_ok(&map).is_err());
+    }
+}
@@ -48,28 +48,163 @@
 //! Finally, an advanced use case is using hyper [`services`] instead of simple
 //! functions. This can be done with the [`proxy_client`] function.
 //!
+//! Each of these functions also exists as a method on [`StubBuilder`], which
+//! can be used to configure how the stub server behaves, including the
+//! [`Transport`] used to connect clients to it.
+//!
+//! # HTTP versions
+//!
+//! Clients talk to the stub server over HTTP/1.1 by default, but a handler can
+//! answer with a different version in the status line by setting it on its
+//! response, with `*response.version_mut() = Version::HTTP_10`. Only HTTP/1.0
+//! and HTTP/1.1 can actually be written by hyper's server. An `HTTP_2`
+//! response is sent as HTTP/1.1 instead, and an `HTTP_09` response makes the
+//! stub server panic.
+//!
+//! A client made with `http2_only` set on its [`StubBuilder::client_builder`]
+//! talks HTTP/2 instead, which the stub server also speaks, and
+//! [`StubBuilder::negotiate_h2`] simulates the ALPN negotiation a client
+//! would do over TLS.
+//!
+//! # Content-Type
+//!
+//! Neither hyper's server nor this crate guesses a `Content-Type` for a
+//! response, so a response only has one if its handler sets it. Of the
+//! helpers that build responses:
+//!
+//! - [`json_ok`], [`json_status`], [`json_response`], [`json_error`] and
+//!   [`json_error_with`] set `Content-Type: application/json`, as do the
+//!   `400 Bad Request` responses sent for requests rejected by a [`Schema`].
+//! - [`sse_response`] sets `Content-Type: text/event-stream`.
+//! - [`no_content_type`], [`response_with_headers`],
+//!   [`large_header_response`], [`chunked_response`], and every handler
+//!   wrapper, like [`delayed`] or [`maintenance_window`], leave it out,
+//!   unless the wrapped handler sets it.
+//! - Responses replayed from a [`Cassette`] have exactly the headers that were
+//!   recorded.
+//!
+//! # Features
+//!
+//! - `serde`: enables handlers for stubbing JSON APIs, like [`json_ok`], and
+//!   for validating requests against a simple [`Schema`].
+//!
 //! [hyper]: https://hyper.rs
 //! [services]: https://docs.rs/hyper/0.12.1/hyper/service/index.html
 //! [`proxy_client_fn_ok`]: fn.proxy_client_fn_ok.html
 //! [`proxy_client_fn`]: fn.proxy_client_fn.html
 //! [`proxy_client`]: fn.proxy_client.html
+//! [`StubBuilder`]: struct.StubBuilder.html
+//! [`StubBuilder::client_builder`]: struct.StubBuilder.html#method.client_builder
+//! [`StubBuilder::negotiate_h2`]: struct.StubBuilder.html#method.negotiate_h2
+//! [`json_ok`]: fn.json_ok.html
+//! [`json_status`]: fn.json_status.html
+//! [`json_response`]: fn.json_response.html
+//! [`json_error`]: fn.json_error.html
+//! [`json_error_with`]: fn.json_error_with.html
+//! [`no_content_type`]: fn.no_content_type.html
+//! [`response_with_headers`]: fn.response_with_headers.html
+//! [`large_header_response`]: fn.large_header_response.html
+//! [`chunked_response`]: fn.chunked_response.html
+//! [`delayed`]: fn.delayed.html
+//! [`maintenance_window`]: fn.maintenance_window.html
+//! [`Cassette`]: struct.Cassette.html
+//! [`Schema`]: struct.Schema.html
+//! [`sse_response`]: fn.sse_response.html
+//! [`Transport`]: trait.Transport.html
 
+extern crate bytes;
+#[macro_use]
 extern crate futures;
+extern crate h2;
+extern crate http;
 extern crate hyper;
 extern crate memsocket;
 extern crate tokio;
 
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_json;
+
+mod body;
+mod builder;
+mod cassette;
+mod chaos;
 mod connector;
+mod drain;
+mod dump;
+mod encoding;
+mod fragmented;
+mod handlers;
+#[cfg(feature = "serde")]
+mod json;
 mod never;
+mod pagination;
+mod panic;
+mod payload;
+mod probe;
+mod raw;
+mod raw_response;
+mod record;
+#[cfg(feature = "serde")]
+mod record_file;
+mod response;
+mod router;
+mod rules;
+#[cfg(feature = "serde")]
+mod schema;
+mod sequence;
+mod service;
+mod snapshot;
+mod sse;
+mod stats;
+mod transport;
+
+pub use body::{
+    body_from_reader, body_from_results, body_stall_at, chunked_response, generated_body,
+    Interruption,
+};
+pub use builder::StubBuilder;
+pub use cassette::{Cassette, Interaction, RecordFuture, Replay, ReplayFuture};
+pub use chaos::{seeded_stub, Behavior, LatencyProfile, SeededFuture};
+pub use connector::{ConnectInfo, ConnectionRequestIndex};
+pub use dump::DumpOnDrop;
+pub use encoding::{bad_content_encoding_stub, negotiate_encoding, EncodingFuture};
+pub use handlers::*;
+#[cfg(feature = "serde")]
+pub use json::*;
+pub use pagination::{paginated_stub, NextLink};
+pub use panic::{HandlerPanic, PanicLog};
+pub use probe::{probe, Probe};
+pub use raw::RawRequestLog;
+pub use raw_response::{empty_reason_response, overlong_response};
+pub use record::{BodyFraming, RecordedRequest, RequestLog};
+pub use response::{large_header_response, no_content_type, response_with_headers, IntoResponse};
+pub use router::{Params, RouteCoverage, Router};
+pub use rules::{Matcher, ResponseFactory};
+#[cfg(feature = "serde")]
+pub use schema::{schema_stub, Schema, SchemaFuture};
+pub use sequence::{ExhaustionPolicy, Sequence};
+pub use snapshot::{RequestSnapshot, SnapshotBody};
+pub use sse::{sse_response, SseEvent};
+pub use stats::Stats;
+pub use transport::{Transport, UnboundedTransport};
 
+use bytes::Bytes;
 use connector::Connector;
 use futures::prelude::*;
 use hyper::body::{Body, Payload};
 use hyper::client::connect::Connect;
 use hyper::service::{NewService, Service};
-use hyper::{Client, Request, Response};
-use never::Never;
+use hyper::{Client, Request, Response, StatusCode, Uri};
+use std::collections::HashMap;
 use std::error::Error;
+use std::hash::Hash;
+use std::io;
 
 /// Creates a hyper client whose requests are converted to responses by being
 /// passed through a hyper [`Service`] instantiated by and returned from the given
@@ -104,9 +239,7 @@ where
         + Sync
         + Send,
 {
-    Client::builder()
-        .set_host(true)
-        .build(Connector::new(new_service))
+    StubBuilder::new().proxy_client(new_service)
 }
 
 /// Creates a hyper client whose requests are converted to responses by being
@@ -115,12 +248,87 @@ pub fn proxy_client_fn<E, Fut, F>(handler: F) -> Client<impl Connect>
 where
     E: Error + Send + Sync + 'static,
     Fut: Future<Item = Response<Body>, Error = E> + Send + 'static,
-    F: Fn(Request<Body>) -> Fut + Send + Sync + Copy + 'static,
+    F: Fn(Request<Body>) -> Fut + Send + Sync + Clone + 'static,
 {
-    use futures::future;
-    use hyper::service::service_fn;
+    StubBuilder::new().proxy_client_fn(handler)
+}
 
-    proxy_client(move || future::ok::<_, Never>(service_fn(handler)))
+/// Creates a hyper client whose requests are converted to responses by being
+/// passed through the given handler function, which can return anything that
+/// implements [`IntoResponse`].
+///
+/// This is like [`proxy_client_fn_ok`], but lets a handler return a response
+/// built with `Response::builder()` as it is, without unwrapping it, or a
+/// response with a body of some other type, or just a status.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::{Response, StatusCode};
+/// use hyper_stub::proxy_client_fn_into;
+///
+/// let client = proxy_client_fn_into(|req| match req.uri().path() {
+///     "/teapot" => Response::builder()
+///         .status(StatusCode::IM_A_TEAPOT)
+///         .header("content-type", "text/plain")
+///         .body("short and stout"),
+///     _ => Response::builder().status(StatusCode::NOT_FOUND).body(""),
+/// });
+/// ```
+///
+/// [`IntoResponse`]: trait.IntoResponse.html
+/// [`proxy_client_fn_ok`]: fn.proxy_client_fn_ok.html
+pub fn proxy_client_fn_into<F, R>(handler: F) -> Client<impl Connect>
+where
+    F: Fn(Request<Body>) -> R + Send + Sync + Clone + 'static,
+    R: IntoResponse,
+{
+    StubBuilder::new().proxy_client_fn_into(handler)
+}
+
+/// Creates a hyper client whose requests are converted to responses by being
+/// passed through the given handler function, which can mutate its own state,
+/// and returns a future.
+///
+/// This is for stubs whose responses depend on each other, like a long poll
+/// that only completes once another request has arrived. The handler is kept
+/// behind a mutex, so it's only ever called for one request at a time, in
+/// the order the stub server receives them. The mutex is only held while the
+/// handler is called to create the future, not while the future runs, so a
+/// future can wait on something a later request will do, and the futures of
+/// any number of requests can be pending at once. State that a future itself
+/// needs to change has to be shared with it some other way, like through a
+/// channel.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use futures::sync::oneshot;
+/// use futures::{future, Future};
+/// use hyper::Response;
+/// use hyper_stub::proxy_client_fn_mut_async;
+///
+/// // Each request waits for the next one to arrive.
+/// let mut waiting: Option<oneshot::Sender<()>> = None;
+/// let client = proxy_client_fn_mut_async(move |_| {
+///     if let Some(sender) = waiting.take() {
+///         let _ = sender.send(());
+///     }
+///     let (sender, receiver) = oneshot::channel();
+///     waiting = Some(sender);
+///     receiver.map(|()| Response::new("next request arrived".into()))
+/// });
+/// ```
+pub fn proxy_client_fn_mut_async<E, Fut, F>(handler: F) -> Client<impl Connect>
+where
+    E: Error + Send + Sync + 'static,
+    Fut: Future<Item = Response<Body>, Error = E> + Send + 'static,
+    F: FnMut(Request<Body>) -> Fut + Send + 'static,
+{
+    StubBuilder::new().proxy_client_fn_mut_async(handler)
 }
 
 /// Creates a hyper client whose requests are converted to responses by being
@@ -131,16 +339,468 @@ where
 /// [`proxy_client_fn`]: fn.proxy_client_fn.html
 pub fn proxy_client_fn_ok<F>(handler: F) -> Client<impl Connect>
 where
-    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Copy + 'static,
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
 {
-    use futures::future;
+    StubBuilder::new().proxy_client_fn_ok(handler)
+}
+
+/// Creates a hyper client whose requests are converted to responses by being
+/// passed through the given handler function, which can fail with an
+/// [`io::Error`] to break the connection instead of responding.
+///
+/// This is for making a client see a transport error for some requests but
+/// not others, from the same handler that answers the rest. When the handler
+/// returns an error, hyper's server closes the connection without writing a
+/// response, so the client's request fails with a hyper error for which
+/// `is_incomplete_message` returns true, as if the server had gone away
+/// while the request was in flight. The `io::Error` itself never reaches the
+/// client, since nothing about it is sent over the connection, so its kind
+/// and message don't matter. The connection isn't reused, and the client's
+/// next request opens a new one.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::proxy_client_fn_io;
+/// use std::io;
+///
+/// let client = proxy_client_fn_io(|req| match req.uri().path() {
+///     "/broken" => Err(io::Error::new(io::ErrorKind::ConnectionReset, "broken")),
+///     _ => Ok(Response::new("hello".into())),
+/// });
+/// ```
+///
+/// [`io::Error`]: https://doc.rust-lang.org/std/io/struct.Error.html
+pub fn proxy_client_fn_io<F>(handler: F) -> Client<impl Connect>
+where
+    F: Fn(Request<Body>) -> io::Result<Response<Body>> + Send + Sync + Clone + 'static,
+{
+    StubBuilder::new().proxy_client_fn_io(handler)
+}
+
+/// Creates a hyper client whose requests are passed to the given handler
+/// function with their bodies already read in full.
+///
+/// This saves a handler that needs the whole body from having to collect it
+/// itself, with `concat2`. The stub reads the body into memory before calling
+/// the handler, so a test that sends very large bodies may want to limit how
+/// much is buffered, with [`StubBuilder::max_buffered_body`]. Bodies longer
+/// than that are answered with `413 Payload Too Large`, without calling the
+/// handler.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::proxy_client_fn_buffered;
+///
+/// let client = proxy_client_fn_buffered(|req| {
+///     Response::new(format!("got {} bytes", req.body().len()).into())
+/// });
+/// ```
+///
+/// [`StubBuilder::max_buffered_body`]: struct.StubBuilder.html#method.max_buffered_body
+pub fn proxy_client_fn_buffered<F>(handler: F) -> Client<impl Connect>
+where
+    F: Fn(Request<Bytes>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    StubBuilder::new().proxy_client_fn_buffered(handler)
+}
+
+/// Like [`proxy_client_fn_ok`], but also returns a future that completes once
+/// the stub server has finished with every connection the client opened.
+///
+/// The future can only complete after the client has been dropped, because
+/// until then the client could always open another connection, and hyper's
+/// client keeps idle connections open in its pool. So the client should be
+/// dropped first, and then the future run to completion, on the same runtime
+/// as the client, to be sure that none of the stub server's tasks are left
+/// running.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use futures::Future;
+/// use hyper::Response;
+/// use hyper_stub::proxy_client_fn_ok_with_drain;
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let (client, drain) = proxy_client_fn_ok_with_drain(|_| Response::new("hello".into()));
+///
+/// let mut runtime = Runtime::new().unwrap();
+/// let future = client.get("http://example.com".parse().unwrap());
+/// runtime.block_on(future).unwrap();
+///
+/// drop(client);
+/// runtime.block_on(drain).unwrap();
+/// ```
+///
+/// [`proxy_client_fn_ok`]: fn.proxy_client_fn_ok.html
+pub fn proxy_client_fn_ok_with_drain<F>(
+    handler: F,
+) -> (Client<impl Connect>, impl Future<Item = (), Error = ()>)
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    StubBuilder::new().proxy_client_fn_ok_with_drain(handler)
+}
+
+/// Creates a hyper client that uses a stub server with a handler that
+/// returns both a response and a side effect, like the stub server recording
+/// an event, that shouldn't hold up the response. Also returns a future that
+/// completes once every side effect has, and the stub server has finished
+/// with all of its connections.
+///
+/// The response is sent straight away. The side effect is spawned onto the
+/// runtime as the handler returns, and runs independently of the response,
+/// so it may finish before or after the client receives the response, and
+/// side effects from different requests may finish in any order. Its result
+/// is ignored. To be sure that a side effect has happened, drop the client
+/// and run the returned future to completion, as with
+/// [`proxy_client_fn_ok_with_drain`].
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use futures::{future, Future};
+/// use hyper::Response;
+/// use hyper_stub::proxy_client_fn_side_effect;
+/// use std::sync::{Arc, Mutex};
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let handled = Arc::new(Mutex::new(Vec::new()));
+/// let effects = handled.clone();
+/// let (client, drain) = proxy_client_fn_side_effect(move |req| {
+///     let path = req.uri().path().to_string();
+///     let effects = effects.clone();
+///     let effect = future::lazy(move || {
+///         effects.lock().unwrap().push(path);
+///         Ok(())
+///     });
+///     (Response::new("hello".into()), effect)
+/// });
+///
+/// let mut runtime = Runtime::new().unwrap();
+/// let future = client.get("http://example.com/path".parse().unwrap());
+/// runtime.block_on(future).unwrap();
+///
+/// drop(client);
+/// runtime.block_on(drain).unwrap();
+/// assert_eq!(*handled.lock().unwrap(), ["/path"]);
+/// ```
+///
+/// [`proxy_client_fn_ok_with_drain`]: fn.proxy_client_fn_ok_with_drain.html
+pub fn proxy_client_fn_side_effect<F, E>(
+    handler: F,
+) -> (Client<impl Connect>, impl Future<Item = (), Error = ()>)
+where
+    F: Fn(Request<Body>) -> (Response<Body>, E) + Send + Sync + Clone + 'static,
+    E: Future<Item = (), Error = ()> + Send + 'static,
+{
+    StubBuilder::new().proxy_client_fn_side_effect(handler)
+}
+
+/// Creates a hyper client whose responses are made in two parts: a head from
+/// `head`, sent straight away, and a body from the future returned by `body`,
+/// sent whenever it resolves.
+///
+/// Both closures are called, `head` first, as soon as the stub server has
+/// read a request's head. `head` returns the response's status, version and
+/// headers, as a `Response<()>`, and is passed a reference to the request.
+/// `body` is then passed the request itself, so its future can read the
+/// request's body. The stub server writes the response head without waiting
+/// for the future, so the client's response future resolves first, and the
+/// body arrives once the future does.
+///
+/// Unless `head` sets `Content-Length`, the body is sent with chunked
+/// transfer encoding. If the future fails, the stub server aborts the
+/// connection, so the client sees an error reading the body.
+///
+/// ```
+/// # extern crate bytes;
+/// # extern crate futures;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use bytes::Bytes;
+/// use futures::future;
+/// use hyper::{Response, StatusCode};
+/// use hyper_stub::proxy_client_split;
+///
+/// let client = proxy_client_split(
+///     |_| {
+///         Response::builder()
+///             .status(StatusCode::CREATED)
+///             .body(())
+///             .unwrap()
+///     },
+///     |_| future::ok::<_, hyper::Error>(Bytes::from("computed later")),
+/// );
+/// ```
+pub fn proxy_client_split<E, BodyFut, H, B>(head: H, body: B) -> Client<impl Connect>
+where
+    E: Into<Box<dyn Error + Send + Sync>> + 'static,
+    BodyFut: Future<Item = Bytes, Error = E> + Send + 'static,
+    H: Fn(&Request<Body>) -> Response<()> + Send + Sync + 'static,
+    B: Fn(Request<Body>) -> BodyFut + Send + Sync + 'static,
+{
+    StubBuilder::new().proxy_client_split(head, body)
+}
+
+/// Creates a hyper client whose connections are answered with the exact bytes
+/// returned by `handler`, without going through hyper's server.
+///
+/// This is for responses hyper's server won't write, like ones with a custom
+/// or missing reason phrase (which doesn't even have to be UTF-8), or that
+/// are malformed in some way. hyper's server always writes the canonical
+/// reason phrase for a status code, or `<none>` for codes that don't have
+/// one, like 299.
+///
+/// For each connection, the stub reads the head of the first request, up to
+/// and including the blank line that ends it, and passes it to `handler`. As
+/// with every stub, the client treats the connection as one to a proxy, so
+/// the request line has the absolute URI, like
+/// `GET http://example.com/path HTTP/1.1`. The stub writes back whatever
+/// `handler` returns, and then closes the connection, so every request gets a
+/// new connection, and the response doesn't have to say where it ends. Any
+/// request body is ignored.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::proxy_client_raw;
+///
+/// let client = proxy_client_raw(|_head| b"HTTP/1.1 299 Totally Fine\r\n\r\n".to_vec());
+/// ```
+pub fn proxy_client_raw<F>(handler: F) -> Client<impl Connect>
+where
+    F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+{
+    StubBuilder::new().proxy_client_raw(handler)
+}
+
+/// Creates a hyper client whose connections are closed by the server as soon
+/// as they're opened, without it reading the request or responding.
+///
+/// This is for testing a client against a server that accepts a connection,
+/// then drops it straight away, like one that's overloaded. Unlike a failed
+/// connection (see [`StubBuilder::connect_fail_once`]), connecting succeeds,
+/// so the error only shows up once the client has tried to send a request.
+/// The request fails with a hyper error that isn't a connect error. hyper's
+/// client notices that the connection has been closed before it gets to
+/// write the request, so the error is usually one for which `is_closed`
+/// returns true ("channel closed"). If the request was already on its way,
+/// it would instead be one for which `is_incomplete_message` does, for a
+/// connection that closed before a response arrived. Either way, hyper's
+/// client doesn't retry the request, because the connection was new.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use hyper_stub::accept_then_close;
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let client = accept_then_close();
+/// let future = client.get("http://example.com".parse().unwrap());
+/// let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+/// assert!(error.is_closed() || error.is_incomplete_message());
+/// ```
+///
+/// [`StubBuilder::connect_fail_once`]: struct.StubBuilder.html#method.connect_fail_once
+pub fn accept_then_close() -> Client<impl Connect> {
+    StubBuilder::new().accept_then_close()
+}
+
+/// Creates a hyper client that speaks HTTP/2 to a server that answers every
+/// request with the head of a `200 OK` response, then resets the stream with
+/// a `RST_STREAM` frame carrying `error_code`.
+///
+/// This is for testing how a client handles a stream that a server aborts
+/// part of the way through a response. The response future resolves, since
+/// the head arrived, but reading its body fails with a hyper error whose
+/// cause is an [`h2::Error`], with the reason `error_code`. Error codes are
+/// the ones from [RFC 7540], like `0x2` for `INTERNAL_ERROR`, or `0x8` for
+/// `CANCEL`.
+///
+/// hyper's server can't do this: the only way to make it reset a stream
+/// mid-response is to make the response body fail, and then it always
+/// sends `INTERNAL_ERROR`. So the stub server is written with the [`h2`]
+/// crate's server instead, using `SendResponse::send_response` to send the
+/// head, and `SendStream::send_reset` to reset the stream. That works over
+/// the in-memory transport like over any other. The client is told HTTP/2
+/// was negotiated for its connections, as with
+/// [`StubBuilder::negotiate_h2`], so it doesn't have to be made with
+/// `http2_only`.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate h2;
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use futures::{Future, Stream};
+/// use hyper_stub::reset_after_headers;
+/// use std::error::Error;
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let client = reset_after_headers(0x8);
+/// let future = client
+///     .get("http://example.com".parse().unwrap())
+///     .and_then(|res| res.into_body().concat2());
+/// let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+/// let cause = error.into_cause().unwrap();
+/// let reason = cause.downcast_ref::<h2::Error>().unwrap().reason();
+/// assert_eq!(reason, Some(h2::Reason::CANCEL));
+/// ```
+///
+/// [`h2::Error`]: https://docs.rs/h2/0.1/h2/struct.Error.html
+/// [RFC 7540]: https://tools.ietf.org/html/rfc7540#section-7
+/// [`h2`]: https://docs.rs/h2/0.1
+/// [`StubBuilder::negotiate_h2`]: struct.StubBuilder.html#method.negotiate_h2
+pub fn reset_after_headers(error_code: u32) -> Client<impl Connect> {
+    StubBuilder::new().reset_after_headers(error_code)
+}
+
+/// Creates a hyper client that responds to each request using the first of
+/// `rules` whose [`Matcher`] returns true for it.
+///
+/// Rules are tried in order, so when several match a request, the one that
+/// comes first wins, and later ones can act as more general defaults. A
+/// request no rule matches gets a `404 Not Found` response; to handle those
+/// requests differently, finish with a rule that always matches.
+///
+/// Matchers only see the request head, not its body.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::{Method, Response};
+/// use hyper_stub::{proxy_client_rules, Matcher, ResponseFactory};
+///
+/// let rules: Vec<(Matcher, ResponseFactory)> = vec![
+///     (
+///         Box::new(|req| req.headers().contains_key("x-api-key")),
+///         Box::new(|_| Response::new("authorized".into())),
+///     ),
+///     (
+///         Box::new(|req| req.method() == Method::GET),
+///         Box::new(|_| Response::new("anonymous".into())),
+///     ),
+/// ];
+/// let client = proxy_client_rules(rules);
+/// ```
+///
+/// [`Matcher`]: type.Matcher.html
+pub fn proxy_client_rules(rules: Vec<(Matcher, ResponseFactory)>) -> Client<impl Connect> {
+    StubBuilder::new().proxy_client_rules(rules)
+}
+
+/// Creates a hyper client that responds to each request whose URI is a key in
+/// `map` with the status and body it maps to, and to every other request with
+/// `404 Not Found`.
+///
+/// This is the simplest way to stub a handful of fixed responses. The whole
+/// URI has to match, and because stub clients send requests as if to a proxy,
+/// the URI the stub sees is the absolute one the client requested, like
+/// `http://example.com/a`. So keys have to be absolute URIs too, and a key of
+/// just `/a` will never match. URIs are compared as hyper's [`Uri`] compares
+/// them: the scheme and host are case-insensitive, and an empty path is the
+/// same as `/`, but everything else is exact, so a query string, a trailing
+/// slash, or an explicit port all have to match. See [`Router`] for matching
+/// on the path alone.
+///
+/// ```
+/// # extern crate bytes;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use bytes::Bytes;
+/// use hyper::StatusCode;
+/// use hyper_stub::proxy_client_map;
+/// use std::collections::HashMap;
+///
+/// let mut map = HashMap::new();
+/// map.insert(
+///     "http://example.com/status".parse().unwrap(),
+///     (StatusCode::OK, Bytes::from("all good")),
+/// );
+/// let client = proxy_client_map(map);
+/// ```
+///
+/// [`Uri`]: https://docs.rs/http/0.1/http/uri/struct.Uri.html
+/// [`Router`]: struct.Router.html
+pub fn proxy_client_map(map: HashMap<Uri, (StatusCode, Bytes)>) -> Client<impl Connect> {
+    StubBuilder::new().proxy_client_map(map)
+}
 
-    proxy_client_fn(move |req| future::ok::<_, Never>(handler(req)))
+/// Creates a hyper client that responds to each request using the
+/// [`ResponseFactory`] that `map` has for the key `extractor` derives from
+/// it, or `default` if there isn't one.
+///
+/// This is the most general way of routing requests: the key can be
+/// anything that can be looked up in a `HashMap`, like a tenant ID taken from
+/// a header, the request's method and path together, or a hash of its sorted
+/// headers. [`proxy_client_map`] and [`Router`] cover the common cases more
+/// concisely. `extractor` is called once per request.
+///
+/// Like a [`Matcher`], `extractor` only sees the request head. The body
+/// hasn't been read yet when it's called, so routing on the body needs a
+/// handler of its own that reads it first, like one passed to
+/// [`proxy_client_fn_buffered`].
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_keyed, ResponseFactory};
+/// use std::collections::HashMap;
+///
+/// let mut map: HashMap<_, ResponseFactory> = HashMap::new();
+/// map.insert("acme".to_string(), Box::new(|_| Response::new("hello, acme".into())));
+///
+/// let client = proxy_client_keyed(
+///     |req| {
+///         let tenant = req.headers().get("x-tenant");
+///         tenant.and_then(|t| t.to_str().ok()).unwrap_or("").to_string()
+///     },
+///     map,
+///     Box::new(|_| Response::new("hello, stranger".into())),
+/// );
+/// ```
+///
+/// [`ResponseFactory`]: type.ResponseFactory.html
+/// [`proxy_client_map`]: fn.proxy_client_map.html
+/// [`Router`]: struct.Router.html
+/// [`Matcher`]: type.Matcher.html
+/// [`proxy_client_fn_buffered`]: fn.proxy_client_fn_buffered.html
+pub fn proxy_client_keyed<K, X>(
+    extractor: X,
+    map: HashMap<K, ResponseFactory>,
+    default: ResponseFactory,
+) -> Client<impl Connect>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    X: Fn(&Request<Body>) -> K + Send + Sync + 'static,
+{
+    StubBuilder::new().proxy_client_keyed(extractor, map, default)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use never::Never;
 
     #[test]
     fn test_ok() {
@@ -168,6 +828,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(non_local_definitions)]
     fn test_err() {
         use futures::future::{self, FutureResult};
         use futures::prelude::*;
@@ -209,4 +870,231 @@ mod tests {
                 .map_err(|err| assert!(err.to_string().contains("correct error for test")))
         });
     }
+
+    #[test]
+    fn test_response_version() {
+        use futures::prelude::*;
+        use hyper::Version;
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = proxy_client_fn_ok(|_| {
+            let mut response = Response::new("hello".into());
+            *response.version_mut() = Version::HTTP_10;
+            response
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..2 {
+            let future = client
+                .get("http://example.com".parse().unwrap())
+                .and_then(|res| {
+                    let version = res.version();
+                    res.into_body().concat2().map(move |body| (version, body))
+                });
+            let (version, body) = runtime.block_on(future).unwrap();
+            assert_eq!(version, Version::HTTP_10);
+            assert_eq!(&body[..], b"hello");
+        }
+    }
+
+    #[test]
+    fn test_buffered() {
+        use futures::prelude::*;
+        use hyper::StatusCode;
+        use tokio::runtime::current_thread::Runtime;
+
+        let handler = |req: Request<Bytes>| {
+            assert_eq!(&req.body()[..], b"a buffered body");
+            Response::new(Body::from(req.into_body()))
+        };
+        let upload = || {
+            let body = Body::wrap_stream(futures::stream::iter_ok::<_, std::io::Error>(vec![
+                "a ",
+                "buffered ",
+                "body",
+            ]));
+            Request::post("http://example.com").body(body).unwrap()
+        };
+
+        let mut runtime = Runtime::new().unwrap();
+        let client = proxy_client_fn_buffered(handler);
+        let future = client
+            .request(upload())
+            .and_then(|res| res.into_body().concat2());
+        assert_eq!(&runtime.block_on(future).unwrap()[..], b"a buffered body");
+
+        let client = StubBuilder::new()
+            .max_buffered_body(8)
+            .proxy_client_fn_buffered(handler);
+        let response = runtime.block_on(client.request(upload())).unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_fn_mut_async() {
+        use futures::future::{self, Either};
+        use futures::prelude::*;
+        use futures::sync::oneshot;
+        use tokio::runtime::current_thread::Runtime;
+
+        // Requests meet in pairs: the first of each pair waits for the
+        // second, which releases it.
+        let mut waiting: Option<oneshot::Sender<String>> = None;
+        let client = proxy_client_fn_mut_async(move |req| match waiting.take() {
+            Some(sender) => {
+                sender.send(req.uri().path().to_string()).unwrap();
+                Either::A(future::ok(Response::new("released".into())))
+            }
+            None => {
+                let (sender, receiver) = oneshot::channel();
+                waiting = Some(sender);
+                Either::B(receiver.map(|path| Response::new(format!("met {}", path).into())))
+            }
+        });
+
+        let get = |uri: &str| {
+            client
+                .get(uri.parse().unwrap())
+                .and_then(|res| res.into_body().concat2())
+                .map(|body| String::from_utf8(body.to_vec()).unwrap())
+        };
+        let mut runtime = Runtime::new().unwrap();
+        let (first, second) = runtime
+            .block_on(get("http://example.com/a").join(get("http://example.com/b")))
+            .unwrap();
+        let mut bodies = vec![first, second];
+        bodies.sort();
+        assert!(bodies[0].starts_with("met /"), "{:?}", bodies);
+        assert_eq!(bodies[1], "released");
+    }
+
+    #[test]
+    fn test_proxy_client_fn_io() {
+        use futures::prelude::*;
+        use hyper::StatusCode;
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = proxy_client_fn_io(|req| match req.uri().path() {
+            "/broken" => Err(io::Error::new(io::ErrorKind::ConnectionReset, "broken")),
+            _ => Ok(Response::new("hello".into())),
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let future = client.get("http://example.com/broken".parse().unwrap());
+        let error = runtime.block_on(future).unwrap_err();
+        assert!(error.is_incomplete_message(), "{}", error);
+
+        let future = client
+            .get("http://example.com/ok".parse().unwrap())
+            .and_then(|res| {
+                assert_eq!(res.status(), StatusCode::OK);
+                res.into_body().concat2()
+            });
+        assert_eq!(&runtime.block_on(future).unwrap()[..], b"hello");
+    }
+
+    #[test]
+    fn test_accept_then_close() {
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = accept_then_close();
+        let future = client.get("http://example.com".parse().unwrap());
+        let error = Runtime::new().unwrap().block_on(future).unwrap_err();
+        assert!(!error.is_connect());
+        assert!(
+            error.is_closed() || error.is_incomplete_message(),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_reset_after_headers() {
+        use futures::prelude::*;
+        use h2;
+        use hyper::StatusCode;
+        use tokio::runtime::current_thread::Runtime;
+
+        let client = reset_after_headers(0x2);
+        let mut runtime = Runtime::new().unwrap();
+        let response = runtime
+            .block_on(client.get("http://example.com".parse().unwrap()))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let error = runtime
+            .block_on(response.into_body().concat2())
+            .unwrap_err();
+        let cause = error.into_cause().unwrap();
+        let reason = cause.downcast_ref::<h2::Error>().unwrap().reason();
+        assert_eq!(reason, Some(h2::Reason::INTERNAL_ERROR));
+    }
+
+    #[test]
+    fn test_split() {
+        use futures::prelude::*;
+        use hyper::StatusCode;
+        use std::time::{Duration, Instant};
+        use tokio::runtime::current_thread::Runtime;
+        use tokio::timer::Delay;
+
+        let client = proxy_client_split(
+            |_| {
+                Response::builder()
+                    .status(StatusCode::CREATED)
+                    .header("x-phase", "head")
+                    .body(())
+                    .unwrap()
+            },
+            |req| {
+                let path = req.uri().path().to_string();
+                Delay::new(Instant::now() + Duration::from_millis(100))
+                    .map(move |()| Bytes::from(path))
+            },
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        let response = runtime
+            .block_on(client.get("http://example.com/lazy".parse().unwrap()))
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["x-phase"], "head");
+
+        let body = runtime.block_on(response.into_body().concat2()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(&body[..], b"/lazy");
+    }
+
+    #[test]
+    fn test_side_effect() {
+        use futures::prelude::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+        use tokio::runtime::current_thread::Runtime;
+        use tokio::timer::Delay;
+
+        let events = Arc::new(AtomicUsize::new(0));
+        let counter = events.clone();
+        let (client, drain) = proxy_client_fn_side_effect(move |_| {
+            let counter = counter.clone();
+            let effect = Delay::new(Instant::now() + Duration::from_millis(100))
+                .map(move |()| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .map_err(|_| ());
+            (Response::new(Body::empty()), effect)
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let future = client.get("http://example.com".parse().unwrap());
+        runtime.block_on(future).unwrap();
+        assert_eq!(events.load(Ordering::SeqCst), 0);
+
+        drop(client);
+        runtime.block_on(drain).unwrap();
+        assert_eq!(events.load(Ordering::SeqCst), 1);
+    }
 }
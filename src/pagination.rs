@@ -0,0 +1,222 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE, LINK};
+use hyper::{Body, Request, Response};
+use sequence::Sequence;
+#[cfg(feature = "serde")]
+use serde_json::{self, Value};
+
+/// How [`paginated_stub`] tells a client where the next page is.
+///
+/// [`paginated_stub`]: fn.paginated_stub.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NextLink {
+    /// A `Link` header, as in `Link: <https://example.com/items?page=2>;
+    /// rel="next"`, like GitHub's API uses. The last page has no `Link`
+    /// header.
+    LinkHeader,
+
+    /// A field with this name at the top level of each page's body, which
+    /// must be a JSON object, like `{"items": [], "next": "..."}`. The
+    /// field is `null` on the last page. Pages are sent with
+    /// `Content-Type: application/json`.
+    ///
+    /// This needs the `serde` feature, which is used to add the field to
+    /// each page. The variant always exists, so that turning the feature on
+    /// doesn't break code that matches on `NextLink`, but without the
+    /// feature, [`paginated_stub`] panics if it's given this variant.
+    ///
+    /// [`paginated_stub`]: fn.paginated_stub.html
+    JsonField(String),
+}
+
+/// Creates a handler that serves `pages` in order, one per request, telling
+/// the client the URL of the next page in the way given by `next_link`, for
+/// testing a client that follows pagination links.
+///
+/// Each page is a body and the URL of the page after it, which should be
+/// `None` for the last page. Pages are handed out like the steps of a
+/// [`Sequence`]: the first request gets the first page, whatever its URL,
+/// the second request the second page, and so on, so the URLs are only
+/// there for the client to follow, and aren't checked. Once every page has
+/// been served, further requests get `500 Internal Server Error`, since a
+/// client that carries on after the last page is usually broken. Record
+/// the requests with a [`RequestLog`] to check which URLs the client asked
+/// for.
+///
+/// It panics if a URL can't be put in a header, or, with
+/// [`NextLink::JsonField`], if a page's body isn't a JSON object, or the
+/// `serde` feature isn't enabled.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{paginated_stub, proxy_client_fn_ok, NextLink};
+///
+/// let client = proxy_client_fn_ok(paginated_stub(
+///     vec![
+///         ("[1, 2]", Some("http://example.com/items?page=2")),
+///         ("[3]", None),
+///     ],
+///     NextLink::LinkHeader,
+/// ));
+/// ```
+///
+/// [`Sequence`]: struct.Sequence.html
+/// [`RequestLog`]: struct.RequestLog.html
+/// [`NextLink::JsonField`]: enum.NextLink.html#variant.JsonField
+pub fn paginated_stub<B: Into<Bytes>>(
+    pages: Vec<(B, Option<&str>)>,
+    next_link: NextLink,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    let mut sequence = Sequence::new();
+    for (body, next) in pages {
+        let page = page(body.into(), next, &next_link);
+        sequence = sequence.then(move |_| {
+            let mut response = Response::new(page.body.clone().into());
+            let headers = response.headers_mut();
+            if let Some(ref link) = page.link {
+                headers.insert(LINK, link.clone());
+            }
+            if let Some(content_type) = page.content_type {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            }
+            response
+        });
+    }
+    sequence.handler()
+}
+
+// A page as it's sent, with the link to the next one already added.
+struct Page {
+    body: Bytes,
+    link: Option<HeaderValue>,
+    content_type: Option<&'static str>,
+}
+
+fn page(body: Bytes, next: Option<&str>, next_link: &NextLink) -> Page {
+    match *next_link {
+        NextLink::LinkHeader => Page {
+            body,
+            link: next.map(|next| {
+                HeaderValue::from_str(&format!("<{}>; rel=\"next\"", next))
+                    .expect("invalid next page URL")
+            }),
+            content_type: None,
+        },
+
+        #[cfg(not(feature = "serde"))]
+        NextLink::JsonField(_) => panic!("NextLink::JsonField needs the serde feature"),
+
+        #[cfg(feature = "serde")]
+        NextLink::JsonField(ref field) => {
+            let mut value: Value = serde_json::from_slice(&body).expect("page isn't JSON");
+            value
+                .as_object_mut()
+                .expect("page isn't a JSON object")
+                .insert(field.clone(), next.map_or(Value::Null, Value::from));
+            Page {
+                body: serde_json::to_vec(&value).unwrap().into(),
+                link: None,
+                content_type: Some("application/json"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use hyper::{StatusCode, Uri};
+    use tokio::runtime::current_thread::Runtime;
+    use {RequestLog, StubBuilder};
+
+    #[test]
+    fn test_follows_link_header() {
+        let log = RequestLog::new();
+        let client = StubBuilder::new()
+            .request_log(&log)
+            .proxy_client_fn_ok(paginated_stub(
+                vec![
+                    ("a", Some("http://example.com/items?page=2")),
+                    ("b", Some("http://example.com/items?page=3")),
+                    ("c", None),
+                ],
+                NextLink::LinkHeader,
+            ));
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut next: Option<Uri> = Some("http://example.com/items".parse().unwrap());
+        let mut bodies = Vec::new();
+        while let Some(uri) = next.take() {
+            let future = client.get(uri).and_then(|res| {
+                let link = res.headers().get(LINK).cloned();
+                res.into_body().concat2().map(move |body| (link, body))
+            });
+            let (link, body) = runtime.block_on(future).unwrap();
+            bodies.push(String::from_utf8(body.to_vec()).unwrap());
+            next = link.map(|link| {
+                let link = link.to_str().unwrap();
+                assert!(link.ends_with(">; rel=\"next\""), "{}", link);
+                link[1..link.find('>').unwrap()].parse().unwrap()
+            });
+        }
+
+        assert_eq!(bodies, ["a", "b", "c"]);
+        let queries: Vec<_> = log
+            .entries()
+            .iter()
+            .map(|entry| entry.uri().query().map(String::from))
+            .collect();
+        assert_eq!(
+            queries,
+            [None, Some("page=2".to_string()), Some("page=3".to_string())]
+        );
+
+        let response = runtime
+            .block_on(client.get("http://example.com/items?page=4".parse().unwrap()))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    #[cfg(not(feature = "serde"))]
+    #[should_panic(expected = "NextLink::JsonField needs the serde feature")]
+    fn test_json_field_without_serde() {
+        let _ = paginated_stub(vec![("{}", None)], NextLink::JsonField("next".to_string()));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_json_field() {
+        let client = ::proxy_client_fn_ok(paginated_stub(
+            vec![
+                (r#"{"items": [1, 2]}"#, Some("/items?page=2")),
+                (r#"{"items": [3]}"#, None),
+            ],
+            NextLink::JsonField("next".to_string()),
+        ));
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |uri: &str| {
+            let future = client.get(uri.parse().unwrap()).and_then(|res| {
+                assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+                res.into_body().concat2()
+            });
+            serde_json::from_slice::<Value>(&runtime.block_on(future).unwrap()).unwrap()
+        };
+
+        assert_eq!(
+            get("http://example.com/items"),
+            json!({"items": [1, 2], "next": "/items?page=2"})
+        );
+        assert_eq!(
+            get("http://example.com/items?page=2"),
+            json!({"items": [3], "next": null})
+        );
+    }
+}
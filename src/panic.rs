@@ -0,0 +1,157 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::panic;
+use std::sync::{Arc, Mutex, Once};
+
+/// A log of the panics caught in a stub's handler. See
+/// [`StubBuilder::catch_handler_panics`].
+///
+/// [`StubBuilder::catch_handler_panics`]: struct.StubBuilder.html#method.catch_handler_panics
+#[derive(Clone, Debug, Default)]
+pub struct PanicLog {
+    panics: Arc<Mutex<Vec<HandlerPanic>>>,
+}
+
+impl PanicLog {
+    /// Creates an empty log.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the panics caught so far, in the order they happened.
+    pub fn panics(&self) -> Vec<HandlerPanic> {
+        self.panics.lock().unwrap().clone()
+    }
+
+    /// Returns the number of panics caught so far.
+    pub fn len(&self) -> usize {
+        self.panics.lock().unwrap().len()
+    }
+
+    /// Returns whether no panics have been caught yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn push(&self, panic: HandlerPanic) {
+        self.panics.lock().unwrap().push(panic);
+    }
+}
+
+/// A panic caught in a stub's handler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandlerPanic {
+    message: String,
+    location: Option<String>,
+}
+
+impl HandlerPanic {
+    pub(crate) fn new(payload: &(dyn Any + Send)) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Box<Any>".to_string()
+        };
+
+        HandlerPanic {
+            message,
+            location: LOCATION.with(|location| location.borrow_mut().take()),
+        }
+    }
+
+    /// Returns the panic's message, if it was a string. Otherwise, returns
+    /// `"Box<Any>"`, like the standard library's panic handler.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the file, line, and column the panic happened at, if known.
+    pub fn location(&self) -> Option<&str> {
+        self.location.as_deref()
+    }
+}
+
+impl Display for HandlerPanic {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "handler panicked at '{}'", self.message)?;
+        if let Some(ref location) = self.location {
+            write!(fmt, ", {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+// catch_unwind only provides a panic's payload, so to find out where the panic
+// happened, a panic hook stashes the location of the most recent panic on each
+// thread. The previous hook is still called, so panics are still printed as
+// normal.
+pub fn install_location_hook() {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let location = info.location().map(ToString::to_string);
+            LOCATION.with(|cell| *cell.borrow_mut() = location);
+            previous(info);
+        }));
+    });
+
+    LOCATION.with(|location| location.borrow_mut().take());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use hyper::{Response, StatusCode};
+    use tokio::runtime::current_thread::Runtime;
+    use StubBuilder;
+
+    #[test]
+    fn test_catches_handler_panics() {
+        let log = PanicLog::new();
+        let client = StubBuilder::new()
+            .catch_handler_panics(&log)
+            .proxy_client_fn_ok(|req| {
+                if req.uri().path() == "/panic" {
+                    panic!("boom");
+                }
+                Response::new("ok".into())
+            });
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |uri: &str| {
+            runtime
+                .block_on(client.get(uri.parse().unwrap()).and_then(|res| {
+                    let status = res.status();
+                    res.into_body().concat2().map(move |body| (status, body))
+                }))
+                .unwrap()
+        };
+
+        let (status, body) = get("http://example.com/panic");
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(String::from_utf8_lossy(&body).contains("boom"));
+
+        let (status, body) = get("http://example.com/");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"ok");
+
+        assert_eq!(log.len(), 1);
+        let panic = &log.panics()[0];
+        assert_eq!(panic.message(), "boom");
+        assert!(panic.location().unwrap().contains("src/panic.rs"));
+    }
+}
@@ -0,0 +1,178 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Buf;
+use futures::prelude::*;
+use hyper::body::{Body, Payload};
+use hyper::{Chunk, HeaderMap};
+use service::BoxError;
+use stats::{InFlight, Stats};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+// The response body type of a StubService. The stub sometimes needs to respond
+// without asking the inner service (e.g. to report a panic), and since the
+// inner service's body type is generic, those responses need a body type of
+// their own.
+pub enum StubBody<B> {
+    Inner(B),
+    Stub(Body),
+}
+
+pub enum StubData<D> {
+    Inner(D),
+    Stub(Chunk),
+}
+
+impl<D: Buf> Buf for StubData<D> {
+    fn remaining(&self) -> usize {
+        match *self {
+            StubData::Inner(ref data) => data.remaining(),
+            StubData::Stub(ref data) => data.remaining(),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match *self {
+            StubData::Inner(ref data) => data.bytes(),
+            StubData::Stub(ref data) => data.bytes(),
+        }
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        match *self {
+            StubData::Inner(ref mut data) => data.advance(cnt),
+            StubData::Stub(ref mut data) => data.advance(cnt),
+        }
+    }
+}
+
+impl<B: Payload> Payload for StubBody<B> {
+    type Data = StubData<B::Data>;
+    type Error = BoxError;
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, BoxError> {
+        match *self {
+            StubBody::Inner(ref mut body) => {
+                let data = try_ready!(body.poll_data().map_err(Into::into));
+                Ok(Async::Ready(data.map(StubData::Inner)))
+            }
+            StubBody::Stub(ref mut body) => {
+                let data = try_ready!(body.poll_data());
+                Ok(Async::Ready(data.map(StubData::Stub)))
+            }
+        }
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, BoxError> {
+        match *self {
+            StubBody::Inner(ref mut body) => body.poll_trailers().map_err(Into::into),
+            StubBody::Stub(ref mut body) => Ok(body.poll_trailers()?),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match *self {
+            StubBody::Inner(ref body) => body.is_end_stream(),
+            StubBody::Stub(ref body) => body.is_end_stream(),
+        }
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        match *self {
+            StubBody::Inner(ref body) => body.content_length(),
+            StubBody::Stub(ref body) => body.content_length(),
+        }
+    }
+}
+
+// Holds back a response body until a delay has passed, starting from when
+// hyper's server first asks for it. See StubBuilder::body_delay.
+pub struct DelayedBody<B> {
+    body: B,
+    delay: Option<Duration>,
+    timer: Option<Delay>,
+}
+
+impl<B> DelayedBody<B> {
+    pub fn new(body: B, delay: Option<Duration>) -> Self {
+        DelayedBody {
+            body,
+            delay,
+            timer: None,
+        }
+    }
+}
+
+impl<B: Payload<Error = BoxError>> Payload for DelayedBody<B> {
+    type Data = B::Data;
+    type Error = BoxError;
+
+    fn poll_data(&mut self) -> Poll<Option<B::Data>, BoxError> {
+        if let Some(delay) = self.delay.take() {
+            self.timer = Some(Delay::new(Instant::now() + delay));
+        }
+        if let Some(ref mut timer) = self.timer {
+            try_ready!(timer.poll());
+        }
+        self.timer = None;
+        self.body.poll_data()
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, BoxError> {
+        self.body.poll_trailers()
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.body.content_length()
+    }
+}
+
+// Counts the bytes of a response body in a stub's Stats as hyper's server
+// takes them to write, and keeps its request counted as in flight until the
+// body is dropped.
+pub struct CountedBody<B> {
+    body: B,
+    stats: Option<Stats>,
+    _in_flight: Option<InFlight>,
+}
+
+impl<B> CountedBody<B> {
+    pub fn new(body: B, stats: Option<Stats>, in_flight: Option<InFlight>) -> Self {
+        CountedBody {
+            body,
+            stats,
+            _in_flight: in_flight,
+        }
+    }
+}
+
+impl<B: Payload> Payload for CountedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<B::Data>, B::Error> {
+        let data = try_ready!(self.body.poll_data());
+        if let (Some(stats), Some(data)) = (&self.stats, &data) {
+            stats.record_response_body_bytes(data.remaining() as u64);
+        }
+        Ok(Async::Ready(data))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, B::Error> {
+        self.body.poll_trailers()
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.body.content_length()
+    }
+}
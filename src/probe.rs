@@ -0,0 +1,168 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use futures::prelude::*;
+use hyper::client::connect::Connect;
+use hyper::{Body, Client, HeaderMap, Request, StatusCode};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::Runtime;
+
+// The number of requests the stub server has received on a connection,
+// shared between the connection's StubService, which counts them, and the
+// client, which gets a clone in the extensions of every response on it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionRequests(Arc<AtomicUsize>);
+
+impl ConnectionRequests {
+    // Counts a new request, returning how many came before it.
+    pub(crate) fn record(&self) -> usize {
+        self.0.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+thread_local! {
+    // Kept for the life of the thread, so that connections in a client's
+    // pool stay alive from one call to probe to the next.
+    static RUNTIME: RefCell<Runtime> = RefCell::new(Runtime::new().unwrap());
+}
+
+/// What happened when a request was sent with [`probe`].
+///
+/// [`probe`]: fn.probe.html
+#[derive(Clone, Debug)]
+pub struct Probe {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    elapsed: Duration,
+    connection_reused: bool,
+}
+
+impl Probe {
+    /// Returns the response's status.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the response's headers.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Returns the response's whole body.
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Returns how long it took from sending the request to having read the
+    /// whole response body.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns whether the request was sent on a connection the stub server
+    /// had already received another request on, rather than a new one.
+    ///
+    /// This is only known for clients made by this crate that go through the
+    /// stub server. For any other client, including ones made with
+    /// [`proxy_client_raw`], it's always false. On an HTTP/2 connection, a
+    /// request made at the same time as another one on the same connection
+    /// may also count as reused, even if it was the first to be sent.
+    ///
+    /// [`proxy_client_raw`]: fn.proxy_client_raw.html
+    pub fn connection_reused(&self) -> bool {
+        self.connection_reused
+    }
+}
+
+/// Sends `request` with `client`, waits for the whole response, and returns
+/// a [`Probe`] describing it, for poking at a stub while debugging.
+///
+/// This blocks the current thread until the response has been read. It runs
+/// the request on a runtime of its own, which is kept for the rest of the
+/// thread's life, so that connections `client` keeps alive can be reused by
+/// the next call, but it can't be used from inside another runtime, or with
+/// a client whose connections were opened on a different runtime. It's meant
+/// for tests and interactive debugging, not for code under test.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::{Request, Response};
+/// use hyper_stub::{probe, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(|_| Response::new("hello".into()));
+/// let request = Request::get("http://example.com").body(Default::default()).unwrap();
+///
+/// let probe = probe(&client, request).unwrap();
+/// assert_eq!(&probe.body()[..], b"hello");
+/// assert!(!probe.connection_reused());
+/// ```
+///
+/// [`Probe`]: struct.Probe.html
+pub fn probe<C>(client: &Client<C>, request: Request<Body>) -> Result<Probe, ::hyper::Error>
+where
+    C: Connect + 'static,
+{
+    let start = Instant::now();
+    let future = client.request(request).and_then(|res| {
+        let (parts, body) = res.into_parts();
+        body.concat2().map(move |body| (parts, body))
+    });
+    let (parts, body) = RUNTIME.with(|runtime| runtime.borrow_mut().block_on(future))?;
+
+    let connection_reused = parts
+        .extensions
+        .get::<ConnectionRequests>()
+        .is_some_and(|requests| requests.get() > 1);
+
+    Ok(Probe {
+        status: parts.status,
+        headers: parts.headers,
+        body: body.into_bytes(),
+        elapsed: start.elapsed(),
+        connection_reused,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Response;
+    use std::thread;
+
+    #[test]
+    fn test_probe() {
+        let client = ::proxy_client_fn_ok(|req| {
+            thread::sleep(Duration::from_millis(10));
+            let mut response = Response::new(req.uri().path().to_string().into());
+            *response.status_mut() = StatusCode::ACCEPTED;
+            response
+                .headers_mut()
+                .insert("x-stub", "yes".parse().unwrap());
+            response
+        });
+        let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let first = probe(&client, get("http://example.com/first")).unwrap();
+        assert_eq!(first.status(), StatusCode::ACCEPTED);
+        assert_eq!(first.headers()["x-stub"], "yes");
+        assert_eq!(&first.body()[..], b"/first");
+        assert!(first.elapsed() >= Duration::from_millis(10));
+        assert!(!first.connection_reused());
+
+        let second = probe(&client, get("http://example.com/second")).unwrap();
+        assert_eq!(&second.body()[..], b"/second");
+        assert!(second.connection_reused());
+    }
+}
@@ -0,0 +1,338 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use futures::Poll;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A log of the exact bytes of every request head (the request line and
+/// headers, up to and including the blank line before the body) received by a
+/// stub, captured before hyper parses them.
+///
+/// This is for tests that care about the wire format a client produces, like
+/// header order, casing, and whitespace. Bear in mind that hyper's client
+/// normalizes much of this: header names are written in lowercase (unless
+/// `http1_title_case_headers` is set on the client), headers with the same name
+/// are grouped together, and `Host`, `Content-Length` and `Transfer-Encoding`
+/// may be added or rewritten. So not everything in the raw head is under the
+/// control of the code using the client.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{RawRequestLog, StubBuilder};
+///
+/// let log = RawRequestLog::new();
+/// let client = StubBuilder::new()
+///     .raw_request_log(&log)
+///     .proxy_client_fn_ok(|_| Default::default());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RawRequestLog {
+    heads: Arc<Mutex<Vec<Bytes>>>,
+}
+
+impl RawRequestLog {
+    /// Creates an empty log.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the raw heads received so far, in the order they were received.
+    pub fn heads(&self) -> Vec<Bytes> {
+        self.heads.lock().unwrap().clone()
+    }
+
+    /// Returns the request target of each raw head received so far, in the
+    /// order they were received, exactly as the client wrote it in the
+    /// request line.
+    ///
+    /// This is for checking which form of request target a client used:
+    /// absolute-form (`http://example.com/path`), as sent to a proxy, or
+    /// origin-form (`/path`), as sent straight to a server. Clients made by
+    /// this crate always use absolute-form, because the stub's connector
+    /// tells hyper's client that it's connected to a proxy. Origin-form
+    /// requests can only be seen on a connection from
+    /// [`StubBuilder::raw_connection_fn`], where the request is written by
+    /// hand.
+    ///
+    /// Handlers only see the target after hyper's server has parsed it into
+    /// a [`Uri`]. The form can mostly be told from that, since only an
+    /// absolute-form target has an authority, but some detail is lost: for
+    /// example, [`Uri::path`] is `/` for both `http://example.com` and
+    /// `http://example.com/`. The strings returned here are unchanged, apart
+    /// from being decoded from UTF-8 lossily.
+    ///
+    /// [`StubBuilder::raw_connection_fn`]: struct.StubBuilder.html#method.raw_connection_fn
+    /// [`Uri`]: https://docs.rs/http/0.1/http/uri/struct.Uri.html
+    /// [`Uri::path`]: https://docs.rs/http/0.1/http/uri/struct.Uri.html#method.path
+    pub fn request_targets(&self) -> Vec<String> {
+        self.heads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|head| {
+                let head = String::from_utf8_lossy(head);
+                let line = head.split("\r\n").next().unwrap_or("");
+                line.split(' ').nth(1).unwrap_or("").to_string()
+            })
+            .collect()
+    }
+
+    /// Returns the number of request heads received so far.
+    pub fn len(&self) -> usize {
+        self.heads.lock().unwrap().len()
+    }
+
+    /// Returns whether no request heads have been received yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn push(&self, head: Bytes) {
+        self.heads.lock().unwrap().push(head);
+    }
+}
+
+// Where the reader is in the stream of requests on a connection. Only the
+// bytes of heads are kept -- the rest of the state machine exists to work out
+// where each body ends, so the next head can be found.
+#[derive(Debug)]
+enum State {
+    Head,
+    Body(u64),
+    ChunkSize,
+    ChunkData(u64),
+    Trailers,
+}
+
+// Wraps the server's end of a connection, copying the heads of all the
+// requests read from it into a RawRequestLog.
+pub struct RawRecordingIo<T> {
+    io: T,
+    log: Option<RawRequestLog>,
+    state: State,
+    buf: Vec<u8>,
+}
+
+impl<T> RawRecordingIo<T> {
+    pub fn new(io: T, log: Option<RawRequestLog>) -> Self {
+        RawRecordingIo {
+            io,
+            log,
+            state: State::Head,
+            buf: Vec::new(),
+        }
+    }
+
+    fn consume(&mut self, byte: u8) {
+        self.state = match mem::replace(&mut self.state, State::Head) {
+            State::Head => {
+                // Empty lines before a request line are ignored by hyper.
+                if self.buf.is_empty() && (byte == b'\r' || byte == b'\n') {
+                    State::Head
+                } else {
+                    self.buf.push(byte);
+                    if self.buf.ends_with(b"\r\n\r\n") {
+                        let head = mem::take(&mut self.buf);
+                        let state = body_state(&head);
+                        if let Some(ref log) = self.log {
+                            log.push(head.into());
+                        }
+                        state
+                    } else {
+                        State::Head
+                    }
+                }
+            }
+
+            State::Body(remaining) => match remaining - 1 {
+                0 => State::Head,
+                remaining => State::Body(remaining),
+            },
+
+            State::ChunkSize => {
+                self.buf.push(byte);
+                if self.buf.ends_with(b"\r\n") {
+                    let size = parse_chunk_size(&self.buf);
+                    self.buf.clear();
+                    match size {
+                        0 => State::Trailers,
+                        // Skip the CRLF after the chunk data too.
+                        size => State::ChunkData(size.saturating_add(2)),
+                    }
+                } else {
+                    State::ChunkSize
+                }
+            }
+
+            State::ChunkData(remaining) => match remaining - 1 {
+                0 => State::ChunkSize,
+                remaining => State::ChunkData(remaining),
+            },
+
+            State::Trailers => {
+                self.buf.push(byte);
+                if self.buf == b"\r\n" {
+                    self.buf.clear();
+                    State::Head
+                } else {
+                    if self.buf.ends_with(b"\r\n") {
+                        self.buf.clear();
+                    }
+                    State::Trailers
+                }
+            }
+        }
+    }
+}
+
+fn body_state(head: &[u8]) -> State {
+    let head = String::from_utf8_lossy(head);
+    let mut content_length = 0;
+
+    for line in head.split("\r\n").skip(1) {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+
+        if name.eq_ignore_ascii_case("transfer-encoding")
+            && value.to_ascii_lowercase().ends_with("chunked")
+        {
+            return State::ChunkSize;
+        }
+
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().unwrap_or(0);
+        }
+    }
+
+    match content_length {
+        0 => State::Head,
+        length => State::Body(length),
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> u64 {
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(&[';', '\r'][..]).next().unwrap_or("");
+    u64::from_str_radix(size.trim(), 16).unwrap_or(0)
+}
+
+impl<T: Read> Read for RawRecordingIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.io.read(buf)?;
+        if self.log.is_some() {
+            for byte in &buf[..len] {
+                self.consume(*byte);
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for RawRecordingIo<T> {}
+
+impl<T: Write> Write for RawRecordingIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for RawRecordingIo<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use hyper::{Body, Request, Response};
+    use tokio::runtime::current_thread::Runtime;
+    use StubBuilder;
+
+    #[test]
+    fn test_raw_heads() {
+        let log = RawRequestLog::new();
+        let client = StubBuilder::new()
+            .raw_request_log(&log)
+            .proxy_client_fn_ok(|req| Response::new(req.into_body()));
+
+        let post = Request::post("http://example.com/upload")
+            .header("X-Custom", "some value")
+            .body(Body::from("a body that isn't a head"))
+            .unwrap();
+        let chunked = Request::put("http://example.com/stream")
+            .body(Body::wrap_stream(futures::stream::iter_ok::<_, io::Error>(
+                vec!["one", "two"],
+            )))
+            .unwrap();
+
+        Runtime::new()
+            .unwrap()
+            .block_on({
+                client
+                    .request(post)
+                    .and_then(|res| res.into_body().concat2())
+                    .and_then(move |_| client.request(chunked))
+                    .and_then(|res| res.into_body().concat2())
+                    .map_err(|err| panic!("{:?}", err))
+            })
+            .unwrap();
+
+        let heads = log.heads();
+        assert_eq!(heads.len(), 2);
+
+        let post = String::from_utf8(heads[0].to_vec()).unwrap();
+        assert!(post.starts_with("POST http://example.com/upload HTTP/1.1\r\n"));
+        assert!(post.contains("\r\nx-custom: some value\r\n"));
+        assert!(post.ends_with("\r\n\r\n"));
+
+        let put = String::from_utf8(heads[1].to_vec()).unwrap();
+        assert!(put.starts_with("PUT http://example.com/stream HTTP/1.1\r\n"));
+        assert!(put.contains("\r\ntransfer-encoding: chunked\r\n"));
+    }
+
+    #[test]
+    fn test_request_targets() {
+        let log = RawRequestLog::new();
+        let mut builder = StubBuilder::new();
+        builder.raw_request_log(&log);
+
+        let client = builder.proxy_client_fn_ok(|req| {
+            assert_eq!(req.uri().authority_part().unwrap(), "example.com");
+            Response::new(Body::empty())
+        });
+        let future = client.get("http://example.com/proxied?q=1".parse().unwrap());
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(future).unwrap();
+
+        let future = builder
+            .raw_connection_fn(|req| {
+                assert!(req.uri().authority_part().is_none());
+                futures::future::ok::<_, ::hyper::Error>(Response::new(Body::empty()))
+            })
+            .and_then(|io| {
+                let request =
+                    "GET /direct HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+                ::tokio::io::write_all(io, request)
+            })
+            .and_then(|(io, _)| ::tokio::io::read_to_end(io, Vec::new()));
+        runtime.block_on(future).unwrap();
+
+        assert_eq!(
+            log.request_targets(),
+            ["http://example.com/proxied?q=1", "/direct"]
+        );
+    }
+}
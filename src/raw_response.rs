@@ -0,0 +1,323 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::future::{self, FutureResult};
+use futures::prelude::*;
+use h2::server;
+use h2::Reason;
+use http;
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::StatusCode;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use tokio;
+use tokio::io::AsyncRead;
+use transport::Transport;
+
+// A connector whose connections are answered by writing bytes straight to
+// the socket, without going through hyper's server. See
+// StubBuilder::proxy_client_raw.
+pub(crate) struct RawConnector<F, T> {
+    handler: Arc<F>,
+    transport: Arc<T>,
+}
+
+impl<F, T> RawConnector<F, T> {
+    pub(crate) fn new(handler: F, transport: Arc<T>) -> Self {
+        RawConnector {
+            handler: Arc::new(handler),
+            transport,
+        }
+    }
+}
+
+impl<F, T> Connect for RawConnector<F, T>
+where
+    F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    T: Transport,
+{
+    type Transport = T::Client;
+    type Error = io::Error;
+    type Future = FutureResult<(T::Client, Connected), io::Error>;
+
+    fn connect(&self, _: Destination) -> Self::Future {
+        let (client_io, server_io) = self.transport.connect();
+        let handler = self.handler.clone();
+
+        tokio::spawn(
+            ReadHead::new(server_io)
+                .and_then(move |(io, head)| tokio::io::write_all(io, handler(&head)))
+                .and_then(|(io, _)| tokio::io::shutdown(io))
+                .then(|_| Ok(())),
+        );
+
+        future::ok((client_io, Connected::new().proxy(true)))
+    }
+}
+
+// A connector whose connections are closed by the server as soon as they're
+// opened. See StubBuilder::accept_then_close.
+pub(crate) struct ClosingConnector<T> {
+    transport: Arc<T>,
+}
+
+impl<T> ClosingConnector<T> {
+    pub(crate) fn new(transport: Arc<T>) -> Self {
+        ClosingConnector { transport }
+    }
+}
+
+impl<T: Transport> Connect for ClosingConnector<T> {
+    type Transport = T::Client;
+    type Error = io::Error;
+    type Future = FutureResult<(T::Client, Connected), io::Error>;
+
+    fn connect(&self, _: Destination) -> Self::Future {
+        let (client_io, server_io) = self.transport.connect();
+        drop(server_io);
+        future::ok((client_io, Connected::new().proxy(true)))
+    }
+}
+
+// A connector whose connections are served over HTTP/2 by h2's server, which
+// resets every stream after sending a response head. See
+// StubBuilder::reset_after_headers.
+pub(crate) struct ResettingConnector<T> {
+    reason: Reason,
+    transport: Arc<T>,
+}
+
+impl<T> ResettingConnector<T> {
+    pub(crate) fn new(error_code: u32, transport: Arc<T>) -> Self {
+        ResettingConnector {
+            reason: Reason::from(error_code),
+            transport,
+        }
+    }
+}
+
+impl<T: Transport> Connect for ResettingConnector<T> {
+    type Transport = T::Client;
+    type Error = io::Error;
+    type Future = FutureResult<(T::Client, Connected), io::Error>;
+
+    fn connect(&self, _: Destination) -> Self::Future {
+        let (client_io, server_io) = self.transport.connect();
+        let reason = self.reason;
+
+        tokio::spawn(
+            server::handshake(server_io)
+                .and_then(move |connection| {
+                    connection.for_each(move |(_, mut respond)| {
+                        let mut stream = respond.send_response(http::Response::new(()), false)?;
+
+                        // Resetting the stream straight away would discard
+                        // the head before it was written. h2 only gives a
+                        // stream capacity to send data once its head has
+                        // been taken off the queue to be written, so reset
+                        // it then, from a task of its own, since this one
+                        // has to carry on driving the connection.
+                        stream.reserve_capacity(1);
+                        tokio::spawn(future::poll_fn(move || {
+                            let _ = try_ready!(stream.poll_capacity().map_err(drop));
+                            stream.send_reset(reason);
+                            Ok(Async::Ready(()))
+                        }));
+                        Ok(())
+                    })
+                })
+                .then(|_| Ok(())),
+        );
+
+        let connected = Connected::new().proxy(true).negotiated_h2();
+        future::ok((client_io, connected))
+    }
+}
+
+/// Creates a raw `200 OK` response, for [`proxy_client_raw`], that declares
+/// a `Content-Length` of `body`'s length, but is followed by `extra` bytes
+/// after the body.
+///
+/// This is for testing that a client stops reading a body where its
+/// `Content-Length` says it ends. hyper's client does: the response's body
+/// yields exactly the bytes of `body`, without an error, and `extra` is never
+/// part of any response. Had the connection been kept alive, hyper would
+/// treat the leftover bytes as unexpected data on an idle connection, and
+/// close it rather than reuse it. A [`proxy_client_raw`] stub closes every
+/// connection after responding anyway, so each request gets a new one.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{overlong_response, proxy_client_raw};
+///
+/// let client = proxy_client_raw(|_| overlong_response(b"hello", b"garbage"));
+/// ```
+///
+/// [`proxy_client_raw`]: fn.proxy_client_raw.html
+pub fn overlong_response(body: &[u8], extra: &[u8]) -> Vec<u8> {
+    let mut response =
+        format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", body.len()).into_bytes();
+    response.extend_from_slice(body);
+    response.extend_from_slice(extra);
+    response
+}
+
+/// Creates a raw response, for [`proxy_client_raw`], with the given status,
+/// but an empty reason phrase, like `HTTP/1.1 200 \r\n`, and `body`.
+///
+/// This is for testing that a client copes with a status line without a
+/// reason phrase, which some servers send. It's only possible with
+/// [`proxy_client_raw`], because hyper's server always writes one: the
+/// status's canonical reason, or `<none>` for a status that doesn't have
+/// one. hyper's client accepts an empty reason phrase, as well as a status
+/// line that ends straight after the code, without the space before the
+/// reason.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::StatusCode;
+/// use hyper_stub::{empty_reason_response, proxy_client_raw};
+///
+/// let client = proxy_client_raw(|_| empty_reason_response(StatusCode::OK, b"hello"));
+/// ```
+///
+/// [`proxy_client_raw`]: fn.proxy_client_raw.html
+pub fn empty_reason_response(status: StatusCode, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {} \r\ncontent-length: {}\r\n\r\n",
+        status.as_u16(),
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+// Reads from a socket up to the end of the first request head, or until the
+// client stops writing.
+struct ReadHead<S> {
+    io: Option<S>,
+    head: Vec<u8>,
+}
+
+impl<S> ReadHead<S> {
+    fn new(io: S) -> Self {
+        ReadHead {
+            io: Some(io),
+            head: Vec::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead> Future for ReadHead<S> {
+    type Item = (S, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(S, Vec<u8>), io::Error> {
+        let mut buf = [0; 1024];
+        loop {
+            let io = self.io.as_mut().expect("ReadHead polled after completion");
+            let len = try_ready!(io.poll_read(&mut buf));
+            self.head.extend_from_slice(&buf[..len]);
+
+            if let Some(end) = self.head.windows(4).position(|w| w == b"\r\n\r\n") {
+                self.head.truncate(end + 4);
+            } else if len > 0 {
+                continue;
+            }
+
+            let io = self.io.take().unwrap();
+            return Ok(Async::Ready((io, mem::take(&mut self.head))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use futures::prelude::*;
+    use hyper::{Body, Response, StatusCode};
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn test_unusual_status() {
+        let client = ::proxy_client_raw(|head| {
+            assert!(head.starts_with(b"GET http://example.com/path HTTP/1.1\r\n"));
+            assert!(head.ends_with(b"\r\n\r\n"));
+            b"HTTP/1.1 299 Totally Fine\r\ncontent-length: 2\r\n\r\nok".to_vec()
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..2 {
+            let future = client
+                .get("http://example.com/path".parse().unwrap())
+                .and_then(|res| {
+                    let status = res.status();
+                    res.into_body().concat2().map(move |body| (status, body))
+                });
+            let (status, body) = runtime.block_on(future).unwrap();
+            assert_eq!(status.as_u16(), 299);
+            assert_eq!(&body[..], b"ok");
+        }
+    }
+
+    #[test]
+    fn test_overlong_response() {
+        let client = ::proxy_client_raw(|_| super::overlong_response(b"hello", b"EXTRA BYTES"));
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..2 {
+            let future = client
+                .get("http://example.com/".parse().unwrap())
+                .and_then(|res| res.into_body().concat2());
+            assert_eq!(&runtime.block_on(future).unwrap()[..], b"hello");
+        }
+    }
+
+    #[test]
+    fn test_empty_reason() {
+        let client = ::proxy_client_raw(|head| {
+            if head.starts_with(b"GET http://example.com/no-space ") {
+                b"HTTP/1.1 204\r\n\r\n".to_vec()
+            } else {
+                super::empty_reason_response(StatusCode::OK, b"hello")
+            }
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let future = client
+            .get("http://example.com/".parse().unwrap())
+            .and_then(|res| {
+                assert_eq!(res.status(), StatusCode::OK);
+                res.into_body().concat2()
+            });
+        assert_eq!(&runtime.block_on(future).unwrap()[..], b"hello");
+
+        let future = client.get("http://example.com/no-space".parse().unwrap());
+        let response = runtime.block_on(future).unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn test_hyper_server_reason() {
+        // hyper's client doesn't expose the reason phrase, so read it from
+        // the raw response.
+        let future = ::StubBuilder::new()
+            .raw_connection_fn(|_| {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::from_u16(299).unwrap();
+                future::ok::<_, ::hyper::Error>(response)
+            })
+            .and_then(|io| {
+                ::tokio::io::write_all(io, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            })
+            .and_then(|(io, _)| ::tokio::io::read_to_end(io, Vec::new()));
+        let (_, response) = Runtime::new().unwrap().block_on(future).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 299 <none>\r\n"));
+    }
+}
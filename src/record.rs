@@ -0,0 +1,1255 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use http::request::Parts;
+use hyper::header::{
+    AsHeaderName, HeaderName, HeaderValue, CONTENT_LENGTH, COOKIE, HOST, TRANSFER_ENCODING,
+};
+use hyper::{HeaderMap, Method, Uri, Version};
+#[cfg(feature = "serde")]
+use serde_json;
+use snapshot::RequestSnapshot;
+use std::collections::HashMap;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A log of every request received by a stub.
+///
+/// Request bodies are buffered in full before the stub's handler is called, so
+/// the handler still sees the whole body, but it arrives all at once.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use futures::Future;
+/// use hyper::Response;
+/// use hyper_stub::{RequestLog, StubBuilder};
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let log = RequestLog::new();
+/// let client = StubBuilder::new()
+///     .request_log(&log)
+///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+///
+/// let future = client.get("http://example.com/path".parse().unwrap());
+/// Runtime::new().unwrap().block_on(future).unwrap();
+///
+/// assert_eq!(log.get(0).unwrap().uri().path(), "/path");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RequestLog {
+    entries: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl RequestLog {
+    /// Creates an empty log.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the requests received so far, in the order they were received.
+    pub fn entries(&self) -> Vec<RecordedRequest> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Returns the request at `index`, if there is one.
+    pub fn get(&self, index: usize) -> Option<RecordedRequest> {
+        self.entries.lock().unwrap().get(index).cloned()
+    }
+
+    /// Returns the number of requests received so far.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Returns whether no requests have been received yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Asserts that the request at `index` had an empty body. See
+    /// [`RecordedRequest::body_is_empty`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the body wasn't empty, or if there is no request at `index`.
+    ///
+    /// [`RecordedRequest::body_is_empty`]: struct.RecordedRequest.html#method.body_is_empty
+    pub fn assert_empty_body(&self, index: usize) {
+        let entry = self.expect(index);
+        assert!(
+            entry.body_is_empty(),
+            "expected request {} ({} {}) to have an empty body, but it had {} bytes: {:?}",
+            index,
+            entry.method(),
+            entry.uri(),
+            entry.body().len(),
+            String::from_utf8_lossy(entry.body())
+        );
+    }
+
+    /// Asserts that the body of the request at `index` was exactly
+    /// `expected`.
+    ///
+    /// If the bodies aren't equal, the panic message shows how they differ.
+    /// When both are valid UTF-8, they're compared as text, line by line, with
+    /// each differing line of the expected body prefixed with `-`, and of the
+    /// actual body with `+`. Otherwise, they're compared as bytes, and the
+    /// message gives the offset of the first byte that differs, with a hex
+    /// dump of both bodies around it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the body doesn't match, or if there is no request at `index`.
+    pub fn assert_body_eq<B: AsRef<[u8]>>(&self, index: usize, expected: B) {
+        let entry = self.expect(index);
+        if let Some(diff) = body_diff(expected.as_ref(), entry.body()) {
+            panic!(
+                "request {} ({} {}) didn't have the expected body:\n{}",
+                index,
+                entry.method(),
+                entry.uri(),
+                diff
+            );
+        }
+    }
+
+    /// Asserts that the recorded requests had exactly the methods in
+    /// `expected`, in order.
+    ///
+    /// This fails if the number of requests differs from the number of
+    /// methods, as well as if any method differs, so it also checks that the
+    /// client made no unexpected extra requests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the methods don't match, with a message showing both the
+    /// expected and the actual sequence of methods.
+    pub fn assert_methods(&self, expected: &[Method]) {
+        let actual: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.method.clone())
+            .collect();
+        assert!(
+            actual == expected,
+            "expected requests with methods {:?}, but got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    /// Asserts that at least one recorded request had the given method and
+    /// path.
+    ///
+    /// The path is compared exactly with the request URI's path, which
+    /// doesn't include the query string, so `"/foo"` matches a request for
+    /// `/foo?page=2`, but not one for `/foo/`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no request matches, with a message listing the method and
+    /// path of every recorded request.
+    pub fn assert_request(&self, method: Method, path: &str) {
+        let requests: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| (entry.method.clone(), entry.uri.path().to_string()))
+            .collect();
+        let found = requests.iter().any(|(m, p)| *m == method && p == path);
+        let requests: Vec<_> = requests
+            .iter()
+            .map(|(method, path)| format!("{} {}", method, path))
+            .collect();
+        assert!(
+            found,
+            "expected a {} {} request, but got {:?}",
+            method, path, requests
+        );
+    }
+
+    /// Asserts that the request at `index` had exactly the values in
+    /// `expected` for the header `name`, in order. See
+    /// [`RecordedRequest::header_all`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the values don't match, with a message showing both the
+    /// expected and the actual values, or if there is no request at `index`.
+    ///
+    /// [`RecordedRequest::header_all`]: struct.RecordedRequest.html#method.header_all
+    pub fn assert_header_values(&self, index: usize, name: &str, expected: &[&str]) {
+        let entry = self.expect(index);
+        let actual = entry.header_all(name);
+        assert!(
+            actual == expected,
+            "expected request {} ({} {}) to have {} values {:?}, but got {:?}",
+            index,
+            entry.method(),
+            entry.uri(),
+            name,
+            expected,
+            actual
+        );
+    }
+
+    /// Asserts that the request at `index` had the cookie `name` with the
+    /// value `expected`. See [`RecordedRequest::cookie`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the request didn't have the cookie, or had it with a
+    /// different value, with a message showing the request's `Cookie`
+    /// headers, or if there is no request at `index`.
+    ///
+    /// [`RecordedRequest::cookie`]: struct.RecordedRequest.html#method.cookie
+    pub fn assert_cookie(&self, index: usize, name: &str, expected: &str) {
+        let entry = self.expect(index);
+        let actual = entry.cookie(name);
+        assert!(
+            actual.as_deref() == Some(expected),
+            "expected request {} ({} {}) to have cookie {}={:?}, but got {:?} \
+             from Cookie headers {:?}",
+            index,
+            entry.method(),
+            entry.uri(),
+            name,
+            expected,
+            actual,
+            entry.header_all(COOKIE)
+        );
+    }
+
+    /// Asserts that the body of every request received so far is valid
+    /// UTF-8, for checking that a client only ever sends text.
+    ///
+    /// Empty bodies count as valid.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any body isn't valid UTF-8, with a message giving the index
+    /// of the first request whose body isn't, and the start of that body.
+    pub fn assert_all_bodies_utf8(&self) {
+        for (index, entry) in self.entries().iter().enumerate() {
+            if let Err(error) = str::from_utf8(entry.body()) {
+                panic!(
+                    "expected every request body to be UTF-8, but request {} ({} {}) had {}: {}",
+                    index,
+                    entry.method(),
+                    entry.uri(),
+                    error,
+                    snippet(entry.body())
+                );
+            }
+        }
+    }
+
+    /// Asserts that the body of every request received so far is valid JSON,
+    /// for checking that a client only ever sends JSON.
+    ///
+    /// Empty bodies count as valid, even though they aren't JSON, so that
+    /// requests without a body, like `GET` requests, don't have to be left
+    /// out of the log.
+    ///
+    /// Only available with the `serde` feature.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any non-empty body isn't valid JSON, with a message giving
+    /// the index of the first request whose body isn't, the parse error, and
+    /// the start of that body.
+    #[cfg(feature = "serde")]
+    pub fn assert_all_bodies_json(&self) {
+        for (index, entry) in self.entries().iter().enumerate() {
+            if entry.body().is_empty() {
+                continue;
+            }
+            if let Err(error) = serde_json::from_slice::<serde_json::Value>(entry.body()) {
+                panic!(
+                    "expected every request body to be JSON, but request {} ({} {}) had {}: {}",
+                    index,
+                    entry.method(),
+                    entry.uri(),
+                    error,
+                    snippet(entry.body())
+                );
+            }
+        }
+    }
+
+    /// Returns a [`RequestSnapshot`] of each request received so far, for
+    /// comparing against a stored snapshot.
+    ///
+    /// Requests are in the order they were received, which is only
+    /// deterministic if the test sends them one at a time. Concurrent requests
+    /// can arrive in any order, so a test making them should sort the
+    /// snapshots before comparing them.
+    ///
+    /// [`RequestSnapshot`]: struct.RequestSnapshot.html
+    pub fn snapshot(&self) -> Vec<RequestSnapshot> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().map(RequestSnapshot::new).collect()
+    }
+
+    pub(crate) fn expect(&self, index: usize) -> RecordedRequest {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(index) {
+            Some(entry) => entry.clone(),
+            None => panic!(
+                "no request at index {}: only {} requests were recorded",
+                index,
+                entries.len()
+            ),
+        }
+    }
+
+    // Returns the index of the new entry, so that it can be updated once the
+    // response is known.
+    pub(crate) fn push(&self, entry: RecordedRequest) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(entry);
+        entries.len() - 1
+    }
+
+    pub(crate) fn set_stub_index(&self, index: usize, stub_index: usize) {
+        self.entries.lock().unwrap()[index].stub_index = Some(stub_index);
+    }
+}
+
+/// A request received by a stub, recorded in a [`RequestLog`].
+///
+/// [`RequestLog`]: struct.RequestLog.html
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+    stub_index: Option<usize>,
+    received_at: Duration,
+    received: Instant,
+}
+
+impl RecordedRequest {
+    pub(crate) fn new(parts: &Parts, body: Bytes, created: Instant, received: Instant) -> Self {
+        RecordedRequest {
+            method: parts.method.clone(),
+            uri: parts.uri.clone(),
+            version: parts.version,
+            headers: parts.headers.clone(),
+            body,
+            stub_index: None,
+            received_at: received.duration_since(created),
+            received,
+        }
+    }
+
+    /// Returns the request's method.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Returns the request's URI, as the stub server received it.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Returns the request's HTTP version.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the request's headers, exactly as the stub server received them.
+    ///
+    /// This includes headers added by hyper's client as well as those set on
+    /// the request explicitly. Over a stub's connection, hyper adds:
+    ///
+    /// - `Host`, taken from the request's URI, unless the request already has
+    ///   one.
+    /// - `Content-Length` for bodies with a known length, or
+    ///   `Transfer-Encoding: chunked` for streamed bodies. Neither is added to
+    ///   requests without a body, like a plain `GET`.
+    ///
+    /// hyper doesn't add a `User-Agent` header, so one will only be present
+    /// if the client under test sets it.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Returns every value of the header `name`, in the order the stub server
+    /// received them.
+    ///
+    /// hyper keeps repeated headers in order at each step: its client writes
+    /// each value as a separate header line, in the order they were appended
+    /// to the request, and its server appends the lines it parses in the order
+    /// they arrive. Values that the client combined into one line, like
+    /// `X-Forwarded-For: a, b`, stay as one value here.
+    pub fn header_all<K: AsHeaderName>(&self, name: K) -> Vec<&HeaderValue> {
+        self.headers.get_all(name).iter().collect()
+    }
+
+    /// Asserts that the request had exactly the headers in `expected`, with
+    /// the same values in the same order, and no others apart from those
+    /// named in `ignore`, for catching a client that sends headers it
+    /// shouldn't.
+    ///
+    /// The headers hyper's client adds by itself, listed under [`headers`],
+    /// are ignored unless they're in `expected`, so they don't have to be
+    /// listed in `ignore`: `Host`, `Content-Length` and `Transfer-Encoding`.
+    /// A header in `ignore` that's also in `expected` is still checked.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any header in `expected` is missing or has different values,
+    /// or if the request had any header that wasn't expected or ignored, with
+    /// a message listing each of them with its values.
+    ///
+    /// [`headers`]: #method.headers
+    pub fn assert_headers_exactly(&self, expected: &HeaderMap, ignore: &[HeaderName]) {
+        const ADDED_BY_HYPER: &[HeaderName] = &[HOST, CONTENT_LENGTH, TRANSFER_ENCODING];
+
+        let mut problems = Vec::new();
+        for name in expected.keys() {
+            let expected: Vec<_> = expected.get_all(name).iter().collect();
+            let actual = self.header_all(name);
+            if actual != expected {
+                problems.push(format!("{} was {:?}, not {:?}", name, actual, expected));
+            }
+        }
+        for name in self.headers.keys() {
+            if !expected.contains_key(name)
+                && !ignore.contains(name)
+                && !ADDED_BY_HYPER.contains(name)
+            {
+                problems.push(format!("unexpected {} {:?}", name, self.header_all(name)));
+            }
+        }
+
+        assert!(
+            problems.is_empty(),
+            "expected {} {} to have exactly the expected headers, but {}",
+            self.method,
+            self.uri,
+            problems.join(", ")
+        );
+    }
+
+    /// Returns the path of the request's URI exactly as the stub server
+    /// received it, with its percent-encoding intact, for checking how a
+    /// client encoded it.
+    ///
+    /// Neither hyper's client nor its server normalizes paths:
+    /// percent-encoded bytes are left encoded, with the case of their hex
+    /// digits as sent, so `%2f` and `%2F` stay different, and `.` and `..`
+    /// segments aren't removed. The only change is that an empty path, as
+    /// in `http://example.com`, is `/`. hyper does refuse to parse a URI
+    /// containing characters that aren't allowed in one, like a space or any
+    /// non-ASCII character, so a client has to percent-encode those itself
+    /// before it can send the request at all.
+    pub fn raw_path(&self) -> &str {
+        self.uri.path()
+    }
+
+    /// Returns the parameters in the request's query string, percent-decoded.
+    ///
+    /// The query is parsed as `application/x-www-form-urlencoded`, so `+` is
+    /// decoded as a space. A parameter without a `=`, like `flag` in
+    /// `?flag&a=1`, has an empty value. If a key appears more than once, the
+    /// last value wins. Invalid percent-encoding is left as it is, and
+    /// sequences that don't decode to valid UTF-8 are replaced with U+FFFD.
+    pub fn query_params(&self) -> HashMap<String, String> {
+        self.uri
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                let key = parts.next().unwrap_or("");
+                let value = parts.next().unwrap_or("");
+                (percent_decode(key), percent_decode(value))
+            })
+            .collect()
+    }
+
+    /// Returns the value of the cookie `name` sent with the request, if there
+    /// was one.
+    ///
+    /// Cookies are read from every `Cookie` header, each of which holds any
+    /// number of `name=value` pairs separated by `;`. If the same name was
+    /// sent more than once, which a client can do for cookies with different
+    /// paths, the first one wins, since that's usually the one with the most
+    /// specific path. Values are returned as sent, without percent-decoding or
+    /// removing quotes, because cookies don't have a standard encoding, so
+    /// it's up to the code that set them what the value means. Names are
+    /// case-sensitive.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|&(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+    }
+
+    /// Returns the request's body.
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Returns the index of the [`Sequence`] step that handled the request.
+    ///
+    /// This is `None` for requests handled by anything other than a
+    /// sequence, and for requests a sequence had no step left for. It is also
+    /// `None` until the handler has responded.
+    ///
+    /// [`Sequence`]: struct.Sequence.html
+    pub fn stub_index(&self) -> Option<usize> {
+        self.stub_index
+    }
+
+    /// Returns how long after the stub's client was created the stub server
+    /// received the request, for checking how requests were spaced out, like
+    /// by a client that rate limits or debounces them.
+    ///
+    /// The time is taken with [`Instant`] when the stub's handler would be
+    /// called for the request, once hyper's server has read the request
+    /// head, but before any of the body. That's after the client sent it, by
+    /// however long it took the tasks involved to be scheduled, which on a
+    /// busy runtime can be a few milliseconds. So gaps between requests are
+    /// best checked with some slack, like asserting one was at least 100ms
+    /// after another, rather than exactly 100ms.
+    ///
+    /// [`Instant`]: https://doc.rust-lang.org/std/time/struct.Instant.html
+    pub fn received_at(&self) -> Duration {
+        self.received_at
+    }
+
+    /// Returns how long it took from `sent` until the stub server received
+    /// the request, for measuring how much time a client spends on a request
+    /// before it goes out.
+    ///
+    /// `sent` should be taken with [`Instant::now`] just before calling into
+    /// the client code being measured. The request is counted as received at
+    /// the same moment as for [`received_at`], and measured with the same
+    /// monotonic clock, so the result includes however long the client's and
+    /// stub server's tasks took to be scheduled as well as the time the
+    /// client itself took. If `sent` is after the request was received, the
+    /// result is zero.
+    ///
+    /// [`Instant::now`]: https://doc.rust-lang.org/std/time/struct.Instant.html#method.now
+    /// [`received_at`]: #method.received_at
+    pub fn overhead_since(&self, sent: Instant) -> Duration {
+        self.received.saturating_duration_since(sent)
+    }
+
+    /// Asserts that the stub server received the request no more than
+    /// `budget` after `sent`, as measured by [`overhead_since`], for checking
+    /// that a client stays within a latency budget.
+    ///
+    /// Task scheduling alone can add a few milliseconds, and more on a busy
+    /// machine or with tests running in parallel, so `budget` should be
+    /// generous, catching a client that sleeps or retries when it shouldn't,
+    /// rather than measuring it precisely.
+    ///
+    /// ```
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// # extern crate tokio;
+    /// #
+    /// use hyper::{Body, Response};
+    /// use hyper_stub::{RequestLog, StubBuilder};
+    /// use std::time::{Duration, Instant};
+    /// use tokio::runtime::current_thread::Runtime;
+    ///
+    /// let log = RequestLog::new();
+    /// let client = StubBuilder::new()
+    ///     .request_log(&log)
+    ///     .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+    ///
+    /// let sent = Instant::now();
+    /// let future = client.get("http://example.com".parse().unwrap());
+    /// Runtime::new().unwrap().block_on(future).unwrap();
+    ///
+    /// log.get(0).unwrap().assert_received_within(sent, Duration::from_secs(1));
+    /// ```
+    ///
+    /// [`overhead_since`]: #method.overhead_since
+    pub fn assert_received_within(&self, sent: Instant, budget: Duration) {
+        let overhead = self.overhead_since(sent);
+        assert!(
+            overhead <= budget,
+            "{} {} was received {:?} after it was sent, over the budget of {:?}",
+            self.method,
+            self.uri,
+            overhead,
+            budget
+        );
+    }
+
+    /// Returns how the client framed the request's body.
+    ///
+    /// This is worked out from the request's headers as hyper's server parsed
+    /// them, so it reflects the decision the client made when it sent the
+    /// request: hyper's client uses `Content-Length` when the body's size is
+    /// known up front, like for a `Body` made from bytes, and chunked
+    /// transfer encoding for a streamed body.
+    pub fn framing(&self) -> BodyFraming {
+        let chunked = self
+            .headers
+            .get_all(TRANSFER_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return BodyFraming::Chunked;
+        }
+
+        self.headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map_or(BodyFraming::Empty, BodyFraming::ContentLength)
+    }
+
+    /// Returns whether the request's body was empty.
+    ///
+    /// This is true both for a request sent without any body at all, and for
+    /// one with an explicitly empty body (`Content-Length: 0`). To tell the two
+    /// apart, check for a `Content-Length` header in [`headers`].
+    ///
+    /// [`headers`]: #method.headers
+    pub fn body_is_empty(&self) -> bool {
+        self.body.is_empty()
+    }
+}
+
+/// How a [`RecordedRequest`]'s body was framed. See
+/// [`RecordedRequest::framing`].
+///
+/// [`RecordedRequest`]: struct.RecordedRequest.html
+/// [`RecordedRequest::framing`]: struct.RecordedRequest.html#method.framing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyFraming {
+    /// The request had a `Content-Length` header, with this length.
+    ContentLength(u64),
+
+    /// The request's body was sent with chunked transfer encoding.
+    Chunked,
+
+    /// The request had neither, so it had no body.
+    Empty,
+}
+
+// Decodes a component of a form-urlencoded query string. See
+// RecordedRequest::query_params.
+fn percent_decode(input: &str) -> String {
+    let input = input.as_bytes();
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => output.push(b' '),
+            b'%' if i + 2 < input.len()
+                && input[i + 1].is_ascii_hexdigit()
+                && input[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = str::from_utf8(&input[i + 1..i + 3]).unwrap();
+                output.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+                continue;
+            }
+            byte => output.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+// The start of a body, for showing in a panic message, with any bytes that
+// aren't valid UTF-8 replaced.
+fn snippet(body: &[u8]) -> String {
+    const MAX_LEN: usize = 64;
+    if body.len() <= MAX_LEN {
+        return format!("{:?}", String::from_utf8_lossy(body));
+    }
+    format!(
+        "{:?}... ({} bytes)",
+        String::from_utf8_lossy(&body[..MAX_LEN]),
+        body.len()
+    )
+}
+
+// Describes how actual differs from expected, or returns None if they're the
+// same. See RequestLog::assert_body_eq.
+fn body_diff(expected: &[u8], actual: &[u8]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+
+    if let (Ok(expected), Ok(actual)) = (str::from_utf8(expected), str::from_utf8(actual)) {
+        let expected: Vec<_> = expected.split('\n').collect();
+        let actual: Vec<_> = actual.split('\n').collect();
+        let mut diff = String::new();
+        for i in 0..expected.len().max(actual.len()) {
+            match (expected.get(i), actual.get(i)) {
+                (Some(e), Some(a)) if e == a => diff.push_str(&format!("  {}\n", e)),
+                (e, a) => {
+                    if let Some(e) = e {
+                        diff.push_str(&format!("- {}\n", e));
+                    }
+                    if let Some(a) = a {
+                        diff.push_str(&format!("+ {}\n", a));
+                    }
+                }
+            }
+        }
+        return Some(diff);
+    }
+
+    let offset = expected
+        .iter()
+        .zip(actual)
+        .position(|(e, a)| e != a)
+        .unwrap_or_else(|| expected.len().min(actual.len()));
+    let row = offset - offset % 16;
+    let hex = |bytes: &[u8]| {
+        let bytes = &bytes[row.min(bytes.len())..(row + 16).min(bytes.len())];
+        let hex: Vec<_> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        hex.join(" ")
+    };
+    Some(format!(
+        "first difference at byte offset {} (expected {} bytes, got {})\n\
+         expected {:08x}: {}\n\
+         actual   {:08x}: {}\n",
+        offset,
+        expected.len(),
+        actual.len(),
+        row,
+        hex(expected),
+        row,
+        hex(actual)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use hyper::header::{CONTENT_LENGTH, HOST, USER_AGENT};
+    use hyper::{Body, Request, Response};
+    use tokio::runtime::current_thread::Runtime;
+    use StubBuilder;
+
+    fn send(log: &RequestLog, requests: Vec<Request<Body>>) {
+        let client = StubBuilder::new()
+            .request_log(log)
+            .proxy_client_fn_ok(|req| Response::new(req.into_body()));
+
+        let mut runtime = Runtime::new().unwrap();
+        for request in requests {
+            runtime
+                .block_on(
+                    client
+                        .request(request)
+                        .and_then(|res| res.into_body().concat2()),
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_records_requests() {
+        let log = RequestLog::new();
+        let request = Request::post("http://example.com/path?query")
+            .header("x-custom", "value")
+            .body("hello".into())
+            .unwrap();
+        send(&log, vec![request]);
+
+        assert_eq!(log.len(), 1);
+        let entry = log.get(0).unwrap();
+        assert_eq!(entry.method(), Method::POST);
+        assert_eq!(entry.uri().path(), "/path");
+        assert_eq!(entry.uri().query(), Some("query"));
+        assert_eq!(entry.version(), Version::HTTP_11);
+        assert_eq!(entry.headers()["x-custom"], "value");
+        assert_eq!(&entry.body()[..], b"hello");
+    }
+
+    #[test]
+    fn test_received_at() {
+        use std::thread;
+
+        let log = RequestLog::new();
+        let client = StubBuilder::new()
+            .request_log(&log)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..2 {
+            let future = client.get("http://example.com".parse().unwrap());
+            runtime.block_on(future).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let first = log.get(0).unwrap().received_at();
+        let second = log.get(1).unwrap().received_at();
+        assert!(first < Duration::from_millis(50));
+        assert!(second - first >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_assert_received_within() {
+        let log = RequestLog::new();
+        let client = StubBuilder::new()
+            .request_log(&log)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let sent = Instant::now();
+        let future = client.get("http://example.com".parse().unwrap());
+        Runtime::new().unwrap().block_on(future).unwrap();
+
+        let entry = log.get(0).unwrap();
+        assert!(entry.overhead_since(sent) < Duration::from_secs(1));
+        assert_eq!(entry.overhead_since(Instant::now()), Duration::from_secs(0));
+        entry.assert_received_within(sent, Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "GET http://example.com/slow was received ")]
+    fn test_assert_received_within_over_budget() {
+        use std::thread;
+
+        let log = RequestLog::new();
+        let client = StubBuilder::new()
+            .request_log(&log)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let sent = Instant::now();
+        thread::sleep(Duration::from_millis(50));
+        let future = client.get("http://example.com/slow".parse().unwrap());
+        Runtime::new().unwrap().block_on(future).unwrap();
+
+        let entry = log.get(0).unwrap();
+        assert!(entry.overhead_since(sent) >= Duration::from_millis(50));
+        entry.assert_received_within(sent, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_records_client_headers() {
+        let log = RequestLog::new();
+        let default = Request::get("http://example.com:8080/")
+            .body(Body::empty())
+            .unwrap();
+        let custom = Request::post("http://example.com/")
+            .header(USER_AGENT, "my-client/1.0")
+            .body("hello".into())
+            .unwrap();
+        send(&log, vec![default, custom]);
+
+        let headers = log.get(0).unwrap().headers().clone();
+        assert_eq!(headers[HOST], "example.com:8080");
+        assert!(headers.get(USER_AGENT).is_none());
+        assert_eq!(headers.len(), 1);
+
+        let headers = log.get(1).unwrap().headers().clone();
+        assert_eq!(headers[HOST], "example.com");
+        assert_eq!(headers[USER_AGENT], "my-client/1.0");
+        assert_eq!(headers[CONTENT_LENGTH], "5");
+    }
+
+    #[test]
+    fn test_assert_headers_exactly() {
+        let log = RequestLog::new();
+        let request = Request::post("http://example.com/")
+            .header(USER_AGENT, "my-client/1.0")
+            .header("x-trace", "a")
+            .header("x-trace", "b")
+            .header("x-request-id", "42")
+            .body("hello".into())
+            .unwrap();
+        send(&log, vec![request]);
+
+        let mut expected = HeaderMap::new();
+        expected.insert(USER_AGENT, "my-client/1.0".parse().unwrap());
+        expected.append("x-trace", "a".parse().unwrap());
+        expected.append("x-trace", "b".parse().unwrap());
+        let entry = log.get(0).unwrap();
+        entry.assert_headers_exactly(&expected, &[HeaderName::from_static("x-request-id")]);
+
+        expected.insert(CONTENT_LENGTH, "5".parse().unwrap());
+        expected.insert("x-request-id", "42".parse().unwrap());
+        entry.assert_headers_exactly(&expected, &[]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "expected GET http://example.com/ to have exactly the expected headers, \
+                    but x-trace was [\"a\"], not [\"b\"], unexpected x-debug [\"1\", \"2\"]"
+    )]
+    fn test_assert_headers_exactly_extra() {
+        let log = RequestLog::new();
+        let request = Request::get("http://example.com/")
+            .header("x-trace", "a")
+            .header("x-debug", "1")
+            .header("x-debug", "2")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![request]);
+
+        let mut expected = HeaderMap::new();
+        expected.insert("x-trace", "b".parse().unwrap());
+        log.get(0).unwrap().assert_headers_exactly(&expected, &[]);
+    }
+
+    #[test]
+    fn test_empty_bodies() {
+        let log = RequestLog::new();
+        let get = Request::get("http://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let post = Request::post("http://example.com")
+            .header(CONTENT_LENGTH, "0")
+            .body(Body::from(""))
+            .unwrap();
+        send(&log, vec![get, post]);
+
+        log.assert_empty_body(0);
+        assert!(log.get(0).unwrap().headers().get(CONTENT_LENGTH).is_none());
+
+        log.assert_empty_body(1);
+        assert_eq!(log.get(1).unwrap().headers()[CONTENT_LENGTH], "0");
+    }
+
+    #[test]
+    #[should_panic(expected = "to have an empty body, but it had 5 bytes")]
+    fn test_assert_empty_body_fails() {
+        let log = RequestLog::new();
+        let get = Request::get("http://example.com")
+            .body("oops!".into())
+            .unwrap();
+        send(&log, vec![get]);
+
+        log.assert_empty_body(0);
+    }
+
+    #[test]
+    fn test_assert_methods() {
+        let log = RequestLog::new();
+        let request = |method| {
+            Request::builder()
+                .method(method)
+                .uri("http://example.com/items/1")
+                .body(Body::empty())
+                .unwrap()
+        };
+        send(
+            &log,
+            vec![
+                request(Method::POST),
+                request(Method::GET),
+                request(Method::DELETE),
+            ],
+        );
+
+        log.assert_methods(&[Method::POST, Method::GET, Method::DELETE]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected requests with methods [POST, GET], but got [POST]")]
+    fn test_assert_methods_fails() {
+        let log = RequestLog::new();
+        let post = Request::post("http://example.com")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![post]);
+
+        log.assert_methods(&[Method::POST, Method::GET]);
+    }
+
+    #[test]
+    fn test_assert_request() {
+        let log = RequestLog::new();
+        let get = Request::get("http://example.com/foo?page=2")
+            .body(Body::empty())
+            .unwrap();
+        let post = Request::post("http://example.com/bar/")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![get, post]);
+
+        log.assert_request(Method::GET, "/foo");
+        log.assert_request(Method::POST, "/bar/");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "expected a POST /bar request, but got [\"GET /foo\", \"POST /bar/\"]"
+    )]
+    fn test_assert_request_fails() {
+        let log = RequestLog::new();
+        let get = Request::get("http://example.com/foo")
+            .body(Body::empty())
+            .unwrap();
+        let post = Request::post("http://example.com/bar/")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![get, post]);
+
+        log.assert_request(Method::POST, "/bar");
+    }
+
+    #[test]
+    fn test_framing() {
+        use futures::stream;
+        use std::io;
+
+        let log = RequestLog::new();
+        let chunks = vec![Ok::<_, io::Error>("hello "), Ok("world")];
+        let requests = vec![
+            Request::post("http://example.com")
+                .body("hello".into())
+                .unwrap(),
+            Request::post("http://example.com")
+                .body(Body::wrap_stream(stream::iter_result(chunks)))
+                .unwrap(),
+            Request::get("http://example.com")
+                .body(Body::empty())
+                .unwrap(),
+        ];
+        send(&log, requests);
+
+        let framings: Vec<_> = log.entries().iter().map(|e| e.framing()).collect();
+        assert_eq!(
+            framings,
+            [
+                BodyFraming::ContentLength(5),
+                BodyFraming::Chunked,
+                BodyFraming::Empty,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assert_body_eq() {
+        let log = RequestLog::new();
+        let post = Request::post("http://example.com")
+            .body("hello\nworld".into())
+            .unwrap();
+        send(&log, vec![post]);
+
+        log.assert_body_eq(0, "hello\nworld");
+        log.assert_body_eq(0, b"hello\nworld");
+    }
+
+    #[test]
+    #[should_panic(expected = "didn't have the expected body:\n  hello\n- there\n+ world\n")]
+    fn test_assert_body_eq_fails() {
+        let log = RequestLog::new();
+        let post = Request::post("http://example.com")
+            .body("hello\nworld".into())
+            .unwrap();
+        send(&log, vec![post]);
+
+        log.assert_body_eq(0, "hello\nthere");
+    }
+
+    #[test]
+    fn test_header_all() {
+        let log = RequestLog::new();
+        let get = Request::get("http://example.com")
+            .header("x-forwarded-for", "203.0.113.1")
+            .header("via", "1.1 proxy-a")
+            .header("x-forwarded-for", "198.51.100.2")
+            .header("via", "1.1 proxy-b")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![get]);
+
+        let entry = log.get(0).unwrap();
+        assert_eq!(
+            entry.header_all("x-forwarded-for"),
+            ["203.0.113.1", "198.51.100.2"]
+        );
+        assert!(entry.header_all("forwarded").is_empty());
+        log.assert_header_values(0, "via", &["1.1 proxy-a", "1.1 proxy-b"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "to have via values [\"1.1 proxy-b\", \"1.1 proxy-a\"], \
+                    but got [\"1.1 proxy-a\", \"1.1 proxy-b\"]")]
+    fn test_assert_header_values_fails() {
+        let log = RequestLog::new();
+        let get = Request::get("http://example.com")
+            .header("via", "1.1 proxy-a")
+            .header("via", "1.1 proxy-b")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![get]);
+
+        log.assert_header_values(0, "via", &["1.1 proxy-b", "1.1 proxy-a"]);
+    }
+
+    #[test]
+    fn test_query_params() {
+        let log = RequestLog::new();
+        let uri = "http://example.com/search?q=caf%C3%A9+au%20lait&tag=a&tag=b&empty=&flag";
+        let get = Request::get(uri).body(Body::empty()).unwrap();
+        let plain = Request::get("http://example.com/")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![get, plain]);
+
+        let params = log.get(0).unwrap().query_params();
+        assert_eq!(params["q"], "café au lait");
+        assert_eq!(params["tag"], "b");
+        assert_eq!(params["empty"], "");
+        assert_eq!(params["flag"], "");
+        assert_eq!(params.len(), 4);
+        assert!(log.get(1).unwrap().query_params().is_empty());
+    }
+
+    #[test]
+    fn test_cookie() {
+        let log = RequestLog::new();
+        let get = Request::get("http://example.com")
+            .header("cookie", "a=1; b=2")
+            .header("cookie", "session=\"x%20y\"; a=3")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![get]);
+
+        let entry = log.get(0).unwrap();
+        assert_eq!(entry.cookie("a").unwrap(), "1");
+        assert_eq!(entry.cookie("b").unwrap(), "2");
+        assert_eq!(entry.cookie("session").unwrap(), "\"x%20y\"");
+        assert_eq!(entry.cookie("A"), None);
+        assert_eq!(entry.cookie("missing"), None);
+        log.assert_cookie(0, "b", "2");
+    }
+
+    #[test]
+    #[should_panic(expected = "to have cookie b=\"3\", but got Some(\"2\")")]
+    fn test_assert_cookie_fails() {
+        let log = RequestLog::new();
+        let get = Request::get("http://example.com")
+            .header("cookie", "a=1; b=2")
+            .body(Body::empty())
+            .unwrap();
+        send(&log, vec![get]);
+
+        log.assert_cookie(0, "b", "3");
+    }
+
+    #[test]
+    fn test_assert_all_bodies_utf8() {
+        let log = RequestLog::new();
+        let post = |body: &'static [u8]| {
+            Request::post("http://example.com")
+                .body(body.into())
+                .unwrap()
+        };
+        send(&log, vec![post(b"caf\xc3\xa9"), post(b"")]);
+        log.assert_all_bodies_utf8();
+    }
+
+    #[test]
+    #[should_panic(expected = "request 1 (POST http://example.com/) had invalid utf-8")]
+    fn test_assert_all_bodies_utf8_fails() {
+        let log = RequestLog::new();
+        let post = |body: &'static [u8]| {
+            Request::post("http://example.com")
+                .body(body.into())
+                .unwrap()
+        };
+        send(&log, vec![post(b"text"), post(b"\xff\xfe"), post(b"\xff")]);
+        log.assert_all_bodies_utf8();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_assert_all_bodies_json() {
+        let log = RequestLog::new();
+        let post = |body: &'static str| {
+            Request::post("http://example.com")
+                .body(body.into())
+                .unwrap()
+        };
+        send(&log, vec![post("{\"a\": 1}"), post(""), post("[1, 2]")]);
+        log.assert_all_bodies_json();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    #[should_panic(expected = "request 1 (POST http://example.com/) had EOF while parsing")]
+    fn test_assert_all_bodies_json_fails() {
+        let log = RequestLog::new();
+        let post = |body: &'static str| {
+            Request::post("http://example.com")
+                .body(body.into())
+                .unwrap()
+        };
+        send(&log, vec![post("{}"), post("{\"a\": "), post("nope")]);
+        log.assert_all_bodies_json();
+    }
+
+    #[test]
+    fn test_raw_path() {
+        let log = RequestLog::new();
+        let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+        send(
+            &log,
+            vec![
+                get("http://example.com/a%20b/caf%C3%A9/%e2%9c%93"),
+                get("http://example.com/./x/../y%2Fz"),
+                get("http://example.com"),
+            ],
+        );
+
+        assert_eq!(log.get(0).unwrap().raw_path(), "/a%20b/caf%C3%A9/%e2%9c%93");
+        assert_eq!(log.get(1).unwrap().raw_path(), "/./x/../y%2Fz");
+        assert_eq!(log.get(2).unwrap().raw_path(), "/");
+
+        assert!("http://example.com/a b".parse::<Uri>().is_err());
+        assert!("http://example.com/café".parse::<Uri>().is_err());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Fb%2fc"), "a/b/c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn test_binary_body_diff() {
+        let mut expected = vec![0u8; 20];
+        expected[0] = 0xff;
+        let mut actual = expected.clone();
+        actual[17] = 0x01;
+
+        assert_eq!(
+            body_diff(&expected, &actual).unwrap(),
+            "first difference at byte offset 17 (expected 20 bytes, got 20)\n\
+             expected 00000010: 00 00 00 00\n\
+             actual   00000010: 00 01 00 00\n"
+        );
+        assert!(body_diff(&expected, &expected).is_none());
+    }
+}
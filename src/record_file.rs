@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use record::RecordedRequest;
+use snapshot::base64;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// A file that requests are appended to as NDJSON. See
+// StubBuilder::record_to_file.
+#[derive(Clone, Debug)]
+pub(crate) struct RecordFile(Arc<Mutex<File>>);
+
+impl RecordFile {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RecordFile(Arc::new(Mutex::new(file))))
+    }
+
+    pub(crate) fn write(&self, request: &RecordedRequest) -> io::Result<()> {
+        let headers: Vec<_> = request
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.as_str(), String::from_utf8_lossy(value.as_bytes())))
+            .collect();
+        let entry = json!({
+            "method": request.method().as_str(),
+            "uri": request.uri().to_string(),
+            "headers": headers,
+            "body": base64(request.body()),
+        });
+
+        // Each line is written with a single call, while holding the lock,
+        // so lines from concurrent requests can't be interleaved.
+        let mut line = entry.to_string();
+        line.push('\n');
+        let mut file = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request, Response};
+    use serde_json::{self, Value};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use tokio::runtime::current_thread::Runtime;
+    use StubBuilder;
+
+    #[test]
+    fn test_record_to_file() {
+        let path = env::temp_dir().join(format!("hyper-stub-record-{}.ndjson", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let client = StubBuilder::new()
+            .record_to_file(&path)
+            .unwrap()
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let post = Request::post("http://example.com/upload?x=1")
+            .header("x-custom", "yes")
+            .body(Body::from(&b"\x00\x01binary"[..]))
+            .unwrap();
+        let get = Request::get("http://example.com/")
+            .body(Body::empty())
+            .unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(client.request(post)).unwrap();
+        runtime.block_on(client.request(get)).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["method"], "POST");
+        assert_eq!(lines[0]["uri"], "http://example.com/upload?x=1");
+        assert!(lines[0]["headers"]
+            .as_array()
+            .unwrap()
+            .contains(&json!(["x-custom", "yes"])));
+        assert_eq!(lines[0]["body"], "AAFiaW5hcnk=");
+        assert_eq!(lines[1]["method"], "GET");
+        assert_eq!(lines[1]["body"], "");
+    }
+
+    #[test]
+    fn test_unwritable_path() {
+        let path = env::temp_dir().join("hyper-stub-missing-directory/requests.ndjson");
+        let error = StubBuilder::new().record_to_file(&path).unwrap_err();
+        assert_eq!(error.kind(), ::std::io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,284 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use http;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+/// A value a handler passed to [`proxy_client_fn_into`] can return, to be
+/// converted into the response the stub sends.
+///
+/// It's implemented for:
+///
+/// - `Response<B>`, for any body `B` that converts into a hyper `Body`, like
+///   `&'static str`, `String`, `Vec<u8>` or `Bytes`.
+/// - `http::Result<Response<B>>`, which is what building a response with
+///   `Response::builder()` gives, so a handler doesn't have to unwrap it
+///   itself. If building the response failed, like because a header value
+///   was invalid, the handler panics with a message saying why, rather than
+///   sending some other response in its place. With
+///   [`StubBuilder::catch_handler_panics`], the client gets a `500 Internal
+///   Server Error` with that message.
+/// - `StatusCode`, for an empty response with that status.
+///
+/// [`proxy_client_fn_into`]: fn.proxy_client_fn_into.html
+/// [`StubBuilder::catch_handler_panics`]: struct.StubBuilder.html#method.catch_handler_panics
+pub trait IntoResponse {
+    /// Converts this value into a response.
+    fn into_response(self) -> Response<Body>;
+}
+
+impl<B: Into<Body>> IntoResponse for Response<B> {
+    fn into_response(self) -> Response<Body> {
+        self.map(Into::into)
+    }
+}
+
+impl<B: Into<Body>> IntoResponse for http::Result<Response<B>> {
+    fn into_response(self) -> Response<Body> {
+        match self {
+            Ok(response) => response.into_response(),
+            Err(err) => panic!("handler built an invalid response: {}", err),
+        }
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = self;
+        response
+    }
+}
+
+/// Creates an empty response with the given status and headers.
+///
+/// Headers are appended in order, so a header name that appears more than
+/// once keeps every value, and hyper writes each one as a separate header
+/// line. This is what's needed for headers like `Set-Cookie`, which can't be
+/// combined into one line.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::header::{HeaderValue, SET_COOKIE};
+/// use hyper::StatusCode;
+/// use hyper_stub::{proxy_client_fn_ok, response_with_headers};
+///
+/// let client = proxy_client_fn_ok(|_| {
+///     response_with_headers(
+///         StatusCode::OK,
+///         vec![
+///             (SET_COOKIE, HeaderValue::from_static("a=1")),
+///             (SET_COOKIE, HeaderValue::from_static("b=2")),
+///         ],
+///     )
+/// });
+/// ```
+pub fn response_with_headers(
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    for (name, value) in headers {
+        response.headers_mut().append(name, value);
+    }
+    response
+}
+
+/// Creates a response with the given status and body, and no headers at all.
+///
+/// In particular, the response has no `Content-Type`, and hyper's server
+/// doesn't add one, so the client sees only the `Content-Length` and `Date`
+/// headers hyper always writes. This is for testing how a client handles a
+/// response without a `Content-Type`, like one that would otherwise sniff it
+/// from the body. See [Content-Type] for which other helpers set one.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::StatusCode;
+/// use hyper_stub::{no_content_type, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(|_| {
+///     no_content_type(StatusCode::OK, vec![0x89, b'P', b'N', b'G'])
+/// });
+/// ```
+///
+/// [Content-Type]: index.html#content-type
+pub fn no_content_type<B: Into<Bytes>>(status: StatusCode, body: B) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Creates an empty `200 OK` response with `count` headers, named
+/// `x-large-0`, `x-large-1`, and so on, each with a value of `value_len`
+/// bytes.
+///
+/// This is for testing how a client handles a response head too big for it.
+/// hyper's server doesn't limit the headers it writes, so these responses go
+/// through the usual stub clients as they are, without needing
+/// [`proxy_client_raw`]. hyper's client, however, has two limits:
+///
+/// - A response with more than 100 headers, counting the `Content-Length`
+///   and `Date` headers hyper's server adds, is rejected.
+/// - A response head is read into a buffer of at most 417,792 bytes (8 KiB,
+///   plus 4 KiB for each of those 100 headers), so a head longer than that
+///   is rejected however few headers it has. hyper only checks this between
+///   reads from the connection, and its reads get bigger as the buffer
+///   grows, so a head that's only a little longer can still be read in
+///   full. One of 1 MiB or more is reliably rejected.
+///
+/// Either way, the request fails with a parse error whose message is
+/// "message head is too large". A head within both limits is accepted, so
+/// `large_header_response(98, 4096)` gets through, but
+/// `large_header_response(99, 1)` or `large_header_response(1, 1024 * 1024)`
+/// don't.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{large_header_response, proxy_client_fn_ok};
+///
+/// let client = proxy_client_fn_ok(|_| large_header_response(200, 16));
+/// ```
+///
+/// [`proxy_client_raw`]: fn.proxy_client_raw.html
+pub fn large_header_response(count: usize, value_len: usize) -> Response<Body> {
+    let value = HeaderValue::from_str(&"a".repeat(value_len)).unwrap();
+    let headers = (0..count)
+        .map(|i| {
+            let name = HeaderName::from_bytes(format!("x-large-{}", i).as_bytes()).unwrap();
+            (name, value.clone())
+        })
+        .collect();
+    response_with_headers(StatusCode::OK, headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use futures::prelude::*;
+    use hyper::header::{CONTENT_TYPE, SET_COOKIE};
+    use tokio::runtime::current_thread::Runtime;
+    use StubBuilder;
+
+    fn cookie_response() -> Response<Body> {
+        response_with_headers(
+            StatusCode::OK,
+            vec![
+                (SET_COOKIE, HeaderValue::from_static("a=1; Path=/")),
+                (SET_COOKIE, HeaderValue::from_static("b=2; HttpOnly")),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_duplicate_headers() {
+        let client = ::proxy_client_fn_ok(|_| cookie_response());
+        let future = client.get("http://example.com".parse().unwrap());
+        let response = Runtime::new().unwrap().block_on(future).unwrap();
+
+        let cookies: Vec<_> = response.headers().get_all(SET_COOKIE).iter().collect();
+        assert_eq!(cookies, ["a=1; Path=/", "b=2; HttpOnly"]);
+    }
+
+    #[test]
+    fn test_separate_header_lines() {
+        let future = StubBuilder::new()
+            .raw_connection_fn(|_| future::ok::<_, ::hyper::Error>(cookie_response()))
+            .and_then(|io| {
+                ::tokio::io::write_all(io, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            })
+            .and_then(|(io, _)| ::tokio::io::read_to_end(io, Vec::new()));
+        let (_, response) = Runtime::new().unwrap().block_on(future).unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.contains("\r\nset-cookie: a=1; Path=/\r\n"));
+        assert!(response.contains("\r\nset-cookie: b=2; HttpOnly\r\n"));
+    }
+
+    #[test]
+    fn test_no_content_type() {
+        let client = ::proxy_client_fn_ok(|_| no_content_type(StatusCode::OK, &b"\x00\x01"[..]));
+        let future = client
+            .get("http://example.com".parse().unwrap())
+            .and_then(|res| {
+                assert!(res.headers().get(CONTENT_TYPE).is_none());
+                res.into_body().concat2()
+            });
+        assert_eq!(
+            &Runtime::new().unwrap().block_on(future).unwrap()[..],
+            b"\x00\x01"
+        );
+    }
+
+    fn get_large(count: usize, value_len: usize) -> Result<Response<Body>, ::hyper::Error> {
+        let client = ::proxy_client_fn_ok(move |_| large_header_response(count, value_len));
+        let future = client.get("http://example.com".parse().unwrap());
+        Runtime::new().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn test_large_headers_within_limits() {
+        let response = get_large(98, 4096).unwrap();
+        assert_eq!(response.headers()["x-large-97"].len(), 4096);
+    }
+
+    #[test]
+    fn test_too_many_headers() {
+        let error = get_large(99, 1).unwrap_err();
+        assert!(error.is_parse(), "{}", error);
+    }
+
+    #[test]
+    fn test_header_too_long() {
+        let error = get_large(1, 1024 * 1024).unwrap_err();
+        assert!(error.is_parse(), "{}", error);
+    }
+
+    #[test]
+    fn test_into_response() {
+        use panic::PanicLog;
+
+        let log = PanicLog::new();
+        let client = StubBuilder::new()
+            .catch_handler_panics(&log)
+            .proxy_client_fn_into(|req| match req.uri().path() {
+                "/created" => Response::builder()
+                    .status(201)
+                    .header("x-id", "42")
+                    .body("created"),
+                _ => Response::builder().header("x-id", "\n").body("invalid"),
+            });
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |uri: &str| {
+            let future = client.get(uri.parse().unwrap()).and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().map(move |body| (status, body))
+            });
+            let (status, body) = runtime.block_on(future).unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        let (status, body) = get("http://example.com/created");
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, "created");
+
+        let (status, body) = get("http://example.com/invalid");
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(
+            body.contains("handler built an invalid response"),
+            "{}",
+            body
+        );
+    }
+}
@@ -0,0 +1,660 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use sequence::Sequence;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+type Handler = Arc<dyn Fn(Request<Body>) -> Response<Body> + Send + Sync>;
+
+/// Dispatches requests to different handlers depending on their method and
+/// path.
+///
+/// Patterns are made of `/`-separated segments. A segment starting with `:`
+/// is a parameter, which matches any non-empty segment and captures it under
+/// that name in the request's [`Params`] extension. Any other segment only
+/// matches itself. Parameters are captured exactly as they appear in the
+/// path, without any percent-decoding.
+///
+/// Trailing slashes are significant: `/users/` and `/users` are different
+/// patterns, and each only matches paths with or without the trailing slash
+/// respectively.
+///
+/// When more than one route matches a request, the most specific one wins.
+/// Routes are compared segment by segment from the start of the path, and the
+/// first time they differ, a literal segment beats a parameter. So
+/// `/users/me` always takes priority over `/users/:id`, however they were
+/// added. Routes that are equally specific are tried in the order they were
+/// added.
+///
+/// Requests that don't match any route get a `404 Not Found` response.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::{Method, Response};
+/// use hyper_stub::{proxy_client_fn_ok, Params, Router};
+///
+/// let router = Router::new().route(Method::GET, "/users/:id", |req| {
+///     let id = req.extensions().get::<Params>().unwrap().get("id").unwrap();
+///     Response::new(format!("user {}", id).into())
+/// });
+///
+/// let client = proxy_client_fn_ok(router.handler());
+/// ```
+///
+/// [`Params`]: struct.Params.html
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+#[derive(Clone)]
+struct Route {
+    method: Method,
+    source: String,
+    pattern: Vec<Segment>,
+    handler: Handler,
+    hits: Arc<AtomicUsize>,
+    expected: Option<Times>,
+}
+
+// How many times a route is expected to be hit, set by Router::times and
+// friends. Routes without one are expected to be hit at least once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Times {
+    Exactly(usize),
+    AtMost(usize),
+    AtLeast(usize),
+}
+
+impl Times {
+    fn allows(self, hits: usize) -> bool {
+        match self {
+            Times::Exactly(n) => hits == n,
+            Times::AtMost(n) => hits <= n,
+            Times::AtLeast(n) => hits >= n,
+        }
+    }
+}
+
+impl fmt::Display for Times {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            Times::Exactly(n) => write!(fmt, "exactly {}", n),
+            Times::AtMost(n) => write!(fmt, "at most {}", n),
+            Times::AtLeast(n) => write!(fmt, "at least {}", n),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+impl Segment {
+    fn is_param(&self) -> bool {
+        match *self {
+            Segment::Literal(_) => false,
+            Segment::Param(_) => true,
+        }
+    }
+}
+
+// Patterns and paths are split the same way, so that each pattern segment
+// lines up with the path segment it should match.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.trim_start_matches('/').split('/')
+}
+
+impl Route {
+    fn matches(&self, req: &Request<Body>) -> Option<Params> {
+        if req.method() != self.method {
+            return None;
+        }
+
+        let path: Vec<_> = segments(req.uri().path()).collect();
+        if path.len() != self.pattern.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        for (segment, actual) in self.pattern.iter().zip(path) {
+            match *segment {
+                Segment::Literal(ref literal) if literal == actual => {}
+                Segment::Param(ref name) if !actual.is_empty() => {
+                    params.insert(name.clone(), actual.to_string());
+                }
+                _ => return None,
+            }
+        }
+
+        Some(Params { params })
+    }
+}
+
+impl Router {
+    /// Creates a router with no routes.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a route that sends requests with `method` and a path matching
+    /// `pattern` to `handler`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` doesn't start with `/`, or if it has a parameter
+    /// without a name.
+    pub fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Request<Body>) -> Response<Body> + Send + Sync + 'static,
+    {
+        assert!(
+            pattern.starts_with('/'),
+            "route pattern {:?} must start with '/'",
+            pattern
+        );
+
+        let source = pattern;
+        let pattern = segments(pattern)
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => {
+                    assert!(!name.is_empty(), "unnamed parameter in route pattern");
+                    Segment::Param(name.to_string())
+                }
+                None => Segment::Literal(segment.to_string()),
+            })
+            .collect();
+
+        self.routes.push(Route {
+            method,
+            source: source.to_string(),
+            pattern,
+            handler: Arc::new(handler),
+            hits: Default::default(),
+            expected: None,
+        });
+        self
+    }
+
+    /// Adds a route that sends requests with `method` and a path matching
+    /// `pattern` through the steps of `sequence`.
+    ///
+    /// Each route added this way works through its own copy of the
+    /// sequence, so requests to one route don't advance the sequence of
+    /// another. The sequence's position belongs to the route, not to the
+    /// path requested: for a pattern with parameters, like `/users/:id`,
+    /// requests for `/users/1` and `/users/2` share one sequence, and
+    /// advance it in the order they arrive.
+    ///
+    /// ```
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// #
+    /// use hyper::{Method, Response, StatusCode};
+    /// use hyper_stub::{proxy_client_fn_ok, Router, Sequence};
+    ///
+    /// let flaky = Sequence::new()
+    ///     .then(|_| {
+    ///         let mut response = Response::new("try again".into());
+    ///         *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    ///         response
+    ///     })
+    ///     .then(|_| Response::new("ok".into()));
+    ///
+    /// let router = Router::new()
+    ///     .route_sequence(Method::GET, "/a", flaky.clone())
+    ///     .route_sequence(Method::GET, "/b", flaky);
+    /// let client = proxy_client_fn_ok(router.handler());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is invalid, as for [`route`].
+    ///
+    /// [`route`]: #method.route
+    pub fn route_sequence(self, method: Method, pattern: &str, sequence: Sequence) -> Self {
+        self.route(method, pattern, sequence.handler())
+    }
+
+    /// Expects the route added last to be hit exactly `n` times, when
+    /// checked by a [`RouteCoverage`] guard.
+    ///
+    /// Without one of these quantifiers, a route is expected to be hit at
+    /// least once. The quantifier replaces that default, and a later call
+    /// for the same route replaces an earlier one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no route has been added yet.
+    ///
+    /// [`RouteCoverage`]: struct.RouteCoverage.html
+    pub fn times(self, n: usize) -> Self {
+        self.expect(Times::Exactly(n))
+    }
+
+    /// Expects the route added last to be hit at most `n` times. See
+    /// [`times`].
+    ///
+    /// [`times`]: #method.times
+    pub fn at_most(self, n: usize) -> Self {
+        self.expect(Times::AtMost(n))
+    }
+
+    /// Expects the route added last to be hit at least `n` times. See
+    /// [`times`].
+    ///
+    /// [`times`]: #method.times
+    pub fn at_least(self, n: usize) -> Self {
+        self.expect(Times::AtLeast(n))
+    }
+
+    /// Expects the route added last never to be hit, for a request the
+    /// client under test mustn't make. See [`times`].
+    ///
+    /// [`times`]: #method.times
+    pub fn never(self) -> Self {
+        self.expect(Times::Exactly(0))
+    }
+
+    fn expect(mut self, times: Times) -> Self {
+        let route = self
+            .routes
+            .last_mut()
+            .expect("a quantifier must follow the route it applies to");
+        route.expected = Some(times);
+        self
+    }
+
+    /// Returns a guard that checks every route added so far is hit by at
+    /// least one request, when [`verify`] is called, or when it is dropped.
+    ///
+    /// Routes given a quantifier, like [`times`] or [`never`], are checked
+    /// against that instead. Since hits are only counted up to the point the
+    /// guard checks them, it should verify at the end of the test, once the
+    /// client has made every request it's going to; dropping the guard
+    /// there does this automatically.
+    ///
+    /// A route is hit when it is the route chosen for a request, and its
+    /// handler is called. What the handler responds with doesn't matter, so a
+    /// route whose handler returns an error response has still been hit.
+    /// Only routes that no request was ever dispatched to count as unhit,
+    /// because they're the stub definitions the test never exercised.
+    ///
+    /// Routes added to the router after this is called aren't checked.
+    ///
+    /// ```
+    /// # extern crate futures;
+    /// # extern crate hyper;
+    /// # extern crate hyper_stub;
+    /// # extern crate tokio;
+    /// #
+    /// use futures::Future;
+    /// use hyper::{Method, Response};
+    /// use hyper_stub::{proxy_client_fn_ok, Router};
+    /// use tokio::runtime::current_thread::Runtime;
+    ///
+    /// let router = Router::new()
+    ///     .route(Method::GET, "/users", |_| Response::new("[]".into()));
+    /// let coverage = router.require_all_hit();
+    /// let client = proxy_client_fn_ok(router.handler());
+    ///
+    /// let future = client.get("http://example.com/users".parse().unwrap());
+    /// Runtime::new().unwrap().block_on(future).unwrap();
+    ///
+    /// coverage.verify();
+    /// ```
+    ///
+    /// [`verify`]: struct.RouteCoverage.html#method.verify
+    /// [`times`]: #method.times
+    /// [`never`]: #method.never
+    pub fn require_all_hit(&self) -> RouteCoverage {
+        RouteCoverage {
+            routes: self
+                .routes
+                .iter()
+                .map(|route| {
+                    (
+                        format!("{} {}", route.method, route.source),
+                        route.hits.clone(),
+                        route.expected,
+                    )
+                })
+                .collect(),
+            verified: false,
+        }
+    }
+
+    /// Creates a handler that dispatches each request to the most specific
+    /// matching route.
+    pub fn handler(
+        mut self,
+    ) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+        // A stable sort, so that equally specific routes keep the order they
+        // were added in.
+        self.routes.sort_by(|a, b| {
+            let a = a.pattern.iter().map(Segment::is_param);
+            let b = b.pattern.iter().map(Segment::is_param);
+            a.cmp(b)
+        });
+        let routes = Arc::new(self.routes);
+
+        move |mut req| {
+            for route in routes.iter() {
+                if let Some(params) = route.matches(&req) {
+                    route.hits.fetch_add(1, Ordering::SeqCst);
+                    req.extensions_mut().insert(params);
+                    return (route.handler)(req);
+                }
+            }
+
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    }
+}
+
+impl Debug for Router {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|route| (&route.method, &route.pattern))
+            .collect();
+        fmt.debug_struct("Router").field("routes", &routes).finish()
+    }
+}
+
+/// A guard that panics if some of a [`Router`]'s routes were never hit, or
+/// were hit a different number of times than expected. See
+/// [`Router::require_all_hit`].
+///
+/// If the guard is dropped without [`verify`] having been called, it verifies
+/// then, unless the thread is already panicking, so that a failing test
+/// doesn't abort with a second panic.
+///
+/// [`Router`]: struct.Router.html
+/// [`Router::require_all_hit`]: struct.Router.html#method.require_all_hit
+/// [`verify`]: #method.verify
+#[derive(Debug)]
+#[must_use = "the guard verifies routes when it is dropped"]
+pub struct RouteCoverage {
+    routes: Vec<(String, Arc<AtomicUsize>, Option<Times>)>,
+    verified: bool,
+}
+
+impl RouteCoverage {
+    /// Checks that every route has been hit, or hit as many times as its
+    /// quantifier expects.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any route hasn't been hit, listing each of them by method
+    /// and pattern, followed by a line for each route whose quantifier wasn't
+    /// satisfied.
+    pub fn verify(mut self) {
+        self.verified = true;
+        self.check();
+    }
+
+    fn check(&self) {
+        let mut unhit = Vec::new();
+        let mut violations = Vec::new();
+        for (route, hits, expected) in &self.routes {
+            let hits = hits.load(Ordering::SeqCst);
+            match *expected {
+                None if hits == 0 => unhit.push(&route[..]),
+                Some(times) if !times.allows(hits) => violations.push(format!(
+                    "{} was hit {} times, expected {}",
+                    route, hits, times
+                )),
+                _ => {}
+            }
+        }
+
+        if !unhit.is_empty() {
+            violations.insert(0, format!("routes were never hit: {}", unhit.join(", ")));
+        }
+        if !violations.is_empty() {
+            panic!("{}", violations.join("\n"));
+        }
+    }
+}
+
+impl Drop for RouteCoverage {
+    fn drop(&mut self) {
+        if !self.verified && !thread::panicking() {
+            self.check();
+        }
+    }
+}
+
+/// The path parameters captured by a [`Router`], available to route handlers
+/// as a request extension.
+///
+/// [`Router`]: struct.Router.html
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Params {
+    params: HashMap<String, String>,
+}
+
+impl Params {
+    /// Returns the value captured for the parameter `name`, if the route had
+    /// a parameter with that name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// Returns all the captured parameters, keyed by name.
+    pub fn as_map(&self) -> &HashMap<String, String> {
+        &self.params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn get(router: Router, uri: &str) -> (StatusCode, String) {
+        let client = ::proxy_client_fn_ok(router.handler());
+        Runtime::new()
+            .unwrap()
+            .block_on(client.get(uri.parse().unwrap()).and_then(|res| {
+                let status = res.status();
+                res.into_body()
+                    .concat2()
+                    .map(move |body| (status, String::from_utf8(body.to_vec()).unwrap()))
+            }))
+            .unwrap()
+    }
+
+    fn param(req: &Request<Body>, name: &str) -> String {
+        let params = req.extensions().get::<Params>().unwrap();
+        params.get(name).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_extracts_params() {
+        let router = Router::new().route(Method::GET, "/users/:id/posts/:post", |req| {
+            let body = format!("{} {}", param(&req, "id"), param(&req, "post"));
+            Response::new(body.into())
+        });
+
+        let (status, body) = get(router.clone(), "http://example.com/users/42/posts/7");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "42 7");
+
+        let (status, _) = get(router.clone(), "http://example.com/users/42/posts/7/");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = get(router, "http://example.com/users//posts/7");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_most_specific_first() {
+        let router = Router::new()
+            .route(Method::GET, "/users/:id", |req| {
+                Response::new(param(&req, "id").into())
+            })
+            .route(Method::GET, "/users/me", |_| Response::new("me".into()));
+
+        assert_eq!(get(router.clone(), "http://example.com/users/me").1, "me");
+        assert_eq!(get(router, "http://example.com/users/you").1, "you");
+    }
+
+    #[test]
+    fn test_require_all_hit() {
+        let router = Router::new()
+            .route(Method::GET, "/users", |_| Response::new(Body::empty()))
+            .route(Method::POST, "/users", |_| {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                response
+            });
+        let coverage = router.require_all_hit();
+
+        let client = ::proxy_client_fn_ok(router.handler());
+        let request = Request::post("http://example.com/users")
+            .body(Body::empty())
+            .unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(client.request(request)).unwrap();
+        runtime
+            .block_on(client.get("http://example.com/users".parse().unwrap()))
+            .unwrap();
+
+        coverage.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = "routes were never hit: DELETE /users/:id, GET /health")]
+    fn test_require_all_hit_fails() {
+        let router = Router::new()
+            .route(Method::GET, "/users/:id", |_| Response::new(Body::empty()))
+            .route(Method::DELETE, "/users/:id", |_| {
+                Response::new(Body::empty())
+            })
+            .route(Method::GET, "/health", |_| Response::new(Body::empty()));
+        let _coverage = router.require_all_hit();
+
+        get(router, "http://example.com/users/1");
+    }
+
+    #[test]
+    fn test_route_sequence() {
+        let sequence = Sequence::new()
+            .then(|_| Response::new("first".into()))
+            .then(|_| Response::new("second".into()));
+        let router = Router::new()
+            .route_sequence(Method::GET, "/a", sequence.clone())
+            .route_sequence(Method::GET, "/b/:id", sequence);
+
+        let client = ::proxy_client_fn_ok(router.handler());
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |uri: &str| {
+            let future = client
+                .get(uri.parse().unwrap())
+                .and_then(|res| res.into_body().concat2());
+            String::from_utf8(runtime.block_on(future).unwrap().to_vec()).unwrap()
+        };
+
+        assert_eq!(get("http://example.com/a"), "first");
+        assert_eq!(get("http://example.com/b/1"), "first");
+        assert_eq!(get("http://example.com/a"), "second");
+        assert_eq!(get("http://example.com/b/2"), "second");
+        assert_eq!(
+            get("http://example.com/a"),
+            "sequence exhausted after 2 responses"
+        );
+    }
+
+    fn hit_routes(router: Router, uris: &[&str]) {
+        let client = ::proxy_client_fn_ok(router.handler());
+        let mut runtime = Runtime::new().unwrap();
+        for uri in uris {
+            runtime.block_on(client.get(uri.parse().unwrap())).unwrap();
+        }
+    }
+
+    fn quantified() -> Router {
+        Router::new()
+            .route(Method::GET, "/once", |_| Response::new(Body::empty()))
+            .times(1)
+            .route(Method::GET, "/few", |_| Response::new(Body::empty()))
+            .at_most(2)
+            .route(Method::GET, "/many", |_| Response::new(Body::empty()))
+            .at_least(2)
+            .route(Method::GET, "/admin", |_| Response::new(Body::empty()))
+            .never()
+    }
+
+    #[test]
+    fn test_quantifiers() {
+        let router = quantified();
+        let coverage = router.require_all_hit();
+
+        hit_routes(
+            router,
+            &[
+                "http://example.com/once",
+                "http://example.com/many",
+                "http://example.com/many",
+                "http://example.com/many",
+            ],
+        );
+
+        coverage.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = "GET /once was hit 2 times, expected exactly 1\n\
+                               GET /few was hit 3 times, expected at most 2\n\
+                               GET /many was hit 1 times, expected at least 2\n\
+                               GET /admin was hit 1 times, expected exactly 0")]
+    fn test_quantifiers_fail() {
+        let router = quantified();
+        let coverage = router.require_all_hit();
+
+        hit_routes(
+            router,
+            &[
+                "http://example.com/once",
+                "http://example.com/once",
+                "http://example.com/few",
+                "http://example.com/few",
+                "http://example.com/few",
+                "http://example.com/many",
+                "http://example.com/admin",
+            ],
+        );
+
+        coverage.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = "routes were never hit: GET /users\nGET /admin was hit 1 times")]
+    fn test_quantifiers_with_unhit() {
+        let router = Router::new()
+            .route(Method::GET, "/users", |_| Response::new(Body::empty()))
+            .route(Method::GET, "/admin", |_| Response::new(Body::empty()))
+            .never();
+        let coverage = router.require_all_hit();
+
+        hit_routes(router, &["http://example.com/admin"]);
+
+        coverage.verify();
+    }
+}
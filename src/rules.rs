@@ -0,0 +1,212 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// A predicate deciding whether a rule passed to [`proxy_client_rules`]
+/// applies to a request.
+///
+/// Matchers only see the request head. The body hasn't been read yet when
+/// they're called, so a matcher that needs to look at it has to be given a
+/// request that has already been buffered some other way.
+///
+/// [`proxy_client_rules`]: fn.proxy_client_rules.html
+pub type Matcher = Box<dyn Fn(&Request<Body>) -> bool + Send + Sync>;
+
+/// Creates the response for a request matched by a rule passed to
+/// [`proxy_client_rules`], or routed to it by [`proxy_client_keyed`].
+///
+/// [`proxy_client_rules`]: fn.proxy_client_rules.html
+/// [`proxy_client_keyed`]: fn.proxy_client_keyed.html
+pub type ResponseFactory = Box<dyn Fn(Request<Body>) -> Response<Body> + Send + Sync>;
+
+// The handler behind proxy_client_rules.
+pub(crate) fn rules_handler(
+    rules: Vec<(Matcher, ResponseFactory)>,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    let rules = Arc::new(rules);
+
+    move |req| {
+        for (matcher, factory) in rules.iter() {
+            if matcher(&req) {
+                return factory(req);
+            }
+        }
+
+        let message = format!("no rule matched {} {}", req.method(), req.uri());
+        let mut response = Response::new(message.into());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        response
+    }
+}
+
+// The handler behind proxy_client_map.
+pub(crate) fn map_handler(
+    map: HashMap<Uri, (StatusCode, Bytes)>,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+    let map = Arc::new(map);
+
+    move |req| match map.get(req.uri()) {
+        Some(&(status, ref body)) => {
+            let mut response = Response::new(body.clone().into());
+            *response.status_mut() = status;
+            response
+        }
+
+        None => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    }
+}
+
+// The handler behind proxy_client_keyed.
+pub(crate) fn keyed_handler<K, X>(
+    extractor: X,
+    map: HashMap<K, ResponseFactory>,
+    default: ResponseFactory,
+) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    X: Fn(&Request<Body>) -> K + Send + Sync + 'static,
+{
+    let extractor = Arc::new(extractor);
+    let map = Arc::new(map);
+    let default = Arc::new(default);
+
+    move |req| match map.get(&extractor(&req)) {
+        Some(factory) => factory(req),
+        None => default(req),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use hyper::Method;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn test_first_match_wins() {
+        let rules: Vec<(Matcher, ResponseFactory)> = vec![
+            (
+                Box::new(|req| req.uri().path().starts_with("/admin")),
+                Box::new(|_| Response::new("admin".into())),
+            ),
+            (
+                Box::new(|req| req.method() == Method::GET),
+                Box::new(|_| Response::new("get".into())),
+            ),
+        ];
+        let client = ::proxy_client_rules(rules);
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut send = |method: Method, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            let future = client.request(request).and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().map(move |body| (status, body))
+            });
+            let (status, body) = runtime.block_on(future).unwrap();
+            (status.as_u16(), String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        assert_eq!(send(Method::GET, "http://example.com/admin").1, "admin");
+        assert_eq!(send(Method::GET, "http://example.com/home").1, "get");
+        assert_eq!(
+            send(Method::POST, "http://example.com/home"),
+            (
+                404,
+                "no rule matched POST http://example.com/home".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        let mut map = HashMap::new();
+        map.insert(
+            "http://example.com/a".parse().unwrap(),
+            (StatusCode::OK, Bytes::from("a")),
+        );
+        map.insert(
+            "http://example.com/b?page=2".parse().unwrap(),
+            (StatusCode::CREATED, Bytes::from("b")),
+        );
+        let client = ::proxy_client_map(map);
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut get = |uri: &str| {
+            let future = client.get(uri.parse().unwrap()).and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().map(move |body| (status, body))
+            });
+            let (status, body) = runtime.block_on(future).unwrap();
+            (status.as_u16(), String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        assert_eq!(get("http://example.com/a"), (200, "a".to_string()));
+        assert_eq!(get("http://EXAMPLE.com/a"), (200, "a".to_string()));
+        assert_eq!(get("http://example.com/b?page=2"), (201, "b".to_string()));
+        assert_eq!(get("http://example.com/b"), (404, String::new()));
+        assert_eq!(get("http://example.com/a/"), (404, String::new()));
+        assert_eq!(get("http://other.example.com/a"), (404, String::new()));
+    }
+
+    #[test]
+    fn test_keyed() {
+        let mut map: HashMap<_, ResponseFactory> = HashMap::new();
+        map.insert(
+            Some("acme".to_string()),
+            Box::new(|_| Response::new("acme".into())),
+        );
+        map.insert(
+            Some("globex".to_string()),
+            Box::new(|_| Response::new("globex".into())),
+        );
+        let client = ::proxy_client_keyed(
+            |req| {
+                let tenant = req.headers().get("x-tenant")?.to_str().ok()?;
+                Some(tenant.to_lowercase())
+            },
+            map,
+            Box::new(|_| {
+                let mut response = Response::new("unknown tenant".into());
+                *response.status_mut() = StatusCode::FORBIDDEN;
+                response
+            }),
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut send = |tenant: Option<&str>| {
+            let mut request = Request::get("http://example.com");
+            if let Some(tenant) = tenant {
+                request.header("x-tenant", tenant);
+            }
+            let future = client
+                .request(request.body(Body::empty()).unwrap())
+                .and_then(|res| {
+                    let status = res.status();
+                    res.into_body().concat2().map(move |body| (status, body))
+                });
+            let (status, body) = runtime.block_on(future).unwrap();
+            (status.as_u16(), String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        assert_eq!(send(Some("acme")), (200, "acme".to_string()));
+        assert_eq!(send(Some("Globex")), (200, "globex".to_string()));
+        assert_eq!(send(Some("initech")), (403, "unknown tenant".to_string()));
+        assert_eq!(send(None), (403, "unknown tenant".to_string()));
+    }
+}
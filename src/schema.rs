@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::prelude::*;
+use hyper::header::{HeaderName, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use json::json_bytes_response;
+use serde_json::{self, Value};
+use std::sync::Arc;
+
+/// A minimal description of the requests a [`schema_stub`] accepts.
+///
+/// A schema can require:
+///
+/// - A content type, compared with the request's `Content-Type` header
+///   without any parameters (so `"application/json"` matches
+///   `application/json; charset=utf-8`), ignoring case.
+/// - Headers, which must be present, with any value.
+/// - Top-level JSON keys. The request body must be a JSON object with each of
+///   these keys, with any value.
+///
+/// Nothing else about a request is checked. In particular, this isn't JSON
+/// Schema: there is no way to check the types of values, or anything below the
+/// top level of the body.
+///
+/// [`schema_stub`]: fn.schema_stub.html
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    content_type: Option<String>,
+    headers: Vec<HeaderName>,
+    json_keys: Vec<String>,
+}
+
+impl Schema {
+    /// Creates a schema that accepts every request.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Requires requests to have the given content type.
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_ascii_lowercase());
+        self
+    }
+
+    /// Requires requests to have a header called `name`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` isn't a valid header name.
+    pub fn require_header(mut self, name: &str) -> Self {
+        let name = HeaderName::from_bytes(name.as_bytes()).expect("invalid header name");
+        self.headers.push(name);
+        self
+    }
+
+    /// Requires request bodies to be JSON objects with the key `key`.
+    pub fn require_json_key(mut self, key: &str) -> Self {
+        self.json_keys.push(key.to_string());
+        self
+    }
+
+    // Returns a description of each way the request doesn't conform.
+    fn validate(&self, req: &Request<Vec<u8>>) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if let Some(ref expected) = self.content_type {
+            let actual = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.split(';').next().unwrap().trim().to_ascii_lowercase());
+            if actual.as_ref() != Some(expected) {
+                problems.push(format!(
+                    "expected content type {}, got {}",
+                    expected,
+                    actual.as_ref().map_or("none", String::as_str)
+                ));
+            }
+        }
+
+        for name in &self.headers {
+            if !req.headers().contains_key(name) {
+                problems.push(format!("missing header {}", name));
+            }
+        }
+
+        if !self.json_keys.is_empty() {
+            match serde_json::from_slice(req.body()) {
+                Ok(Value::Object(object)) => {
+                    for key in &self.json_keys {
+                        if !object.contains_key(key) {
+                            problems.push(format!("missing JSON key {:?}", key));
+                        }
+                    }
+                }
+                Ok(_) => problems.push("body is not a JSON object".to_string()),
+                Err(err) => problems.push(format!("body is not valid JSON: {}", err)),
+            }
+        }
+
+        problems
+    }
+}
+
+/// Wraps `handler` so that it is only called for requests that conform to
+/// `schema`. Other requests get a `400 Bad Request` response, with a JSON body
+/// listing what was wrong with them:
+///
+/// ```json
+/// {"error": "request does not match schema", "details": ["missing header x-api-key"]}
+/// ```
+///
+/// The request body is read in full before it is validated, and `handler`
+/// receives it all at once.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::{proxy_client_fn, schema_stub, Schema};
+///
+/// let schema = Schema::new()
+///     .content_type("application/json")
+///     .require_header("x-api-key")
+///     .require_json_key("name");
+///
+/// let client = proxy_client_fn(schema_stub(schema, |_| Response::new("created".into())));
+/// ```
+pub fn schema_stub<F>(
+    schema: Schema,
+    handler: F,
+) -> impl Fn(Request<Body>) -> SchemaFuture + Send + Sync + Clone + 'static
+where
+    F: Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static,
+{
+    let schema = Arc::new(schema);
+
+    move |req| {
+        let schema = schema.clone();
+        let handler = handler.clone();
+        let (parts, body) = req.into_parts();
+
+        let future = body.concat2().map(move |body| {
+            let req = Request::from_parts(parts, body.to_vec());
+            let problems = schema.validate(&req);
+            if problems.is_empty() {
+                return handler(req.map(Body::from));
+            }
+
+            let body = json!({
+                "error": "request does not match schema",
+                "details": problems,
+            });
+            json_bytes_response(StatusCode::BAD_REQUEST, body.to_string().into())
+        });
+
+        SchemaFuture {
+            inner: Box::new(future),
+        }
+    }
+}
+
+/// The future returned by handlers created by [`schema_stub`].
+///
+/// [`schema_stub`]: fn.schema_stub.html
+pub struct SchemaFuture {
+    inner: Box<dyn Future<Item = Response<Body>, Error = ::hyper::Error> + Send>,
+}
+
+impl Future for SchemaFuture {
+    type Item = Response<Body>;
+    type Error = ::hyper::Error;
+
+    fn poll(&mut self) -> Poll<Response<Body>, ::hyper::Error> {
+        self.inner.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn post(content_type: &str, api_key: Option<&str>, body: &str) -> (StatusCode, Value) {
+        let schema = Schema::new()
+            .content_type("application/json")
+            .require_header("x-api-key")
+            .require_json_key("name");
+        let client = ::proxy_client_fn(schema_stub(schema, |_| {
+            Response::new(r#"{"created": true}"#.into())
+        }));
+
+        let mut request = Request::post("http://example.com/users");
+        request.header(CONTENT_TYPE, content_type);
+        if let Some(api_key) = api_key {
+            request.header("x-api-key", api_key);
+        }
+        let request = request.body(body.to_string().into()).unwrap();
+
+        Runtime::new()
+            .unwrap()
+            .block_on(client.request(request).and_then(|res| {
+                let status = res.status();
+                res.into_body()
+                    .concat2()
+                    .map(move |body| (status, serde_json::from_slice(&body).unwrap()))
+            }))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_conforming_request() {
+        let (status, body) = post(
+            "application/json; charset=utf-8",
+            Some("secret"),
+            r#"{"name": "Alyssa"}"#,
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "created": true }));
+    }
+
+    #[test]
+    fn test_non_conforming_request() {
+        let (status, body) = post("text/plain", None, r#"{"id": 1}"#);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["details"],
+            json!([
+                "expected content type application/json, got text/plain",
+                "missing header x-api-key",
+                "missing JSON key \"name\"",
+            ])
+        );
+
+        let (status, body) = post("application/json", Some("secret"), "[]");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["details"], json!(["body is not a JSON object"]));
+    }
+}
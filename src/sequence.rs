@@ -0,0 +1,233 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use hyper::{Body, Request, Response, StatusCode};
+use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+type Handler = Arc<dyn Fn(Request<Body>) -> Response<Body> + Send + Sync>;
+
+/// A scripted series of handlers, each of which handles one request in turn.
+///
+/// The first request is handled by the first step, the second by the second,
+/// and so on, across all connections to the stub. What happens once every
+/// step has been used is decided by the sequence's [`ExhaustionPolicy`].
+///
+/// If the stub has a [`RequestLog`], each recorded request notes which step
+/// handled it (see [`RecordedRequest::stub_index`]).
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// #
+/// use hyper::{Response, StatusCode};
+/// use hyper_stub::{proxy_client_fn_ok, Sequence};
+///
+/// let sequence = Sequence::new()
+///     .then(|_| {
+///         let mut response = Response::new("try again".into());
+///         *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+///         response
+///     })
+///     .then(|_| Response::new("hello".into()));
+///
+/// let client = proxy_client_fn_ok(sequence.handler());
+/// ```
+///
+/// [`ExhaustionPolicy`]: enum.ExhaustionPolicy.html
+/// [`RequestLog`]: struct.RequestLog.html
+/// [`RecordedRequest::stub_index`]: struct.RecordedRequest.html#method.stub_index
+#[derive(Clone, Default)]
+pub struct Sequence {
+    steps: Vec<Handler>,
+    exhaustion: ExhaustionPolicy,
+    exhausted_handler: Option<Handler>,
+}
+
+/// What a [`Sequence`] does with requests after all of its steps have been
+/// used.
+///
+/// [`Sequence`]: struct.Sequence.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExhaustionPolicy {
+    /// Respond with `500 Internal Server Error`, with a body saying the
+    /// sequence was exhausted. This is the default, because a client making
+    /// more requests than expected is usually a bug.
+    #[default]
+    Fail,
+
+    /// Keep using the last step for every request from then on.
+    RepeatLast,
+
+    /// Start again from the first step.
+    Cycle,
+}
+
+// Recorded in the extensions of responses from a Sequence, so that the stub
+// can note in its RequestLog which step was used.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StubIndex(pub(crate) usize);
+
+impl Sequence {
+    /// Creates a sequence with no steps.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a step that handles the next request with `handler`.
+    pub fn then<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(Request<Body>) -> Response<Body> + Send + Sync + 'static,
+    {
+        self.steps.push(Arc::new(handler));
+        self
+    }
+
+    /// Sets what happens to requests once every step has been used. See
+    /// [`ExhaustionPolicy`].
+    ///
+    /// [`ExhaustionPolicy`]: enum.ExhaustionPolicy.html
+    pub fn on_exhausted(mut self, policy: ExhaustionPolicy) -> Self {
+        self.exhaustion = policy;
+        self
+    }
+
+    /// Handles every request after the last step with `handler`.
+    ///
+    /// This takes precedence over any [`ExhaustionPolicy`], whether it was set
+    /// before or after. Requests handled by `handler` aren't considered to
+    /// have been handled by a step, so their [`stub_index`] is `None`.
+    ///
+    /// [`ExhaustionPolicy`]: enum.ExhaustionPolicy.html
+    /// [`stub_index`]: struct.RecordedRequest.html#method.stub_index
+    pub fn on_exhausted_with<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(Request<Body>) -> Response<Body> + Send + Sync + 'static,
+    {
+        self.exhausted_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Creates a handler that works through the sequence's steps.
+    pub fn handler(
+        self,
+    ) -> impl Fn(Request<Body>) -> Response<Body> + Send + Sync + Clone + 'static {
+        let steps = Arc::new(self.steps);
+        let exhaustion = self.exhaustion;
+        let exhausted_handler = self.exhausted_handler;
+        let position = Arc::new(AtomicUsize::new(0));
+
+        move |req| {
+            let position = position.fetch_add(1, Ordering::SeqCst);
+            let index = match exhaustion {
+                _ if position < steps.len() => Some(position),
+                _ if exhausted_handler.is_some() => None,
+                _ if steps.is_empty() => None,
+                ExhaustionPolicy::Fail => None,
+                ExhaustionPolicy::RepeatLast => Some(steps.len() - 1),
+                ExhaustionPolicy::Cycle => Some(position % steps.len()),
+            };
+
+            match index {
+                Some(index) => {
+                    let mut response = steps[index](req);
+                    response.extensions_mut().insert(StubIndex(index));
+                    response
+                }
+
+                None => {
+                    if let Some(ref handler) = exhausted_handler {
+                        return handler(req);
+                    }
+
+                    let message = format!("sequence exhausted after {} responses", steps.len());
+                    let mut response = Response::new(message.into());
+                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    response
+                }
+            }
+        }
+    }
+}
+
+impl Debug for Sequence {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.debug_struct("Sequence")
+            .field("steps", &self.steps.len())
+            .field("exhaustion", &self.exhaustion)
+            .field("exhausted_handler", &self.exhausted_handler.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+    use {RequestLog, StubBuilder};
+
+    fn step(status: StatusCode) -> impl Fn(Request<Body>) -> Response<Body> {
+        move |_| {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = status;
+            response
+        }
+    }
+
+    fn statuses(sequence: Sequence, log: &RequestLog, count: usize) -> Vec<u16> {
+        let client = StubBuilder::new()
+            .request_log(log)
+            .proxy_client_fn_ok(sequence.handler());
+        let mut runtime = Runtime::new().unwrap();
+        (0..count)
+            .map(|_| {
+                let future = client.get("http://example.com".parse().unwrap());
+                runtime.block_on(future).unwrap().status().as_u16()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_records_stub_index() {
+        let log = RequestLog::new();
+        let sequence = Sequence::new()
+            .then(step(StatusCode::SERVICE_UNAVAILABLE))
+            .then(step(StatusCode::OK));
+
+        assert_eq!(statuses(sequence, &log, 3), [503, 200, 500]);
+        let indices: Vec<_> = log.entries().iter().map(|e| e.stub_index()).collect();
+        assert_eq!(indices, [Some(0), Some(1), None]);
+    }
+
+    #[test]
+    fn test_exhaustion_policies() {
+        let sequence = Sequence::new()
+            .then(step(StatusCode::SERVICE_UNAVAILABLE))
+            .then(step(StatusCode::OK));
+
+        let log = RequestLog::new();
+        let repeat = sequence.clone().on_exhausted(ExhaustionPolicy::RepeatLast);
+        assert_eq!(statuses(repeat, &log, 4), [503, 200, 200, 200]);
+
+        let log = RequestLog::new();
+        let cycle = sequence.on_exhausted(ExhaustionPolicy::Cycle);
+        assert_eq!(statuses(cycle, &log, 4), [503, 200, 503, 200]);
+        let indices: Vec<_> = log.entries().iter().map(|e| e.stub_index()).collect();
+        assert_eq!(indices, [Some(0), Some(1), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn test_exhaustion_closure() {
+        let log = RequestLog::new();
+        let sequence = Sequence::new()
+            .then(step(StatusCode::OK))
+            .on_exhausted_with(step(StatusCode::TOO_MANY_REQUESTS))
+            .on_exhausted(ExhaustionPolicy::RepeatLast);
+
+        assert_eq!(statuses(sequence, &log, 3), [200, 429, 429]);
+        let indices: Vec<_> = log.entries().iter().map(|e| e.stub_index()).collect();
+        assert_eq!(indices, [Some(0), None, None]);
+    }
+}
@@ -0,0 +1,196 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use builder::Config;
+use connector::{ConnectInfo, ConnectionRequestIndex};
+use drain;
+use futures::future;
+use futures::prelude::*;
+use hyper::service::Service;
+use hyper::{Body, Chunk, Request, Response, StatusCode};
+use panic::{self, HandlerPanic, PanicLog};
+use payload::{CountedBody, DelayedBody, StubBody};
+use probe::ConnectionRequests;
+use record::RecordedRequest;
+use sequence::StubIndex;
+use stats::InFlight;
+use std::any::Any;
+use std::error::Error;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+type ResponseFuture<B> = Box<dyn Future<Item = Response<StubBody<B>>, Error = BoxError> + Send>;
+
+// Wraps every service created by a stub's NewService, to implement the parts
+// of the stub's configuration that need to see requests and responses.
+//
+// The inner service is kept behind a mutex so that it can be called from
+// inside the returned future, after the request body has been buffered.
+pub struct StubService<S> {
+    inner: Arc<Mutex<S>>,
+    config: Arc<Config>,
+    connect_info: Option<ConnectInfo>,
+    requests: ConnectionRequests,
+}
+
+impl<S> StubService<S> {
+    pub fn new(
+        inner: S,
+        config: Arc<Config>,
+        connect_info: Option<ConnectInfo>,
+        requests: ConnectionRequests,
+    ) -> Self {
+        StubService {
+            inner: Arc::new(Mutex::new(inner)),
+            config,
+            connect_info,
+            requests,
+        }
+    }
+}
+
+// If a handler panicked while being called, the mutex will have been
+// poisoned, but when panics are being caught the service should carry on.
+fn lock<S>(inner: &Mutex<S>) -> MutexGuard<'_, S> {
+    inner.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn call<S>(inner: &Mutex<S>, req: Request<Body>) -> ResponseFuture<S::ResBody>
+where
+    S: Service<ReqBody = Body> + 'static,
+    S::Future: Send + 'static,
+{
+    let future = lock(inner).call(req);
+    Box::new(
+        future
+            .map(|res| res.map(StubBody::Inner))
+            .map_err(Into::into),
+    )
+}
+
+fn panic_response<B>(log: &PanicLog, payload: &(dyn Any + Send)) -> Response<StubBody<B>> {
+    let panic = HandlerPanic::new(payload);
+    let mut response = Response::new(StubBody::Stub(panic.to_string().into()));
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    log.push(panic);
+    response
+}
+
+fn catch_panics<B, F>(log: PanicLog, f: F) -> ResponseFuture<B>
+where
+    B: Send + 'static,
+    F: FnOnce() -> ResponseFuture<B>,
+{
+    panic::install_location_hook();
+
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(future) => {
+            Box::new(
+                AssertUnwindSafe(future)
+                    .catch_unwind()
+                    .then(move |result| match result {
+                        Ok(result) => result,
+                        Err(payload) => Ok(panic_response(&log, &*payload)),
+                    }),
+            )
+        }
+        Err(payload) => Box::new(future::ok(panic_response(&log, &*payload))),
+    }
+}
+
+impl<S> StubService<S>
+where
+    S: Service<ReqBody = Body> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    fn dispatch(&mut self, req: Request<Body>) -> ResponseFuture<S::ResBody> {
+        if !self.config.records_requests() {
+            if self.config.drain_request_bodies {
+                let req = req.map(drain::drain_in_background);
+                return call(&self.inner, req);
+            }
+            return call(&self.inner, req);
+        }
+
+        let inner = self.inner.clone();
+        let config = self.config.clone();
+        let (parts, body) = req.into_parts();
+        let created = self.config.created.unwrap_or_else(Instant::now);
+        let received = Instant::now();
+
+        let body = body.concat2().map_err(Into::into);
+        Box::new(body.and_then(move |body| -> ResponseFuture<S::ResBody> {
+            let body = body.into_bytes();
+            let entry = RecordedRequest::new(&parts, body.clone(), created, received);
+
+            #[cfg(feature = "serde")]
+            {
+                if let Some(ref file) = config.record_file {
+                    if let Err(error) = file.write(&entry) {
+                        return Box::new(future::err(error.into()));
+                    }
+                }
+            }
+
+            let log = config.request_log.clone();
+            let index = log.as_ref().map(|log| log.push(entry));
+            Box::new(
+                call(&inner, Request::from_parts(parts, body.into())).map(move |res| {
+                    if let (Some(log), Some(index), Some(&StubIndex(stub_index))) =
+                        (log, index, res.extensions().get())
+                    {
+                        log.set_stub_index(index, stub_index);
+                    }
+                    res
+                }),
+            )
+        }))
+    }
+}
+
+impl<S> Service for StubService<S>
+where
+    S: Service<ReqBody = Body> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type ReqBody = Body;
+    type ResBody = CountedBody<DelayedBody<StubBody<S::ResBody>>>;
+    type Error = BoxError;
+    type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = BoxError> + Send>;
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let index = self.requests.record();
+        req.extensions_mut().insert(ConnectionRequestIndex(index));
+        let stats = self.config.stats.clone();
+        let in_flight = stats.clone().map(InFlight::new);
+
+        if let Some(ref info) = self.connect_info {
+            req.extensions_mut().insert(info.clone());
+        }
+        self.config.extensions.apply(req.extensions_mut());
+
+        if let Some(hook) = self.config.on_request_chunk.clone() {
+            req = req.map(|body| {
+                Body::wrap_stream(body.map(move |chunk| {
+                    let chunk = chunk.into_bytes();
+                    hook.call(&chunk);
+                    Chunk::from(chunk)
+                }))
+            });
+        }
+
+        let future = match self.config.panic_log.clone() {
+            Some(log) => catch_panics(log, || self.dispatch(req)),
+            None => self.dispatch(req),
+        };
+
+        let body_delay = self.config.body_delay;
+        Box::new(future.map(move |res| {
+            res.map(|body| CountedBody::new(DelayedBody::new(body, body_delay), stats, in_flight))
+        }))
+    }
+}
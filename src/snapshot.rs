@@ -0,0 +1,205 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use record::RecordedRequest;
+use std::str;
+
+#[cfg(feature = "serde")]
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+/// A deterministic summary of a [`RecordedRequest`], for comparing against a
+/// stored snapshot. See [`RequestLog::snapshot`].
+///
+/// Headers are sorted by name, and then by value, so the order hyper happened
+/// to write them in doesn't matter. With the `serde` feature, snapshots
+/// implement `Serialize`, so they can be compared as JSON, with [insta] or
+/// similar.
+///
+/// [`RecordedRequest`]: struct.RecordedRequest.html
+/// [`RequestLog::snapshot`]: struct.RequestLog.html#method.snapshot
+/// [insta]: https://docs.rs/insta
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestSnapshot {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: SnapshotBody,
+}
+
+/// The body of a [`RequestSnapshot`].
+///
+/// [`RequestSnapshot`]: struct.RequestSnapshot.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotBody {
+    /// A body that was valid UTF-8.
+    Text(String),
+
+    /// Any other body, encoded as standard base64 with padding.
+    Base64(String),
+}
+
+impl RequestSnapshot {
+    pub(crate) fn new(request: &RecordedRequest) -> Self {
+        let mut headers: Vec<_> = request
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.as_str().to_string(), value)
+            })
+            .collect();
+        headers.sort();
+
+        let body = match str::from_utf8(request.body()) {
+            Ok(text) => SnapshotBody::Text(text.to_string()),
+            Err(_) => SnapshotBody::Base64(base64(request.body())),
+        };
+
+        RequestSnapshot {
+            method: request.method().to_string(),
+            path: request
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_string(),
+            headers,
+            body,
+        }
+    }
+
+    /// Returns the request's method.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Returns the request's path, including the query string if it had one.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the request's headers, sorted.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Returns the request's body.
+    pub fn body(&self) -> &SnapshotBody {
+        &self.body
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for RequestSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("RequestSnapshot", 4)?;
+        state.serialize_field("method", &self.method)?;
+        state.serialize_field("path", &self.path)?;
+        state.serialize_field("headers", &self.headers)?;
+        state.serialize_field("body", &self.body)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for SnapshotBody {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            SnapshotBody::Text(ref text) => {
+                serializer.serialize_newtype_variant("SnapshotBody", 0, "text", text)
+            }
+            SnapshotBody::Base64(ref data) => {
+                serializer.serialize_newtype_variant("SnapshotBody", 1, "base64", data)
+            }
+        }
+    }
+}
+
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use hyper::{Body, Request, Response};
+    use tokio::runtime::current_thread::Runtime;
+    use {RequestLog, StubBuilder};
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(&[0xff, 0xfe, 0x00, 0x01]), "//4AAQ==");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let log = RequestLog::new();
+        let client = StubBuilder::new()
+            .request_log(&log)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let requests = vec![
+            Request::post("http://example.com/users?notify=true")
+                .header("x-b", "2")
+                .header("x-a", "1")
+                .body("hello".into())
+                .unwrap(),
+            Request::put("http://example.com/avatar")
+                .body(vec![0xff, 0x00].into())
+                .unwrap(),
+        ];
+        let mut runtime = Runtime::new().unwrap();
+        for request in requests {
+            let future = client
+                .request(request)
+                .and_then(|res| res.into_body().concat2());
+            runtime.block_on(future).unwrap();
+        }
+
+        let snapshot = log.snapshot();
+        assert_eq!(snapshot[0].method(), "POST");
+        assert_eq!(snapshot[0].path(), "/users?notify=true");
+        let names: Vec<_> = snapshot[0].headers().iter().map(|h| &h.0[..]).collect();
+        assert_eq!(names, ["content-length", "host", "x-a", "x-b"]);
+        assert_eq!(*snapshot[0].body(), SnapshotBody::Text("hello".to_string()));
+        assert_eq!(
+            *snapshot[1].body(),
+            SnapshotBody::Base64("/wA=".to_string())
+        );
+
+        #[cfg(feature = "serde")]
+        {
+            let json = ::serde_json::to_value(&snapshot[1]).unwrap();
+            assert_eq!(
+                json,
+                json!({
+                    "method": "PUT",
+                    "path": "/avatar",
+                    "headers": [["content-length", "2"], ["host", "example.com"]],
+                    "body": { "base64": "/wA=" },
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::stream;
+use handlers::deadline;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Response};
+use std::time::Duration;
+use tokio::timer::Delay;
+
+/// An event sent by [`sse_response`].
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::SseEvent;
+/// use std::time::Duration;
+///
+/// let event = SseEvent::new("{\"price\": 42}")
+///     .event("tick")
+///     .id("1")
+///     .delay(Duration::from_millis(100));
+/// ```
+///
+/// [`sse_response`]: fn.sse_response.html
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+    delay: Option<Duration>,
+}
+
+impl SseEvent {
+    /// Creates an event with the given data, and no name or ID.
+    ///
+    /// Data with several lines is sent as one `data:` field per line, which a
+    /// client joins back together with newlines.
+    pub fn new(data: &str) -> Self {
+        SseEvent {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the event's name, sent as its `event:` field.
+    pub fn event(mut self, name: &str) -> Self {
+        self.event = Some(name.to_string());
+        self
+    }
+
+    /// Sets the event's ID, sent as its `id:` field.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Makes the stub server wait for `delay` before sending the event,
+    /// counted from when the one before it was sent, or from when the
+    /// response's head was sent for the first event.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    // The event as it's written in an event stream, including the blank
+    // line that ends it.
+    fn frame(&self) -> Bytes {
+        let mut frame = String::new();
+        if let Some(ref id) = self.id {
+            frame.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(ref event) = self.event {
+            frame.push_str(&format!("event: {}\n", event));
+        }
+        for line in self.data.split('\n') {
+            frame.push_str(&format!("data: {}\n", line));
+        }
+        frame.push('\n');
+        frame.into()
+    }
+}
+
+/// Creates a `200 OK` response that streams `events` as [Server-Sent
+/// Events], with `Content-Type: text/event-stream` and `Cache-Control:
+/// no-cache`.
+///
+/// Each event is written as a separate chunk, using chunked transfer
+/// encoding, once its [`delay`] is over, so a client reading the stream sees
+/// each event as it's sent, rather than all of them at the end. As with
+/// [`chunked_response`], the client may still read events that are sent
+/// close together in one go. The response ends after the last event, like a
+/// server closing the stream, which a client following the specification
+/// would then reconnect to.
+///
+/// ```
+/// # extern crate hyper_stub;
+/// #
+/// use hyper_stub::{proxy_client_fn_ok, sse_response, SseEvent};
+/// use std::time::Duration;
+///
+/// let client = proxy_client_fn_ok(|_| {
+///     sse_response(vec![
+///         SseEvent::new("connected"),
+///         SseEvent::new("42").event("tick").delay(Duration::from_millis(100)),
+///     ])
+/// });
+/// ```
+///
+/// [Server-Sent Events]: https://html.spec.whatwg.org/multipage/server-sent-events.html
+/// [`delay`]: struct.SseEvent.html#method.delay
+/// [`chunked_response`]: fn.chunked_response.html
+pub fn sse_response(events: Vec<SseEvent>) -> Response<Body> {
+    let frames = stream::iter_ok(events).and_then(|event| match event.delay {
+        Some(delay) => Either::A(Delay::new(deadline(delay)).map(move |()| event.frame())),
+        None => Either::B(future::ok(event.frame())),
+    });
+
+    let mut response = Response::new(Body::wrap_stream(frames));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::runtime::current_thread::Runtime;
+
+    // A minimal event stream parser, returning each event's name, data and
+    // ID.
+    fn parse(stream: &str) -> Vec<(Option<String>, String, Option<String>)> {
+        stream
+            .split("\n\n")
+            .filter(|block| !block.is_empty())
+            .map(|block| {
+                let (mut event, mut data, mut id) = (None, Vec::new(), None);
+                for line in block.lines() {
+                    let (field, value) = line.split_at(line.find(": ").unwrap());
+                    let value = value[2..].to_string();
+                    match field {
+                        "event" => event = Some(value),
+                        "data" => data.push(value),
+                        "id" => id = Some(value),
+                        _ => panic!("unexpected field {}", field),
+                    }
+                }
+                (event, data.join("\n"), id)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sse_response() {
+        let client = ::proxy_client_fn_ok(|_| {
+            sse_response(vec![
+                SseEvent::new("hello"),
+                SseEvent::new("line one\nline two").event("multi").id("2"),
+                SseEvent::new("late").delay(Duration::from_millis(100)),
+            ])
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        let response = runtime
+            .block_on(client.get("http://example.com/events".parse().unwrap()))
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-cache");
+
+        let body = runtime.block_on(response.into_body().concat2()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            parse(&body),
+            [
+                (None, "hello".to_string(), None),
+                (some("multi"), "line one\nline two".to_string(), some("2")),
+                (None, "late".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_huge_delay() {
+        use tokio::timer::Timeout;
+
+        let client = ::proxy_client_fn_ok(|_| {
+            sse_response(vec![
+                SseEvent::new("hello"),
+                SseEvent::new("never").delay(Duration::MAX),
+            ])
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let response = runtime
+            .block_on(client.get("http://example.com/events".parse().unwrap()))
+            .unwrap();
+
+        // The first event arrives, and the stream stays open waiting for the
+        // second, rather than failing.
+        let (first, rest) = runtime
+            .block_on(response.into_body().into_future())
+            .map_err(|(err, _)| err)
+            .unwrap();
+        assert_eq!(&first.unwrap()[..], b"data: hello\n\n");
+        let rest = Timeout::new(rest.into_future(), Duration::from_millis(100));
+        let error = runtime.block_on(rest).map(|_| ()).unwrap_err();
+        assert!(error.is_elapsed());
+    }
+}
@@ -0,0 +1,420 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Counters describing what has happened to a stub's connections.
+///
+/// Pass a `Stats` to [`StubBuilder::stats`], then use the same handle (or a
+/// clone of it) to read the counters while or after the client runs.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// # extern crate tokio;
+/// #
+/// use futures::Future;
+/// use hyper::Response;
+/// use hyper_stub::{Stats, StubBuilder};
+/// use tokio::runtime::current_thread::Runtime;
+///
+/// let stats = Stats::new();
+/// let client = StubBuilder::new()
+///     .stats(&stats)
+///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+///
+/// let future = client.get("http://example.com".parse().unwrap());
+/// Runtime::new().unwrap().block_on(future).unwrap();
+///
+/// assert_eq!(stats.connections_opened(), 1);
+/// ```
+///
+/// [`StubBuilder::stats`]: struct.StubBuilder.html#method.stats
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    connections_opened: AtomicUsize,
+    connections_by_host: Mutex<HashMap<String, usize>>,
+    connections_closed: AtomicUsize,
+    response_body_bytes: AtomicU64,
+    in_flight_requests: AtomicUsize,
+    peak_in_flight_requests: AtomicUsize,
+}
+
+impl Stats {
+    /// Creates a set of counters, all starting at zero.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the number of connections clients have opened to the stub.
+    pub fn connections_opened(&self) -> usize {
+        self.inner.connections_opened.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of connections clients have opened to the stub for
+    /// each host, for checking that a client keeps a separate pool of
+    /// connections for each host it talks to.
+    ///
+    /// Hosts are taken from the destination hyper's client asks the stub's
+    /// connector to connect to, which comes from the request's URI, and are
+    /// lowercased, since hosts are case-insensitive. The scheme and port
+    /// aren't part of the key, so `http://example.com`,
+    /// `https://example.com` and `http://example.com:8080` are all counted
+    /// as `example.com`, even though hyper's client pools connections to
+    /// each of them separately. Connections opened with
+    /// [`raw_connection_fn`] have no destination, so they're counted in
+    /// [`connections_opened`], but not here.
+    ///
+    /// [`raw_connection_fn`]: struct.StubBuilder.html#method.raw_connection_fn
+    /// [`connections_opened`]: #method.connections_opened
+    pub fn connections_by_host(&self) -> HashMap<String, usize> {
+        self.inner.connections_by_host.lock().unwrap().clone()
+    }
+
+    /// Asserts that no connection to the stub has ever been opened, for
+    /// checking that the code under test avoided the network entirely, like
+    /// when it should have answered from a cache.
+    ///
+    /// Call this once the code under test has finished. A connection is
+    /// counted as soon as the client's connector completes it, so a request
+    /// that was started but is still waiting to connect (for example, because
+    /// of a [`connect_delay`]) hasn't been counted yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any connection has been opened, saying how many.
+    ///
+    /// [`connect_delay`]: struct.StubBuilder.html#method.connect_delay
+    pub fn assert_no_connections(&self) {
+        let opened = self.connections_opened();
+        assert!(
+            opened == 0,
+            "expected no connections to the stub, but {} were opened",
+            opened
+        );
+    }
+
+    /// Asserts that exactly one connection to the stub has been opened, for
+    /// checking that a client sent a batch of requests over a single
+    /// kept-alive connection.
+    ///
+    /// Whether it did depends on the client's connection pool as much as on
+    /// the code under test. hyper's client only reuses a connection that's
+    /// idle in its pool, so requests have to be sent one after another over
+    /// HTTP/1: ones sent at the same time each open a connection of their
+    /// own. A client built with `keep_alive(false)`, or with
+    /// `max_idle_per_host(0)`, on its [`StubBuilder::client_builder`], never
+    /// reuses connections, and neither does one whose responses say
+    /// `Connection: close` (see [`close_connection`]). Requests to different
+    /// hosts never share a connection either. Over HTTP/2, every request
+    /// shares one connection, even concurrent ones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no connections, or more than one, have been opened, saying
+    /// how many were opened for each host.
+    ///
+    /// [`StubBuilder::client_builder`]: struct.StubBuilder.html#method.client_builder
+    /// [`close_connection`]: fn.close_connection.html
+    pub fn assert_single_connection(&self) {
+        let opened = self.connections_opened();
+        assert!(
+            opened == 1,
+            "expected a single connection to the stub, but {} were opened: {:?}",
+            opened,
+            self.connections_by_host()
+        );
+    }
+
+    /// Returns the number of connections the stub server has finished
+    /// serving.
+    ///
+    /// A connection is counted as soon as the stub server sees it end, which
+    /// is usually because the client closed it. A hyper client only closes a
+    /// kept-alive connection when it's dropped, or when the connection has
+    /// been idle in its pool for longer than the pool's
+    /// [`keep_alive_timeout`]. Expired connections are only removed from the
+    /// pool periodically, by a task the client spawns on its executor, so
+    /// there can be a further delay of up to the timeout again before this
+    /// counter changes, during which the runtime has to keep running. Setting
+    /// the timeout (see [`StubBuilder::client_builder`]) makes this easier to
+    /// test.
+    ///
+    /// [`keep_alive_timeout`]: https://docs.rs/hyper/0.12/hyper/client/struct.Builder.html#method.keep_alive_timeout
+    /// [`StubBuilder::client_builder`]: struct.StubBuilder.html#method.client_builder
+    pub fn connections_closed(&self) -> usize {
+        self.inner.connections_closed.load(Ordering::SeqCst)
+    }
+
+    /// Returns the total number of bytes of response bodies the stub has
+    /// served, across every request.
+    ///
+    /// Bytes are counted as hyper's server takes them from each body to
+    /// write, so this is what the client was sent, after any encoding done
+    /// by the handler (like [`negotiate_encoding`]). It doesn't include
+    /// response heads, or the framing of chunked bodies. A body the client
+    /// stops reading part-way through may have been counted further than the
+    /// client read.
+    ///
+    /// [`negotiate_encoding`]: fn.negotiate_encoding.html
+    pub fn response_body_bytes(&self) -> u64 {
+        self.inner.response_body_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of requests the stub is handling right now.
+    ///
+    /// A request is counted from when the stub server has read its head and
+    /// calls the handler, before any of its body is read, until its response
+    /// has been sent in full, including the whole body. So a response with a
+    /// slowly streamed body keeps its request in flight until the last of
+    /// the body has been written, and one whose connection is closed
+    /// part-way through stops counting when the connection closes. If the
+    /// handler fails without responding, the request stops counting then.
+    pub fn in_flight_requests(&self) -> usize {
+        self.inner.in_flight_requests.load(Ordering::SeqCst)
+    }
+
+    /// Returns the highest number of requests the stub has been handling at
+    /// once. See [`in_flight_requests`].
+    ///
+    /// This is useful for checking a client doesn't make more concurrent
+    /// requests than it's supposed to. Since hyper's client only sends one
+    /// request at a time over each HTTP/1.1 connection, it's also the most
+    /// connections the client was using at once.
+    ///
+    /// [`in_flight_requests`]: #method.in_flight_requests
+    pub fn peak_in_flight_requests(&self) -> usize {
+        self.inner.peak_in_flight_requests.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn record_connection_opened(&self, host: Option<&str>) {
+        self.inner.connections_opened.fetch_add(1, Ordering::SeqCst);
+        if let Some(host) = host {
+            let by_host = &self.inner.connections_by_host;
+            let mut by_host = by_host.lock().unwrap_or_else(|e| e.into_inner());
+            *by_host.entry(host.to_ascii_lowercase()).or_insert(0) += 1;
+        }
+    }
+
+    pub(crate) fn record_connection_closed(&self) {
+        self.inner.connections_closed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_response_body_bytes(&self, len: u64) {
+        self.inner
+            .response_body_bytes
+            .fetch_add(len, Ordering::SeqCst);
+    }
+}
+
+// Counts a request as in flight in a stub's Stats for as long as it's alive.
+// Created when the stub's service is called, and kept with the response body
+// until hyper's server drops it.
+pub(crate) struct InFlight(Stats);
+
+impl InFlight {
+    pub(crate) fn new(stats: Stats) -> Self {
+        let counters = &stats.inner;
+        let current = counters.in_flight_requests.fetch_add(1, Ordering::SeqCst) + 1;
+        counters
+            .peak_in_flight_requests
+            .fetch_max(current, Ordering::SeqCst);
+        InFlight(stats)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0
+            .inner
+            .in_flight_requests
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::client::Client;
+    use hyper::{Body, Response};
+    use std::time::{Duration, Instant};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+    use StubBuilder;
+
+    #[test]
+    fn test_idle_connection_closed() {
+        let stats = Stats::new();
+        let client = StubBuilder::new()
+            .stats(&stats)
+            .client_builder(Client::builder().keep_alive_timeout(Duration::from_millis(50)))
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let mut runtime = Runtime::new().unwrap();
+        let future = client.get("http://example.com".parse().unwrap());
+        runtime.block_on(future).unwrap();
+        assert_eq!(stats.connections_opened(), 1);
+        assert_eq!(stats.connections_closed(), 0);
+
+        let idle = Delay::new(Instant::now() + Duration::from_millis(500));
+        runtime.block_on(idle).unwrap();
+        assert_eq!(stats.connections_closed(), 1);
+    }
+
+    #[test]
+    fn test_connections_by_host() {
+        let stats = Stats::new();
+        let client = StubBuilder::new()
+            .stats(&stats)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let mut runtime = Runtime::new().unwrap();
+        for uri in &[
+            "http://a.example.com/1",
+            "http://a.example.com/2",
+            "http://b.example.com/",
+            "http://A.example.com:8080/",
+        ] {
+            let future = client.get(uri.parse().unwrap());
+            runtime.block_on(future).unwrap();
+        }
+
+        let mut expected = HashMap::new();
+        expected.insert("a.example.com".to_string(), 2);
+        expected.insert("b.example.com".to_string(), 1);
+        assert_eq!(stats.connections_by_host(), expected);
+        assert_eq!(stats.connections_opened(), 3);
+    }
+
+    #[test]
+    fn test_assert_no_connections() {
+        let stats = Stats::new();
+        let _client = StubBuilder::new()
+            .stats(&stats)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        // The code under test answers from its cache, without using the
+        // client.
+
+        stats.assert_no_connections();
+    }
+
+    #[test]
+    #[should_panic(expected = "expected no connections to the stub, but 1 were opened")]
+    fn test_assert_no_connections_fails() {
+        let stats = Stats::new();
+        let client = StubBuilder::new()
+            .stats(&stats)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let future = client.get("http://example.com".parse().unwrap());
+        Runtime::new().unwrap().block_on(future).unwrap();
+
+        stats.assert_no_connections();
+    }
+
+    #[test]
+    fn test_assert_single_connection() {
+        let stats = Stats::new();
+        let client = StubBuilder::new()
+            .stats(&stats)
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..5 {
+            let future = client.get("http://example.com".parse().unwrap());
+            runtime.block_on(future).unwrap();
+        }
+
+        stats.assert_single_connection();
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a single connection to the stub, but 2 were opened")]
+    fn test_assert_single_connection_fails() {
+        let stats = Stats::new();
+        let client = StubBuilder::new()
+            .stats(&stats)
+            .client_builder(Client::builder().keep_alive(false))
+            .proxy_client_fn_ok(|_| Response::new(Body::empty()));
+
+        let mut runtime = Runtime::new().unwrap();
+        for _ in 0..2 {
+            let future = client.get("http://example.com".parse().unwrap());
+            runtime.block_on(future).unwrap();
+        }
+
+        stats.assert_single_connection();
+    }
+
+    #[test]
+    fn test_response_body_bytes() {
+        use body::chunked_response;
+        use futures::prelude::*;
+
+        let stats = Stats::new();
+        let client =
+            StubBuilder::new()
+                .stats(&stats)
+                .proxy_client_fn_ok(|req| match req.uri().path() {
+                    "/chunked" => chunked_response(&[100, 23]),
+                    path => Response::new(path.to_string().into()),
+                });
+
+        let mut runtime = Runtime::new().unwrap();
+        for uri in &[
+            "http://example.com/a",
+            "http://example.com/chunked",
+            "http://example.com/bc",
+        ] {
+            let future = client
+                .get(uri.parse().unwrap())
+                .and_then(|res| res.into_body().concat2());
+            runtime.block_on(future).unwrap();
+        }
+
+        assert_eq!(stats.response_body_bytes(), 2 + 123 + 3);
+    }
+
+    #[test]
+    fn test_in_flight_requests() {
+        use futures::future;
+        use futures::prelude::*;
+        use handlers::delayed;
+
+        let stats = Stats::new();
+        let client = StubBuilder::new()
+            .stats(&stats)
+            .proxy_client_fn(delayed(Duration::from_millis(50), |_| {
+                Response::new("hello".into())
+            }));
+
+        let mut runtime = Runtime::new().unwrap();
+        let requests = (0..3).map(|_| {
+            client
+                .get("http://example.com".parse().unwrap())
+                .and_then(|res| res.into_body().concat2())
+        });
+        runtime.block_on(future::join_all(requests)).unwrap();
+        assert_eq!(stats.peak_in_flight_requests(), 3);
+
+        // The server finishes with each response just after the client has
+        // read it.
+        let idle = Delay::new(Instant::now() + Duration::from_millis(50));
+        runtime.block_on(idle).unwrap();
+        assert_eq!(stats.in_flight_requests(), 0);
+
+        let future = client.get("http://example.com".parse().unwrap());
+        runtime.block_on(future).unwrap();
+        assert_eq!(stats.peak_in_flight_requests(), 3);
+    }
+}
@@ -0,0 +1,131 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use memsocket::{self, UnboundedSocket};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A factory for the in-memory connections between a stub client and its
+/// server.
+///
+/// A new pair of connected IO objects is created every time the client opens a
+/// connection. Bytes written to one of them must be readable from the other, as
+/// with a socket. Both halves must be [`AsyncRead`] + [`AsyncWrite`] + `Send` +
+/// `'static`, since the client half is handed to hyper's connection pool, and
+/// the server half is moved into a spawned task.
+///
+/// The default transport is [`UnboundedTransport`]. Any function returning a
+/// pair of sockets is also a transport:
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_stub;
+/// # extern crate memsocket;
+/// #
+/// use hyper::Response;
+/// use hyper_stub::StubBuilder;
+///
+/// let client = StubBuilder::new()
+///     .transport(|| memsocket::bounded(16))
+///     .proxy_client_fn_ok(|_| Response::new("hello".into()));
+/// ```
+///
+/// Note that memsocket's bounded sockets don't wake up a blocked writer when
+/// space becomes available, so once one fills up, that side of the connection
+/// stalls forever. That makes them useful for tests that want a stall, but not
+/// much else.
+///
+/// Implementing this trait for a custom type allows more advanced simulation,
+/// such as wrapping sockets to add latency or drop bytes.
+///
+/// [`AsyncRead`]: https://docs.rs/tokio/0.1/tokio/io/trait.AsyncRead.html
+/// [`AsyncWrite`]: https://docs.rs/tokio/0.1/tokio/io/trait.AsyncWrite.html
+/// [`UnboundedTransport`]: struct.UnboundedTransport.html
+pub trait Transport: Send + Sync + 'static {
+    /// The half of the connection used by the client.
+    type Client: AsyncRead + AsyncWrite + Send + 'static;
+
+    /// The half of the connection used by the stub server.
+    type Server: AsyncRead + AsyncWrite + Send + 'static;
+
+    /// Creates a new pair of connected IO objects.
+    fn connect(&self) -> (Self::Client, Self::Server);
+}
+
+impl<F, C, S> Transport for F
+where
+    F: Fn() -> (C, S) + Send + Sync + 'static,
+    C: AsyncRead + AsyncWrite + Send + 'static,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    type Client = C;
+    type Server = S;
+
+    fn connect(&self) -> (C, S) {
+        self()
+    }
+}
+
+/// The default transport, which connects clients and servers with unbounded
+/// [memsocket] sockets.
+///
+/// [memsocket]: https://docs.rs/memsocket
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnboundedTransport;
+
+impl Transport for UnboundedTransport {
+    type Client = UnboundedSocket;
+    type Server = UnboundedSocket;
+
+    fn connect(&self) -> (UnboundedSocket, UnboundedSocket) {
+        memsocket::unbounded()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use hyper::{Request, Response};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::runtime::current_thread::Runtime;
+    use StubBuilder;
+
+    struct CountingTransport(Arc<AtomicUsize>);
+
+    impl Transport for CountingTransport {
+        type Client = UnboundedSocket;
+        type Server = UnboundedSocket;
+
+        fn connect(&self) -> (UnboundedSocket, UnboundedSocket) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            memsocket::unbounded()
+        }
+    }
+
+    #[test]
+    fn test_custom_transport() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let client = StubBuilder::new()
+            .transport(CountingTransport(connections.clone()))
+            .proxy_client_fn_ok(|req| Response::new(req.into_body()));
+
+        let request = Request::post("http://example.com")
+            .body("hello".into())
+            .unwrap();
+
+        Runtime::new()
+            .unwrap()
+            .block_on({
+                client
+                    .request(request)
+                    .and_then(|res| res.into_body().concat2())
+                    .map(|bytes| assert_eq!(&bytes[..], b"hello"))
+                    .map_err(|err| panic!("{:?}", err))
+            })
+            .unwrap();
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+}